@@ -8,6 +8,7 @@ use std::path::Path;
 fn main() {
     let out_dir = env::var("OUT_DIR").unwrap();
     let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let target = env::var("TARGET").unwrap();
     let dest_path = Path::new(&out_dir).join("recipients.rs");
     let mut f = File::create(&dest_path).unwrap();
 
@@ -16,12 +17,13 @@ fn main() {
         r#"
 #[test]
 fn verify_deps() {{
-    let r = Recipients::with_env("{out_dir}", "{manifest_dir}").unwrap();
-    r.get("dhltest").unwrap();
-    r.get("dhltest-dash").unwrap();
-    r.get("dhltest_underscore").unwrap();
+    let r = Recipients::with_env("{out_dir}", "{manifest_dir}", "{target}").unwrap();
+    r.get("dhltest", None, DuplicatePolicy::Newest).unwrap().unwrap();
+    r.get("dhltest-dash", None, DuplicatePolicy::Newest).unwrap().unwrap();
+    r.get("dhltest_underscore", None, DuplicatePolicy::Newest).unwrap().unwrap();
 }}"#,
         out_dir = out_dir,
         manifest_dir = manifest_dir,
+        target = target,
     ).unwrap();
 }
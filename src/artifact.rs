@@ -0,0 +1,261 @@
+/// The kinds of library artifacts a dummy crate can produce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ArtifactKind {
+    /// A Rust library, from `crate-type = ["lib"]` or `["rlib"]`.
+    Rlib,
+    /// The metadata of a Rust library, used by pipelined and `cargo check`
+    /// builds.
+    Rmeta,
+    /// A dynamic library, from `crate-type = ["dylib"]` or `["cdylib"]`.
+    Dylib,
+    /// A static library, from `crate-type = ["staticlib"]`.
+    Staticlib,
+}
+
+impl ArtifactKind {
+    /// Every kind of artifact, in the order they are searched.
+    pub const ALL: [ArtifactKind; 4] = [
+        ArtifactKind::Rlib,
+        ArtifactKind::Rmeta,
+        ArtifactKind::Dylib,
+        ArtifactKind::Staticlib,
+    ];
+}
+
+/// A library file name split into its parts, e.g.
+/// `libfoo_bar-d15ea5e.rlib`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArtifactName {
+    /// The crate name, with `-` already replaced by `_`.
+    pub crate_name: String,
+    /// The metadata hash cargo passes as `-C extra-filename`, if any.
+    pub hash: Option<String>,
+    pub kind: ArtifactKind,
+}
+
+/// The platform-specific file naming rustc uses for library artifacts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArtifactNaming {
+    dylib_prefix: &'static str,
+    dylib_extension: &'static str,
+    staticlib_prefix: &'static str,
+    staticlib_extension: &'static str,
+}
+
+impl ArtifactNaming {
+    /// Determines the naming conventions from a target triple, e.g. the
+    /// `TARGET` environment variable of a build script.
+    pub fn for_target(target: &str) -> Self {
+        let windows = target.contains("windows");
+        let msvc = windows && target.ends_with("-msvc");
+        let apple = target.contains("-apple-");
+
+        ArtifactNaming {
+            dylib_prefix: if windows { "" } else { "lib" },
+            dylib_extension: if windows {
+                ".dll"
+            } else if apple {
+                ".dylib"
+            } else {
+                ".so"
+            },
+            staticlib_prefix: if msvc { "" } else { "lib" },
+            staticlib_extension: if msvc { ".lib" } else { ".a" },
+        }
+    }
+
+    /// The prefix rustc puts before the crate name.
+    pub fn prefix(&self, kind: ArtifactKind) -> &'static str {
+        match kind {
+            ArtifactKind::Rlib | ArtifactKind::Rmeta => "lib",
+            ArtifactKind::Dylib => self.dylib_prefix,
+            ArtifactKind::Staticlib => self.staticlib_prefix,
+        }
+    }
+
+    /// The extension rustc puts after the crate name, including the `.`.
+    pub fn extension(&self, kind: ArtifactKind) -> &'static str {
+        match kind {
+            ArtifactKind::Rlib => ".rlib",
+            ArtifactKind::Rmeta => ".rmeta",
+            ArtifactKind::Dylib => self.dylib_extension,
+            ArtifactKind::Staticlib => self.staticlib_extension,
+        }
+    }
+
+    /// The name of the archive entry that is delivered onto the artifact,
+    /// e.g. `export.rlib` or `export.so`.
+    pub fn export_name(&self, kind: ArtifactKind) -> String {
+        format!("export{}", self.extension(kind))
+    }
+
+    /// Returns the kind of artifact an archive entry exports, if any.
+    pub fn export_kind(&self, file_name: &str) -> Option<ArtifactKind> {
+        ArtifactKind::ALL.iter().cloned().find(|&kind| {
+            file_name == self.export_name(kind)
+        })
+    }
+
+    /// Splits a file name from the `deps` directory into its crate name, hash
+    /// and kind of artifact. Returns `None` for anything that isn't a library
+    /// artifact of the target, such as `.d` files or `foo.dll.lib` import
+    /// libraries.
+    pub fn parse(&self, file_name: &str) -> Option<ArtifactName> {
+        ArtifactKind::ALL.iter().filter_map(|&kind| {
+            let prefix = self.prefix(kind);
+            let extension = self.extension(kind);
+            if file_name.len() <= prefix.len() + extension.len() ||
+                !file_name.starts_with(prefix) || !file_name.ends_with(extension)
+            {
+                return None;
+            }
+            let stem = &file_name[prefix.len()..file_name.len() - extension.len()];
+
+            // Cargo replaces `-` with `_` in crate names, so the first `-`
+            // starts the hash.
+            let mut parts = stem.splitn(2, '-');
+            let crate_name = parts.next().unwrap_or(stem);
+            let hash = parts.next();
+
+            if !is_crate_name(crate_name) {
+                return None;
+            }
+            if let Some(hash) = hash {
+                if hash.is_empty() || !hash.chars().all(|c| c.is_ascii_alphanumeric()) {
+                    return None;
+                }
+            }
+
+            Some(ArtifactName {
+                crate_name: crate_name.to_owned(),
+                hash: hash.map(ToOwned::to_owned),
+                kind,
+            })
+        }).next()
+    }
+}
+
+fn is_crate_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ArtifactKind, ArtifactName, ArtifactNaming};
+
+    fn name(crate_name: &str, hash: Option<&str>, kind: ArtifactKind) -> Option<ArtifactName> {
+        Some(ArtifactName {
+            crate_name: crate_name.to_owned(),
+            hash: hash.map(ToOwned::to_owned),
+            kind,
+        })
+    }
+
+    #[test]
+    fn linux_naming() {
+        let n = ArtifactNaming::for_target("x86_64-unknown-linux-gnu");
+        assert_eq!(n.export_name(ArtifactKind::Rlib), "export.rlib");
+        assert_eq!(n.export_name(ArtifactKind::Rmeta), "export.rmeta");
+        assert_eq!(n.export_name(ArtifactKind::Dylib), "export.so");
+        assert_eq!(n.export_name(ArtifactKind::Staticlib), "export.a");
+
+        assert_eq!(
+            n.parse("libfoo-d15ea5e.so"),
+            name("foo", Some("d15ea5e"), ArtifactKind::Dylib)
+        );
+        assert_eq!(
+            n.parse("libfoo_bar-d15ea5e.a"),
+            name("foo_bar", Some("d15ea5e"), ArtifactKind::Staticlib)
+        );
+        assert_eq!(
+            n.parse("libfoo-d15ea5e.rlib"),
+            name("foo", Some("d15ea5e"), ArtifactKind::Rlib)
+        );
+        assert_eq!(
+            n.parse("libfoo-d15ea5e.rmeta"),
+            name("foo", Some("d15ea5e"), ArtifactKind::Rmeta)
+        );
+        assert_eq!(n.parse("foo-d15ea5e.d"), None);
+    }
+
+    #[test]
+    fn apple_naming() {
+        let n = ArtifactNaming::for_target("x86_64-apple-darwin");
+        assert_eq!(n.export_name(ArtifactKind::Dylib), "export.dylib");
+        assert_eq!(
+            n.parse("libfoo.dylib"),
+            name("foo", None, ArtifactKind::Dylib)
+        );
+    }
+
+    #[test]
+    fn windows_naming() {
+        let n = ArtifactNaming::for_target("x86_64-pc-windows-msvc");
+        assert_eq!(n.export_name(ArtifactKind::Dylib), "export.dll");
+        assert_eq!(n.export_name(ArtifactKind::Staticlib), "export.lib");
+        assert_eq!(
+            n.parse("foo-d15ea5e.dll"),
+            name("foo", Some("d15ea5e"), ArtifactKind::Dylib)
+        );
+        assert_eq!(
+            n.parse("foo-d15ea5e.lib"),
+            name("foo", Some("d15ea5e"), ArtifactKind::Staticlib)
+        );
+        assert_eq!(
+            n.parse("libc-d15ea5e.dll"),
+            name("libc", Some("d15ea5e"), ArtifactKind::Dylib)
+        );
+        assert_eq!(n.parse("foo-d15ea5e.dll.lib"), None);
+
+        let n = ArtifactNaming::for_target("x86_64-pc-windows-gnu");
+        assert_eq!(n.export_name(ArtifactKind::Staticlib), "export.a");
+        assert_eq!(
+            n.parse("libfoo-d15ea5e.a"),
+            name("foo", Some("d15ea5e"), ArtifactKind::Staticlib)
+        );
+    }
+
+    #[test]
+    fn parse_lib_names() {
+        let n = ArtifactNaming::for_target("x86_64-unknown-linux-gnu");
+        assert_eq!(
+            n.parse("liblibc-9a3a4a3fb8f2a5b4.rlib"),
+            name("libc", Some("9a3a4a3fb8f2a5b4"), ArtifactKind::Rlib)
+        );
+        assert_eq!(
+            n.parse("libzlib_sys-9a3a4a3fb8f2a5b4.rlib"),
+            name("zlib_sys", Some("9a3a4a3fb8f2a5b4"), ArtifactKind::Rlib)
+        );
+        assert_eq!(
+            n.parse("libmylib-9a3a4a3fb8f2a5b4.rlib"),
+            name("mylib", Some("9a3a4a3fb8f2a5b4"), ArtifactKind::Rlib)
+        );
+        assert_eq!(
+            n.parse("liblib.rlib"),
+            name("lib", None, ArtifactKind::Rlib)
+        );
+        assert_eq!(
+            n.parse("libfoo__bar_baz_-9a3a4a3fb8f2a5b4.rlib"),
+            name("foo__bar_baz_", Some("9a3a4a3fb8f2a5b4"), ArtifactKind::Rlib)
+        );
+    }
+
+    #[test]
+    fn parse_invalid_names() {
+        let n = ArtifactNaming::for_target("x86_64-unknown-linux-gnu");
+        assert_eq!(n.parse("foo-9a3a4a3fb8f2a5b4.rlib"), None);
+        assert_eq!(n.parse("lib.rlib"), None);
+        assert_eq!(n.parse("lib-9a3a4a3fb8f2a5b4.rlib"), None);
+        assert_eq!(n.parse("libfoo-.rlib"), None);
+        assert_eq!(n.parse("libfoo-bar-baz.rlib"), None);
+        assert_eq!(n.parse("lib1foo-9a3a4a3fb8f2a5b4.rlib"), None);
+        assert_eq!(n.parse("libfoo.so.1"), None);
+        assert_eq!(n.parse("libfoo-9a3a4a3fb8f2a5b4.d"), None);
+        assert_eq!(n.parse("libfoo-9a3a4a3fb8f2a5b4"), None);
+    }
+}
@@ -0,0 +1,21 @@
+extern crate dhl;
+
+use std::env::{args_os, set_var};
+use std::process::exit;
+
+/// Cargo runs `$RUSTC_WRAPPER path/to/rustc ARGS...` for every crate it
+/// compiles.
+fn main() {
+    let args = args_os().skip(1).collect::<Vec<_>>();
+    // The templates and lockfile ask the same rustc cargo does
+    if let Some(rustc) = args.first() {
+        set_var("RUSTC", rustc);
+    }
+    match dhl::wrap_rustc(args) {
+        Ok(code) => exit(code),
+        Err(e) => {
+            eprintln!("error: dhl-rustc-wrapper: {}", e);
+            exit(1);
+        }
+    }
+}
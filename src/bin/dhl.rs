@@ -0,0 +1,314 @@
+extern crate dhl;
+
+use std::env::{args, current_dir, var};
+#[cfg(feature = "encryption")]
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+const USAGE: &'static str = "\
+Usage:
+    dhl update [CRATE...]
+    dhl publish [OPTIONS] REGISTRY ARCHIVE...
+    dhl serve [OPTIONS] DIR
+    dhl vendor --target TARGET... [OPTIONS]
+    dhl keygen [--symmetric]
+    dhl encrypt KEY ARCHIVE OUTPUT
+
+Commands:
+    update    Forget the locked archives of the given crates, or of every
+              crate, so the next build records them again
+    publish   Upload archives to a directory or HTTP registry and add them to
+              its index.toml
+    serve     Serve a directory of archives over HTTP
+    vendor    Download the HTTP archives and indexes of the current package so
+              it builds offline
+    keygen    Print a new secret key, and its public key to stderr, or with
+              --symmetric a new symmetric key
+    encrypt   Encrypt an archive with a symmetric key, or to a public key
+
+Publish options:
+    --name NAME, --version VERSION, --target TARGET, --rustc VERSION,
+    --profile PROFILE
+              Describe the archive, overriding its export.toml
+    --token TOKEN
+              Bearer token for HTTP registries, defaults to $DHL_TOKEN
+    --force   Replace archives that are already published
+
+Serve options:
+    --addr ADDR
+              Address to listen on, defaults to 127.0.0.1:8080
+    --token TOKEN
+              Bearer token required of every request, defaults to $DHL_TOKEN
+    --writable
+              Accept PUT requests, e.g. from dhl publish
+
+Vendor options:
+    --target TARGET, --profile PROFILE
+              Vendor for these, may be repeated; at least one target is
+              needed and the profiles default to debug and release
+    --features FEATURES
+              Features of the package to vendor for, separated by commas
+    --set KEY=VALUE
+              Set a substitution, over the context of each target and profile
+    --dir DIR
+              Directory to vendor into, defaults to dhl-vendor
+";
+
+/// The `dhl.lock` next to the nearest `Cargo.lock`.
+fn find_lock_file() -> Option<PathBuf> {
+    let cwd = current_dir().ok();
+    let mut dir: Option<&Path> = cwd.as_ref().map(PathBuf::as_path);
+    while let Some(d) = dir {
+        if d.join("Cargo.lock").is_file() {
+            return Some(d.join(dhl::LOCK_FILE_NAME));
+        }
+        dir = d.parent();
+    }
+    None
+}
+
+fn update(crates: &[String]) -> Result<(), String> {
+    let lock_file = find_lock_file().ok_or(
+        "could not find Cargo.lock in this directory or any parent",
+    )?;
+    if !lock_file.is_file() {
+        return Ok(());
+    }
+    let removed = dhl::update_lock(&lock_file, crates).map_err(|e| e.to_string())?;
+    println!("Removed {} entries from {}", removed, lock_file.display());
+    Ok(())
+}
+
+fn publish(args: &[String]) -> Result<(), String> {
+    let mut overrides = dhl::ArchiveMetadata::default();
+    let mut token = var("DHL_TOKEN").ok();
+    let mut force = false;
+    let mut positional = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let field = match arg.as_str() {
+            "--force" => {
+                force = true;
+                continue;
+            }
+            "--name" => &mut overrides.name,
+            "--version" => &mut overrides.version,
+            "--target" => &mut overrides.target,
+            "--rustc" => &mut overrides.rustc,
+            "--profile" => &mut overrides.profile,
+            "--token" => &mut token,
+            _ if arg.starts_with("--") => return Err(format!("unknown option '{}'", arg)),
+            _ => {
+                positional.push(arg);
+                continue;
+            }
+        };
+        *field = Some(args.next().ok_or_else(|| format!("'{}' needs a value", arg))?.clone());
+    }
+
+    let (registry, archives) = match positional.split_first() {
+        Some((registry, archives)) if !archives.is_empty() => (registry, archives),
+        _ => return Err("publish needs a registry and at least one archive".into()),
+    };
+    let registry = dhl::Registry::new(registry.as_str(), token);
+    for archive in archives {
+        let entry = registry
+            .publish(Path::new(archive), &overrides, force)
+            .map_err(|e| e.to_string())?;
+        println!(
+            "Published {} {} for {} with rustc {} as {}",
+            entry.name,
+            entry.version,
+            entry.target,
+            entry.rustc,
+            entry.source
+        );
+    }
+    Ok(())
+}
+
+fn serve(args: &[String]) -> Result<(), String> {
+    let mut addr = "127.0.0.1:8080".to_owned();
+    let mut token = var("DHL_TOKEN").ok();
+    let mut writable = false;
+    let mut dir = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--writable" => writable = true,
+            "--addr" | "--token" => {
+                let value = args.next().ok_or_else(|| format!("'{}' needs a value", arg))?;
+                if arg == "--addr" {
+                    addr = value.clone();
+                } else {
+                    token = Some(value.clone());
+                }
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option '{}'", arg)),
+            _ if dir.is_none() => dir = Some(arg),
+            _ => return Err(format!("unexpected argument '{}'", arg)),
+        }
+    }
+
+    let dir = dir.ok_or("serve needs a directory")?;
+    let server = dhl::Server::bind(addr.as_str(), dir.as_str())
+        .map_err(|e| format!("failed to listen on {}: {}", addr, e))?
+        .token(token)
+        .writable(writable);
+    let addr = server.local_addr().map_err(|e| e.to_string())?;
+    println!("Serving {} on http://{}/", dir, addr);
+    server
+        .run(|e| eprintln!("dhl serve: {}", e))
+        .map_err(|e| e.to_string())
+}
+
+/// The directory of the nearest `Cargo.toml`.
+#[cfg(feature = "reqwest")]
+fn find_manifest_dir() -> Option<PathBuf> {
+    let cwd = current_dir().ok();
+    let mut dir: Option<&Path> = cwd.as_ref().map(PathBuf::as_path);
+    while let Some(d) = dir {
+        if d.join("Cargo.toml").is_file() {
+            return Some(d.to_owned());
+        }
+        dir = d.parent();
+    }
+    None
+}
+
+#[cfg(feature = "reqwest")]
+fn vendor(args: &[String]) -> Result<(), String> {
+    let mut targets = Vec::new();
+    let mut profiles = Vec::new();
+    let mut substitutions = Vec::new();
+    let mut features = Vec::new();
+    let mut dir = dhl::VENDOR_DIR_NAME.to_owned();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = match arg.as_str() {
+            "--target" | "--profile" | "--features" | "--set" | "--dir" => {
+                args.next().ok_or_else(|| format!("'{}' needs a value", arg))?.clone()
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option '{}'", arg)),
+            _ => return Err(format!("unexpected argument '{}'", arg)),
+        };
+        match arg.as_str() {
+            "--target" => targets.push(value),
+            "--profile" => profiles.push(value),
+            "--features" => {
+                features.extend(
+                    value.split(',').map(str::trim).filter(|f| !f.is_empty()).map(String::from),
+                )
+            }
+            "--dir" => dir = value,
+            _ => {
+                let mut split = value.splitn(2, '=');
+                match (split.next(), split.next()) {
+                    (Some(k), Some(v)) => substitutions.push((k.to_owned(), v.to_owned())),
+                    _ => return Err(format!("'--set {}' is not KEY=VALUE", value)),
+                }
+            }
+        }
+    }
+    if targets.is_empty() {
+        return Err("vendor needs at least one --target".into());
+    }
+    if profiles.is_empty() {
+        profiles = vec!["debug".into(), "release".into()];
+    }
+
+    let manifest_dir = find_manifest_dir().ok_or(
+        "could not find Cargo.toml in this directory or any parent",
+    )?;
+    let mut vendor = dhl::Vendor::new(&manifest_dir, &dir);
+    for (k, v) in substitutions {
+        vendor.substitute(k, v);
+    }
+    for feature in features {
+        vendor.feature(feature);
+    }
+    let mut cells = Vec::new();
+    for target in &targets {
+        for profile in &profiles {
+            cells.push(dhl::Cell {
+                target: target.clone(),
+                profile: profile.clone(),
+            });
+        }
+    }
+    let report = vendor.vendor(&cells).map_err(|e| e.to_string())?;
+    for missing in &report.missing {
+        eprintln!("warning: no archive of {}", missing);
+    }
+    println!(
+        "Vendored {} files into {}",
+        report.files.len(),
+        manifest_dir.join(&dir).display()
+    );
+    println!("To build from them, add to dhl.toml or [package.metadata.dhl]:");
+    println!("    vendor-dir = {:?}", dir);
+    Ok(())
+}
+
+#[cfg(feature = "encryption")]
+fn keygen(args: &[String]) -> Result<(), String> {
+    let key = match args.first().map(String::as_str) {
+        None => dhl::Key::generate_secret(),
+        Some("--symmetric") if args.len() == 1 => dhl::Key::generate_symmetric(),
+        Some(arg) => return Err(format!("unexpected argument '{}'", arg)),
+    }.map_err(|e| e.to_string())?;
+    if let dhl::Key::Secret(_) = key {
+        eprintln!("# public key: {}", key.public());
+    }
+    println!("{}", key);
+    Ok(())
+}
+
+#[cfg(feature = "encryption")]
+fn encrypt(args: &[String]) -> Result<(), String> {
+    if args.len() != 3 {
+        return Err("encrypt needs a key, an archive and an output file".into());
+    }
+    let (key, archive, output) = (&args[0], &args[1], &args[2]);
+    let key = dhl::Key::parse(key).map_err(|e| e.to_string())?;
+    let archive = File::open(archive).map_err(|e| format!("failed to open '{}': {}", archive, e))?;
+    let output = File::create(output).map_err(|e| format!("failed to create '{}': {}", output, e))?;
+    dhl::encrypt(&key, archive, output).map_err(|e| e.to_string())
+}
+
+#[cfg(not(feature = "reqwest"))]
+fn vendor(_: &[String]) -> Result<(), String> {
+    Err("dhl was built without the reqwest feature, so it cannot vendor".into())
+}
+
+#[cfg(not(feature = "encryption"))]
+fn keygen(_: &[String]) -> Result<(), String> {
+    Err("dhl was built without the encryption feature, so it cannot make keys".into())
+}
+
+#[cfg(not(feature = "encryption"))]
+fn encrypt(_: &[String]) -> Result<(), String> {
+    Err("dhl was built without the encryption feature, so it cannot encrypt".into())
+}
+
+fn main() {
+    dhl::Reporter::Stderr.install();
+    let args = args().skip(1).collect::<Vec<_>>();
+    let result = match args.split_first() {
+        Some((command, crates)) if command == "update" => update(crates),
+        Some((command, args)) if command == "publish" => publish(args),
+        Some((command, args)) if command == "serve" => serve(args),
+        Some((command, args)) if command == "vendor" => vendor(args),
+        Some((command, args)) if command == "keygen" => keygen(args),
+        Some((command, args)) if command == "encrypt" => encrypt(args),
+        _ => {
+            eprint!("{}", USAGE);
+            exit(1);
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        exit(1);
+    }
+}
@@ -0,0 +1,535 @@
+use std::collections::{BTreeMap, HashMap};
+use std::iter::once;
+use std::path::{Path, PathBuf};
+
+use semver::{Version, VersionReq};
+use toml;
+
+use env::Env;
+use manifest::{ManifestCreationError, read_file};
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum TomlDependency {
+    String(String),
+    Table {
+        version: Option<String>,
+        path: Option<String>,
+        package: Option<String>,
+        #[serde(default)]
+        workspace: bool,
+    },
+}
+
+/// The dependency tables of a `Cargo.toml`.
+#[derive(Deserialize, Debug, Default)]
+pub struct TomlDependencies {
+    #[serde(default)]
+    dependencies: HashMap<String, TomlDependency>,
+    #[serde(default, rename = "build-dependencies")]
+    build_dependencies: HashMap<String, TomlDependency>,
+    /// Sorted, so a dependency in several `[target.*]` tables always
+    /// resolves to the same one.
+    #[serde(default)]
+    target: BTreeMap<String, TomlTargetDependencies>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct TomlTargetDependencies {
+    #[serde(default)]
+    dependencies: HashMap<String, TomlDependency>,
+    #[serde(default, rename = "build-dependencies")]
+    build_dependencies: HashMap<String, TomlDependency>,
+}
+
+/// Just enough of a dummy crate's `Cargo.toml` to know what it builds.
+#[derive(Deserialize, Debug)]
+struct TomlDummy {
+    package: Option<TomlDummyPackage>,
+    lib: Option<TomlDummyLib>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TomlDummyPackage {
+    name: String,
+    /// Usually a string, but may be `{ workspace = true }`.
+    version: Option<toml::Value>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TomlDummyLib {
+    name: Option<String>,
+}
+
+/// The resolved packages of a `Cargo.lock`.
+#[derive(Deserialize, Debug, Default)]
+struct TomlLock {
+    #[serde(default)]
+    package: Vec<TomlLockPackage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TomlLockPackage {
+    name: String,
+    version: String,
+}
+
+/// A dependency, resolved to the package it refers to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dependency {
+    /// The version requirement, e.g. `^3.2`.
+    pub version: Option<String>,
+    /// The name of the package, which differs from the name of the
+    /// dependency if it was renamed with `package = "..."`.
+    pub package: String,
+    /// The directory of a path dependency.
+    pub path: Option<PathBuf>,
+}
+
+impl Dependency {
+    fn new(name: &str, dependency: &TomlDependency, dir: &Path) -> Self {
+        match *dependency {
+            TomlDependency::String(ref version) => {
+                Dependency {
+                    version: Some(version.clone()),
+                    package: name.to_owned(),
+                    path: None,
+                }
+            }
+            TomlDependency::Table {
+                ref version,
+                ref path,
+                ref package,
+                ..
+            } => {
+                Dependency {
+                    version: version.clone(),
+                    package: package.as_ref().map_or(name, AsRef::as_ref).to_owned(),
+                    path: path.as_ref().map(|path| dir.join(path)),
+                }
+            }
+        }
+    }
+
+    fn read_dummy(&self) -> Result<Option<TomlDummy>, ManifestCreationError> {
+        if let Some(ref path) = self.path {
+            let manifest_file = path.join("Cargo.toml");
+            if manifest_file.is_file() {
+                return Ok(Some(toml::from_str(&*read_file(&manifest_file)?)?));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The name of the library the package builds, which its artifacts are
+    /// named after. Read from the package's `Cargo.toml` for path
+    /// dependencies, since `[lib] name` can differ from the package name.
+    pub fn lib_name(&self) -> Result<String, ManifestCreationError> {
+        if let Some(TomlDummy { package, lib }) = self.read_dummy()? {
+            let name = lib.and_then(|lib| lib.name).or_else(
+                || package.map(|package| package.name),
+            );
+            if let Some(name) = name {
+                return Ok(name.replace('-', "_"));
+            }
+        }
+        Ok(self.package.replace('-', "_"))
+    }
+}
+
+/// A `cfg(...)` expression of a `[target.'cfg(...)']` table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Cfg {
+    Name(String),
+    KeyValue(String, String),
+    All(Vec<Cfg>),
+    Any(Vec<Cfg>),
+    Not(Box<Cfg>),
+}
+
+impl Cfg {
+    /// Parses the expression inside `cfg(...)`, e.g.
+    /// `all(unix, target_arch = "x86_64")`.
+    fn parse(expr: &str) -> Option<Cfg> {
+        let (cfg, rest) = Self::parse_expr(expr)?;
+        if rest.trim().is_empty() { Some(cfg) } else { None }
+    }
+
+    fn parse_expr(expr: &str) -> Option<(Cfg, &str)> {
+        let expr = expr.trim_start();
+        let end = expr
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(expr.len());
+        if end == 0 {
+            return None;
+        }
+        let (name, rest) = (&expr[..end], expr[end..].trim_start());
+
+        if rest.starts_with('=') {
+            let rest = rest[1..].trim_start();
+            if !rest.starts_with('"') {
+                return None;
+            }
+            let close = rest[1..].find('"')? + 1;
+            let cfg = Cfg::KeyValue(name.to_owned(), rest[1..close].to_owned());
+            return Some((cfg, &rest[close + 1..]));
+        }
+        if !rest.starts_with('(') {
+            return Some((Cfg::Name(name.to_owned()), rest));
+        }
+
+        let mut args = Vec::new();
+        let mut rest = rest[1..].trim_start();
+        while !rest.starts_with(')') {
+            let (arg, after) = Self::parse_expr(rest)?;
+            args.push(arg);
+            rest = after.trim_start();
+            if rest.starts_with(',') {
+                rest = rest[1..].trim_start();
+            } else if !rest.starts_with(')') {
+                return None;
+            }
+        }
+        let cfg = match name {
+            "all" => Cfg::All(args),
+            "any" => Cfg::Any(args),
+            "not" if args.len() == 1 => Cfg::Not(Box::new(args.remove(0))),
+            _ => return None,
+        };
+        Some((cfg, &rest[1..]))
+    }
+
+    /// Evaluates the expression against the `CARGO_CFG_*` variables.
+    fn matches(&self, env: &Env) -> bool {
+        let var = |name: &str| env.var(&format!("CARGO_CFG_{}", name.to_uppercase())).ok();
+        match *self {
+            Cfg::Name(ref name) => var(name).is_some(),
+            Cfg::KeyValue(ref key, ref value) => {
+                var(key).map_or(false, |values| values.split(',').any(|v| v == value))
+            }
+            Cfg::All(ref cfgs) => cfgs.iter().all(|cfg| cfg.matches(env)),
+            Cfg::Any(ref cfgs) => cfgs.iter().any(|cfg| cfg.matches(env)),
+            Cfg::Not(ref cfg) => !cfg.matches(env),
+        }
+    }
+}
+
+/// Whether the dependencies of `[target.<key>]` apply to the target being
+/// built, `key` being its triple or a `cfg(...)` expression. Every table
+/// applies when `TARGET` isn't set, since there's no telling.
+fn target_applies(key: &str, env: &Env) -> bool {
+    let target = match env.var("TARGET") {
+        Ok(target) => target,
+        Err(_) => return true,
+    };
+    if key.starts_with("cfg(") && key.ends_with(')') {
+        Cfg::parse(&key[4..key.len() - 1]).map_or(false, |cfg| cfg.matches(env))
+    } else {
+        key == target
+    }
+}
+
+/// Every dependency a package declares, including those it inherits from
+/// its workspace.
+#[derive(Debug)]
+pub struct Dependencies {
+    manifest_dir: PathBuf,
+    tables: TomlDependencies,
+    workspace: Option<(PathBuf, HashMap<String, TomlDependency>)>,
+    lock: TomlLock,
+    env: Env,
+}
+
+impl Dependencies {
+    /// Collects the dependencies, along with the `Cargo.lock` of the
+    /// workspace (or package) if there is one. `env` decides which
+    /// `[target.*]` tables apply.
+    pub fn new(
+        manifest_dir: PathBuf,
+        tables: TomlDependencies,
+        workspace: Option<(PathBuf, HashMap<String, TomlDependency>)>,
+        env: Env,
+    ) -> Result<Self, ManifestCreationError> {
+        let lock_file = workspace
+            .as_ref()
+            .map_or(&manifest_dir, |&(ref dir, _)| dir)
+            .join("Cargo.lock");
+        let lock = if lock_file.is_file() {
+            toml::from_str(&*read_file(&lock_file)?)?
+        } else {
+            TomlLock::default()
+        };
+
+        Ok(Dependencies {
+            manifest_dir,
+            tables,
+            workspace,
+            lock,
+            env,
+        })
+    }
+
+    /// The actual version of the package a dependency resolves to. Read from
+    /// the package's `Cargo.toml` for path dependencies, otherwise the
+    /// newest matching version in `Cargo.lock`.
+    pub fn package_version(
+        &self,
+        dependency: &Dependency,
+    ) -> Result<Option<String>, ManifestCreationError> {
+        let version = dependency.read_dummy()?.and_then(|dummy| dummy.package).and_then(
+            |package| package.version,
+        );
+        if let Some(toml::Value::String(version)) = version {
+            return Ok(Some(version));
+        }
+
+        let requirement = dependency.version.as_ref().and_then(
+            |v| VersionReq::parse(v).ok(),
+        );
+        let locked = self.lock
+            .package
+            .iter()
+            .filter(|package| package.name == dependency.package)
+            .filter_map(|package| Version::parse(&package.version).ok())
+            .filter(|version| requirement.as_ref().map_or(true, |r| r.matches(version)))
+            .max();
+        Ok(locked.map(|version| version.to_string()))
+    }
+
+    /// Finds a dependency by name, searching `[dependencies]`, then
+    /// `[build-dependencies]`, each followed by the `[target.*]` tables that
+    /// apply to the target in the order of their names.
+    pub fn get(&self, name: &str) -> Option<Dependency> {
+        let targets = self.tables
+            .target
+            .iter()
+            .filter(|&(key, _)| target_applies(key, &self.env))
+            .map(|(_, table)| table)
+            .collect::<Vec<_>>();
+        let mut tables = once(&self.tables.dependencies)
+            .chain(targets.iter().map(|t| &t.dependencies))
+            .chain(once(&self.tables.build_dependencies))
+            .chain(targets.iter().map(|t| &t.build_dependencies));
+
+        tables.find(|table| table.contains_key(name)).map(|table| {
+            match table[name] {
+                TomlDependency::Table { workspace: true, .. } => self.get_workspace(name),
+                ref dependency => Dependency::new(name, dependency, &self.manifest_dir),
+            }
+        })
+    }
+
+    /// Finds a dependency inherited with `workspace = true`.
+    fn get_workspace(&self, name: &str) -> Dependency {
+        let inherited = self.workspace.as_ref().and_then(|&(ref dir, ref dependencies)| {
+            dependencies.get(name).map(|dependency| Dependency::new(name, dependency, dir))
+        });
+        inherited.unwrap_or_else(|| {
+            Dependency {
+                version: None,
+                package: name.to_owned(),
+                path: None,
+            }
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use tempdir::TempDir;
+    use toml;
+
+    use env::Env;
+    use super::{Cfg, Dependencies, Dependency, TomlDependencies, TomlDependency};
+
+    const DEPENDENCIES: &'static str = r#"
+[dependencies]
+foo = "0.4.5"
+bar = { version = "^3.2", optional = true }
+renamed = { package = "real-name", version = "1.0" }
+inherited = { workspace = true }
+
+[build-dependencies]
+priv = { path = "priv" }
+
+[target.'cfg(windows)'.dependencies]
+winpriv = { version = "2.0", path = "winpriv" }
+both = "2.0"
+
+[target.'cfg(unix)'.dependencies]
+both = "1.0"
+
+[target.'cfg(all(unix, not(target_os = "macos")))'.dependencies]
+unixpriv = "5.0"
+
+[target.x86_64-unknown-linux-gnu.build-dependencies]
+linuxpriv = "3.0"
+
+[target.aarch64-apple-darwin.build-dependencies]
+macpriv = "6.0"
+"#;
+
+    const LOCK: &'static str = r#"
+[[package]]
+name = "priv"
+version = "1.3.0"
+
+[[package]]
+name = "priv"
+version = "2.0.0"
+
+[[package]]
+name = "other"
+version = "1.4.0"
+"#;
+
+    const WORKSPACE_DEPENDENCIES: &'static str = r#"
+inherited = { version = "4.0", path = "crates/inherited" }
+"#;
+
+    fn dependency(version: Option<&str>, package: &str, path: Option<PathBuf>) -> Dependency {
+        Dependency {
+            version: version.map(ToOwned::to_owned),
+            package: package.to_owned(),
+            path,
+        }
+    }
+
+    #[test]
+    fn resolve_dependencies() {
+        let manifest_dir = PathBuf::from("member");
+        let workspace_dir = PathBuf::from("workspace");
+        let tables = toml::from_str::<TomlDependencies>(DEPENDENCIES).unwrap();
+        let workspace = toml::from_str::<HashMap<String, TomlDependency>>(WORKSPACE_DEPENDENCIES)
+            .unwrap();
+        let env = Env::with_vars(vec![
+            ("TARGET", "x86_64-unknown-linux-gnu"),
+            ("CARGO_CFG_UNIX", ""),
+            ("CARGO_CFG_TARGET_OS", "linux"),
+        ]);
+        let d = Dependencies::new(
+            manifest_dir.clone(),
+            tables,
+            Some((workspace_dir.clone(), workspace)),
+            env,
+        ).unwrap();
+
+        assert_eq!(d.get("foo"), Some(dependency(Some("0.4.5"), "foo", None)));
+        assert_eq!(d.get("bar"), Some(dependency(Some("^3.2"), "bar", None)));
+        assert_eq!(
+            d.get("renamed"),
+            Some(dependency(Some("1.0"), "real-name", None))
+        );
+        assert_eq!(
+            d.get("inherited"),
+            Some(dependency(
+                Some("4.0"),
+                "inherited",
+                Some(workspace_dir.join("crates/inherited")),
+            ))
+        );
+        assert_eq!(
+            d.get("priv"),
+            Some(dependency(None, "priv", Some(manifest_dir.join("priv"))))
+        );
+        assert_eq!(
+            d.get("unixpriv"),
+            Some(dependency(Some("5.0"), "unixpriv", None))
+        );
+        assert_eq!(
+            d.get("linuxpriv"),
+            Some(dependency(Some("3.0"), "linuxpriv", None))
+        );
+        assert_eq!(d.get("both"), Some(dependency(Some("1.0"), "both", None)));
+        assert_eq!(d.get("missing"), None);
+
+        // Tables for other targets are skipped
+        assert_eq!(d.get("winpriv"), None);
+        assert_eq!(d.get("macpriv"), None);
+
+        assert_eq!(d.get("renamed").unwrap().lib_name().unwrap(), "real_name");
+
+        // Without a target, every table applies
+        let d = Dependencies::new(
+            manifest_dir.clone(),
+            toml::from_str::<TomlDependencies>(DEPENDENCIES).unwrap(),
+            None,
+            Env::with_vars(Vec::<(String, String)>::new()),
+        ).unwrap();
+        assert_eq!(
+            d.get("winpriv"),
+            Some(dependency(
+                Some("2.0"),
+                "winpriv",
+                Some(manifest_dir.join("winpriv")),
+            ))
+        );
+        assert_eq!(d.get("macpriv"), Some(dependency(Some("6.0"), "macpriv", None)));
+    }
+
+    #[test]
+    fn parse_cfg() {
+        assert_eq!(
+            Cfg::parse(r#"all(unix, not(target_env = "musl"))"#),
+            Some(Cfg::All(vec![
+                Cfg::Name("unix".to_owned()),
+                Cfg::Not(Box::new(Cfg::KeyValue("target_env".to_owned(), "musl".to_owned()))),
+            ]))
+        );
+        assert_eq!(Cfg::parse("any()"), Some(Cfg::Any(vec![])));
+        assert_eq!(Cfg::parse("not(unix, windows)"), None);
+        assert_eq!(Cfg::parse("unix windows"), None);
+        assert_eq!(Cfg::parse(r#"target_os = "linux"#), None);
+
+        let env = Env::with_vars(vec![("CARGO_CFG_TARGET_FAMILY", "unix,wasm")]);
+        assert!(Cfg::parse(r#"target_family = "wasm""#).unwrap().matches(&env));
+        assert!(!Cfg::parse(r#"target_family = "windows""#).unwrap().matches(&env));
+    }
+
+    #[test]
+    fn dummy_lib_name() {
+        let d = dependency(
+            None,
+            "renamed",
+            Some(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("dhltest-dash")),
+        );
+        assert_eq!(d.lib_name().unwrap(), "dhltest_dash");
+    }
+
+    #[test]
+    fn package_versions() {
+        let base_dir = TempDir::new("package").unwrap();
+        File::create(base_dir.path().join("Cargo.lock"))
+            .unwrap()
+            .write_all(LOCK.as_bytes())
+            .unwrap();
+        let d = Dependencies::new(
+            base_dir.path().to_path_buf(),
+            TomlDependencies::default(),
+            None,
+            Env::with_vars(Vec::<(String, String)>::new()),
+        ).unwrap();
+
+        let dummy = dependency(
+            Some("1.0"),
+            "dhltest-dash",
+            Some(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("dhltest-dash")),
+        );
+        assert_eq!(d.package_version(&dummy).unwrap(), Some("1.0.0".to_owned()));
+
+        let locked = dependency(Some("^1.2"), "priv", None);
+        assert_eq!(d.package_version(&locked).unwrap(), Some("1.3.0".to_owned()));
+        let locked = dependency(None, "priv", None);
+        assert_eq!(d.package_version(&locked).unwrap(), Some("2.0.0".to_owned()));
+        let locked = dependency(Some("^3"), "priv", None);
+        assert_eq!(d.package_version(&locked).unwrap(), None);
+
+        base_dir.close().unwrap();
+    }
+}
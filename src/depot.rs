@@ -1,73 +1,133 @@
-use std::fs::File;
-use std::io::{self, Read};
-use std::path::PathBuf;
+use std::fs::{File, create_dir_all};
+use std::io::{self, ErrorKind, Read};
+use std::path::{Path, PathBuf};
 #[cfg(feature = "reqwest")]
 use std::sync::Arc;
 
 #[cfg(feature = "reqwest")]
-use reqwest::{self, Client as HttpClient, Method, Request};
+use reqwest::{self, Client as HttpClient, Method, Request, StatusCode};
+#[cfg(feature = "reqwest")]
+use reqwest::header::{Authorization, Bearer};
 use quick_error::ResultExt;
+use toml::{self, de};
 
-use manifest::{FileData, Package, Packages, PackageData};
+use manifest::{FileData, MissingData, Package, Packages, PackageData};
 #[cfg(feature = "reqwest")]
 use manifest::UrlData;
-use recipients::Recipients;
-
-const DEFAULT_EXPORT: &'static str = "export.rlib";
-
 #[cfg(feature = "reqwest")]
+use oci::{self, Fetched, OciError};
+use artifact::ArtifactNaming;
+#[cfg(feature = "encryption")]
+use encryption::{self, EncryptionError, Key};
+#[cfg(feature = "reqwest")]
+use env::Env;
+use lock::{self, LockError, Lockfile};
+use metadata::{ArchiveMetadata, METADATA_NAME, NATIVE_DIR, is_encrypted};
+use recipients::{Recipient, Recipients, RecipientsError};
+use report;
+use version::{VersionError, check_archive, check_requirement};
+
 quick_error! {
     #[derive(Debug)]
     pub enum DepotError {
         FileError(crate_name: String, source: FileData, err: io::Error) {
-            context(context: (&'a str, FileData), err: io::Error) ->
-                (context.0.to_owned(), context.1, err)
+            context(context: (&'a str, &'a FileData), err: io::Error) ->
+                (context.0.to_owned(), context.1.clone(), err)
             description("file depot io error")
             display("File Depot failed to acquire '{}' from '{}' with I/O error: {}",
                 crate_name, source.source.display(), err)
             cause(err)
         }
+        #[cfg(feature = "reqwest")]
         TlsError(err: Arc<reqwest::Error>) {
-            from()
             description("tls backend error")
             display("Failed to create TLS backend")
             cause(err.as_ref())
         }
+        #[cfg(feature = "reqwest")]
         HttpError(crate_name: String, source: UrlData, err: reqwest::Error) {
-            context(context: (&'a str, UrlData), err: reqwest::Error) ->
-                (context.0.to_owned(), context.1, err)
             description("depot url error")
             display("Error parsing from url: {}", err)
             cause(err)
         }
+        #[cfg(feature = "reqwest")]
+        HttpReadError(crate_name: String, source: UrlData, err: io::Error) {
+            description("depot url read error")
+            display("Failed to download '{}' from '{}' with I/O error: {}",
+                crate_name, source.source, err)
+            cause(err)
+        }
+        #[cfg(feature = "reqwest")]
+        HttpStatusError(crate_name: String, source: UrlData, status: StatusCode) {
+            description("depot url status error")
+            display("Failed to download '{}' from '{}': {}", crate_name, source.source, status)
+        }
+        #[cfg(feature = "reqwest")]
+        OciError(crate_name: String, source: UrlData, err: OciError) {
+            description("depot oci error")
+            display("Failed to download '{}' from '{}': {}", crate_name, source.source, err)
+            cause(err)
+        }
         MissingLibraryFile(crate_name: String) {
             description("missing library file")
             display("No local library file to inject onto")
         }
-        ArchiveError(err: ArchiveError) {
+        DummyNotReplaced(crate_name: String, sources: Vec<PathBuf>) {
+            description("crate to build from source was built from its dummy")
+            display("crate '{}' is set to build from source, but was built from the empty dummy \
+                {:?}, patch in the real source with `[patch]`", crate_name, sources)
+        }
+        MissingArchive(crate_name: String, source: String, available: Vec<String>) {
+            description("no archive for the target and toolchain")
+            display("crate '{}' has no archive at '{}', {}", crate_name, source,
+                describe_available(available))
+        }
+        WrongPackage(crate_name: String, name: String) {
+            description("archive is of another package")
+            display("crate '{}' archive was exported from '{}'", crate_name, name)
+        }
+        DigestMismatch(crate_name: String, expected: String, actual: String) {
+            description("archive digest does not match the index")
+            display("crate '{}' archive has sha256 {}, but its index lists {}",
+                crate_name, actual, expected)
+        }
+        MissingKey(crate_name: String) {
+            description("no key for an encrypted archive")
+            display("crate '{}' archive is encrypted, but the package has no `key`", crate_name)
+        }
+        WrongKey(crate_name: String, reason: &'static str) {
+            description("wrong key for an encrypted archive")
+            display("crate '{}' archive was not encrypted with its `key`: {}", crate_name, reason)
+        }
+        #[cfg(feature = "encryption")]
+        EncryptionError(crate_name: String, err: EncryptionError) {
+            description("encrypted archive error")
+            display("crate '{}' archive failed to decrypt: {}", crate_name, err)
+            cause(err)
+        }
+        #[cfg(not(feature = "encryption"))]
+        EncryptionUnsupported(crate_name: String) {
+            description("encrypted archives unsupported")
+            display("crate '{}' archive is encrypted, which needs dhl's encryption feature",
+                crate_name)
+        }
+        RecipientsError(err: RecipientsError) {
             from()
-            description("missing library file")
-            display("No local library file to inject onto")
+            description("recipients error")
+            display("Failed to find library file: {}", err)
             cause(err)
         }
-    }
-}
-
-#[cfg(not(feature = "reqwest"))]
-quick_error! {
-    #[derive(Debug)]
-    pub enum DepotError {
-        FileError(crate_name: String, source: FileData, err: io::Error) {
-            context(context: (&'a str, &'a FileData), err: io::Error) ->
-                (context.0.to_owned(), context.1.clone(), err)
-            description("file depot io error")
-            display("File Depot failed to acquire '{}' from '{}' with I/O error: {}",
-                crate_name, source.source.display(), err)
+        VersionError(err: VersionError) {
+            from()
+            description("version error")
+            display("Version check failed: {}", err)
             cause(err)
         }
-        MissingLibraryFile(crate_name: String) {
-            description("missing library file")
-            display("No local library file to inject onto")
+        LockError(err: LockError) {
+            from()
+            description("lock error")
+            display("Lockfile check failed: {}", err)
+            cause(err)
         }
         ArchiveError(err: ArchiveError) {
             from()
@@ -77,6 +137,7 @@ quick_error! {
         }
     }
 }
+
 quick_error! {
     #[derive(Debug)]
     pub enum ArchiveError {
@@ -103,6 +164,92 @@ quick_error! {
             display("Tar entry for '{}' did not have a file name in '{}'",
                 crate_name, path.display())
         }
+        MetadataError(crate_name: String, err: de::Error) {
+            description("archive metadata error")
+            display("Metadata of '{}' failed to parse: {}", crate_name, err)
+            cause(err)
+        }
+        NativeDirError(crate_name: String, path: PathBuf, err: io::Error) {
+            description("native dir io error")
+            display("Failed to create native library directory '{}' for '{}' with I/O error: {}",
+                path.display(), crate_name, err)
+            cause(err)
+        }
+    }
+}
+
+/// What to do when a package's archive doesn't exist, e.g. because nothing
+/// was exported for the current target or rustc yet.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MissingPolicy {
+    /// Fail with `DepotError::MissingArchive`.
+    Error,
+    /// Warn and leave the dummy crate as it is.
+    Warn,
+    /// Build the crate from source instead, as if it was listed in
+    /// `from-source`.
+    FallbackSource,
+}
+
+impl Default for MissingPolicy {
+    fn default() -> Self {
+        MissingPolicy::Error
+    }
+}
+
+/// Lists the builds that do exist, for `DepotError::MissingArchive`.
+fn describe_available(available: &[String]) -> String {
+    if available.is_empty() {
+        "and no other builds were found".to_owned()
+    } else {
+        format!("but there are builds for '{}'", available.join("', '"))
+    }
+}
+
+/// What a `Depot` delivered, and what the build needs to link against it.
+#[derive(Debug, Clone, Default)]
+pub struct Receipt {
+    /// Directories native libraries were delivered to.
+    pub link_search: Vec<PathBuf>,
+    /// Native libraries to link, in the `[KIND=]NAME` form of
+    /// `cargo:rustc-link-lib`.
+    pub link_libs: Vec<String>,
+}
+
+impl Receipt {
+    fn add_link_search(&mut self, dir: PathBuf) {
+        if !self.link_search.contains(&dir) {
+            self.link_search.push(dir);
+        }
+    }
+
+    fn add_link_libs<I: IntoIterator<Item = String>>(&mut self, libs: I) {
+        for lib in libs {
+            if !self.link_libs.contains(&lib) {
+                self.link_libs.push(lib);
+            }
+        }
+    }
+
+    /// Prints the `cargo:rustc-link-search` and `cargo:rustc-link-lib`
+    /// directives for the delivered native libraries.
+    pub fn print_link_directives(&self) {
+        for dir in self.link_search.iter() {
+            println!("cargo:rustc-link-search=native={}", dir.display());
+        }
+        for lib in self.link_libs.iter() {
+            println!("cargo:rustc-link-lib={}", lib);
+        }
+    }
+
+    /// The same as `print_link_directives`, as arguments to rustc.
+    pub fn link_args(&self) -> Vec<String> {
+        let search = self.link_search.iter().map(|dir| {
+            format!("-Lnative={}", dir.display())
+        });
+        let libs = self.link_libs.iter().map(|lib| format!("-l{}", lib));
+        search.chain(libs).collect()
     }
 }
 
@@ -111,6 +258,9 @@ quick_error! {
 pub struct Depot {
     #[cfg(feature = "reqwest")]
     http_client: Result<HttpClient, Arc<reqwest::Error>>,
+    /// The target `oci://` layers are selected for, from `TARGET`.
+    #[cfg(feature = "reqwest")]
+    target: Option<String>,
 }
 
 impl Depot {
@@ -118,31 +268,276 @@ impl Depot {
         Depot {
             #[cfg(feature = "reqwest")]
             http_client: HttpClient::new().map_err(Arc::new),
+            #[cfg(feature = "reqwest")]
+            target: Env::process().var("TARGET").ok(),
+        }
+    }
+
+    /// A depot for `target` rather than the build script's `TARGET`.
+    #[cfg_attr(not(feature = "reqwest"), allow(unused_variables))]
+    pub(crate) fn for_target(target: &str) -> Self {
+        Depot {
+            #[cfg(feature = "reqwest")]
+            target: Some(target.to_owned()),
+            ..Self::new()
         }
     }
 
-    pub fn deliver(&self, recipients: &Recipients, packages: Packages) -> Result<(), DepotError> {
+    pub fn deliver(
+        &self,
+        recipients: &Recipients,
+        packages: Packages,
+    ) -> Result<Receipt, DepotError> {
+        self.deliver_with_lock(recipients, packages, None)
+    }
+
+    /// Like `deliver`, but checks every archive against the lockfile before
+    /// unpacking it, recording new ones as allowed by its `LockMode`.
+    pub fn deliver_locked(
+        &self,
+        recipients: &Recipients,
+        packages: Packages,
+        lock: &mut Lockfile,
+    ) -> Result<Receipt, DepotError> {
+        self.deliver_with_lock(recipients, packages, Some(lock))
+    }
+
+    fn deliver_with_lock(
+        &self,
+        recipients: &Recipients,
+        packages: Packages,
+        mut lock: Option<&mut Lockfile>,
+    ) -> Result<Receipt, DepotError> {
         use self::DepotError::MissingLibraryFile;
+        let naming = recipients.naming();
+        let mut receipt = Receipt::default();
+        let policy = packages.duplicates;
+        for (crate_name, package) in packages.from_source.into_iter() {
+            let dest = recipients.get(
+                package.lib_name.as_ref(),
+                package.hash.as_ref().map(AsRef::as_ref),
+                policy,
+            )?;
+            match dest {
+                Some(dest) => Self::check_from_source(crate_name, &dest)?,
+                None => return Err(MissingLibraryFile(crate_name)),
+            }
+        }
         for (crate_name, package) in packages.packages.into_iter() {
-            let dest = if let Some(dest) = recipients.get(crate_name.as_ref()) {
+            let dest = recipients.get(
+                package.lib_name.as_ref(),
+                package.hash.as_ref().map(AsRef::as_ref),
+                policy,
+            )?;
+            let dest = if let Some(dest) = dest {
                 dest
             } else {
                 return Err(MissingLibraryFile(crate_name));
             };
 
-            self.deliver_helper(crate_name, package, dest)?;
+            let lock = lock.as_mut().map(|lock| &mut **lock);
+            self.deliver_checked(crate_name, package, naming, &dest, lock, &mut receipt)?;
+        }
+        Ok(receipt)
+    }
+
+    /// Delivers a single package onto `dest`, e.g. the outputs of a rustc
+    /// invocation hijacked by `dhl-rustc-wrapper`.
+    pub(crate) fn deliver_package(
+        &self,
+        crate_name: String,
+        package: Package,
+        naming: &ArtifactNaming,
+        dest: &Recipient,
+        lock: Option<&mut Lockfile>,
+    ) -> Result<Receipt, DepotError> {
+        let mut receipt = Receipt::default();
+        self.deliver_checked(crate_name, package, naming, dest, lock, &mut receipt)?;
+        Ok(receipt)
+    }
+
+    /// Checks the version of the dummy crate, then delivers the package.
+    fn deliver_checked(
+        &self,
+        crate_name: String,
+        package: Package,
+        naming: &ArtifactNaming,
+        dest: &Recipient,
+        lock: Option<&mut Lockfile>,
+        receipt: &mut Receipt,
+    ) -> Result<(), DepotError> {
+        if let Some(ref package_version) = package.package_version {
+            check_requirement(
+                &crate_name,
+                "dummy crate",
+                package_version,
+                package.version.as_ref().map(AsRef::as_ref),
+            )?;
+        }
+        self.deliver_helper(crate_name, package, naming, dest, lock, receipt)
+    }
+
+    /// Checks that a crate that isn't hijacked was built from its real
+    /// source, rather than from the dummy.
+    fn check_from_source(crate_name: String, dest: &Recipient) -> Result<(), DepotError> {
+        let sources = match dest.sources() {
+            Some(sources) => sources,
+            None => {
+                report::warning(&format!(
+                    "could not check that {} was built from source, it has no dep-info file",
+                    crate_name
+                ));
+                return Ok(());
+            }
+        };
+        if sources.iter().all(|source| Self::is_empty_source(source)) {
+            return Err(DepotError::DummyNotReplaced(crate_name, sources));
         }
         Ok(())
     }
 
-    fn unpack<R: Read>(crate_name: String, r: R, dest: PathBuf) -> Result<(), ArchiveError> {
+    /// Handles a package whose archive doesn't exist as its `MissingPolicy`
+    /// says.
+    fn missing(
+        crate_name: String,
+        source: String,
+        available: Vec<String>,
+        policy: MissingPolicy,
+        dest: &Recipient,
+    ) -> Result<(), DepotError> {
+        match policy {
+            MissingPolicy::Error => Err(DepotError::MissingArchive(crate_name, source, available)),
+            MissingPolicy::Warn => {
+                report::warning(&format!(
+                    "skipping {}, it has no archive at '{}', {}",
+                    crate_name,
+                    source,
+                    describe_available(&available)
+                ));
+                Ok(())
+            }
+            MissingPolicy::FallbackSource => {
+                report::warning(&format!(
+                    "building {} from source, it has no archive at '{}'",
+                    crate_name,
+                    source
+                ));
+                Self::check_from_source(crate_name, dest)
+            }
+        }
+    }
+
+    /// The builds next to a missing archive that do exist. For a missing
+    /// `libs/<target>/<rustc>/exported.tar.gz` these are the `<target>/<rustc>`
+    /// directories with an `exported.tar.gz`, below the deepest directory that
+    /// exists. If only the file is missing, they're the files next to it.
+    /// The search only goes a few directories deep and gives up after a few
+    /// hundred entries, skipping `target` and hidden directories, so a
+    /// mistyped source can't walk a whole checkout.
+    fn available(source: &Path) -> Vec<String> {
+        const MAX_DEPTH: usize = 4;
+        const MAX_ENTRIES: usize = 512;
+
+        let file_name = match source.file_name() {
+            Some(file_name) => file_name,
+            None => return Vec::new(),
+        };
+        let mut depth = 0;
+        let mut base = source.parent();
+        while let Some(dir) = base {
+            if dir.is_dir() {
+                break;
+            }
+            depth += 1;
+            base = dir.parent();
+        }
+        let base = match (base, depth <= MAX_DEPTH) {
+            (Some(base), true) => base,
+            _ => return Vec::new(),
+        };
+
+        let mut budget = MAX_ENTRIES;
+        let mut entries = |dir: &Path| {
+            let entries = dir.read_dir()
+                .into_iter()
+                .flat_map(|entries| entries)
+                .filter_map(Result::ok)
+                .take(budget)
+                .map(|entry| entry.path())
+                .collect::<Vec<_>>();
+            budget -= entries.len();
+            entries
+                .into_iter()
+                .filter(|path| {
+                    path.file_name().and_then(|name| name.to_str()).map_or(
+                        false,
+                        |name| name != "target" && !name.starts_with('.'),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut available = if depth == 0 {
+            entries(base)
+                .into_iter()
+                .filter(|path| path.is_file())
+                .collect::<Vec<_>>()
+        } else {
+            let mut dirs = vec![base.to_path_buf()];
+            for _ in 0..depth {
+                dirs = dirs.iter()
+                    .flat_map(|dir| entries(dir))
+                    .filter(|path| path.is_dir())
+                    .collect();
+            }
+            dirs.into_iter()
+                .filter(|dir| dir.join(file_name).is_file())
+                .collect()
+        }.into_iter()
+            .filter_map(|path| {
+                path.strip_prefix(base).ok().map(|relative| {
+                    relative.to_string_lossy().replace('\\', "/")
+                })
+            })
+            .collect::<Vec<_>>();
+        available.sort();
+        available
+    }
+
+    /// Whether a source file has nothing but comments and whitespace. Files
+    /// that can't be read count as not empty.
+    pub(crate) fn is_empty_source(source: &Path) -> bool {
+        let mut contents = String::new();
+        if File::open(source)
+            .and_then(|mut f| f.read_to_string(&mut contents))
+            .is_err()
+        {
+            return false;
+        }
+
+        let mut rest = contents.as_str();
+        loop {
+            rest = rest.trim_start();
+            if rest.starts_with("//") {
+                rest = rest.find('\n').map_or("", |end| &rest[end..]);
+            } else if rest.starts_with("/*") {
+                rest = rest.find("*/").map_or("", |end| &rest[end + 2..]);
+            } else {
+                return rest.is_empty();
+            }
+        }
+    }
+
+    fn unpack<R: Read>(
+        crate_name: String,
+        r: R,
+        naming: &ArtifactNaming,
+        dest: &Recipient,
+        receipt: &mut Receipt,
+    ) -> Result<(), ArchiveError> {
         use tar::Archive;
         use libflate::gzip::Decoder;
         use self::ArchiveError::*;
 
-        // TODO add as configurable value
-        let export_name = DEFAULT_EXPORT;
-
         let mut archive = Archive::new(Decoder::new(r).map_err(
             |e| GzipError(crate_name.clone(), e),
         )?);
@@ -168,10 +563,39 @@ impl Depot {
                     return Err(TarFileNameError(crate_name, entry_path.to_path_buf()));
                 };
 
-                if file_name == &*export_name {
-                    dest.as_path()
+                let is_native = entry_path.parent().map_or(
+                    false,
+                    |p| p.ends_with(NATIVE_DIR),
+                );
+                let export = file_name.to_str().and_then(|file_name| {
+                    naming.export_kind(file_name).map(|kind| (file_name, kind))
+                });
+
+                if is_native {
+                    let native_dir = dest.native_dir();
+                    create_dir_all(native_dir).map_err(|e| {
+                        NativeDirError(crate_name.clone(), native_dir.to_path_buf(), e)
+                    })?;
+                    receipt.add_link_search(native_dir.to_path_buf());
+                    new_dest = native_dir.join(file_name);
+                    new_dest.as_path()
+                } else if file_name == METADATA_NAME {
+                    // Already read and checked before unpacking
+                    continue;
+                } else if let Some((file_name, kind)) = export {
+                    if let Some(dest) = dest.export(file_name) {
+                        dest
+                    } else {
+                        report::warning(&format!(
+                            "skipping '{}' for {}, no {:?} artifact to inject onto",
+                            file_name,
+                            crate_name,
+                            kind
+                        ));
+                        continue;
+                    }
                 } else {
-                    new_dest = dest.with_file_name(file_name);
+                    new_dest = dest.deps_dir().join(file_name);
                     new_dest.as_path()
                 }
             };
@@ -183,43 +607,265 @@ impl Depot {
         Ok(())
     }
 
-    #[cfg(feature = "reqwest")]
-    fn deliver_helper(
-        &self,
+    /// Unpacks an archive, decrypting it with the package's `key` if it's
+    /// encrypted. Its `export.toml` is read and checked against the package
+    /// first, so nothing is unpacked from an archive of another package or
+    /// version.
+    fn decrypt_unpack(
+        crate_name: &str,
+        package: &Package,
+        contents: &[u8],
+        naming: &ArtifactNaming,
+        dest: &Recipient,
+        receipt: &mut Receipt,
+    ) -> Result<(), DepotError> {
+        let plain = Self::decrypt(crate_name, package, contents)?;
+        let contents = plain.as_ref().map_or(contents, Vec::as_slice);
+
+        let metadata = Self::metadata(crate_name.to_owned(), contents)?.unwrap_or_default();
+        Self::check_metadata(crate_name, package, &metadata)?;
+        receipt.add_link_libs(package.link_libs.iter().cloned());
+        receipt.add_link_libs(metadata.link_libs);
+
+        Ok(Self::unpack(crate_name.to_owned(), contents, naming, dest, receipt)?)
+    }
+
+    /// Decrypts an encrypted archive with the package's `key`, or `None` if
+    /// it isn't encrypted.
+    ///
+    /// Chunks authenticate one at a time, so the archive is decrypted in
+    /// full before it's read rather than as it's unpacked: a chunk that
+    /// fails must fail the delivery before anything is written. The archive
+    /// is in memory already, to check its `sha256`.
+    #[cfg(feature = "encryption")]
+    fn decrypt(
+        crate_name: &str,
+        package: &Package,
+        contents: &[u8],
+    ) -> Result<Option<Vec<u8>>, DepotError> {
+        if !is_encrypted(contents) {
+            return Ok(None);
+        }
+        let key = match package.key {
+            Some(ref key) => Key::parse(&key.0).map_err(|e| Self::decryption_error(crate_name, e))?,
+            None => return Err(DepotError::MissingKey(crate_name.to_owned())),
+        };
+        let mut plain = Vec::with_capacity(contents.len());
+        encryption::decrypt(&key, contents)
+            .map_err(|e| Self::decryption_error(crate_name, e))?
+            .read_to_end(&mut plain)
+            .map_err(|e| {
+                DepotError::EncryptionError(crate_name.to_owned(), EncryptionError::Io(e))
+            })?;
+        Ok(Some(plain))
+    }
+
+    /// Encrypted archives are an error without the `encryption` feature.
+    #[cfg(not(feature = "encryption"))]
+    fn decrypt(
+        crate_name: &str,
+        _package: &Package,
+        contents: &[u8],
+    ) -> Result<Option<Vec<u8>>, DepotError> {
+        if is_encrypted(contents) {
+            Err(DepotError::EncryptionUnsupported(crate_name.to_owned()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    fn decryption_error(crate_name: &str, err: EncryptionError) -> DepotError {
+        match err {
+            EncryptionError::WrongKey(reason) => {
+                DepotError::WrongKey(crate_name.to_owned(), reason)
+            }
+            e => DepotError::EncryptionError(crate_name.to_owned(), e),
+        }
+    }
+
+    /// Reads the `export.toml` of an archive without unpacking anything.
+    pub(crate) fn metadata<R: Read>(
         crate_name: String,
-        package: Package,
-        dest: PathBuf,
+        r: R,
+    ) -> Result<Option<ArchiveMetadata>, ArchiveError> {
+        use tar::Archive;
+        use libflate::gzip::Decoder;
+        use self::ArchiveError::*;
+
+        let mut archive = Archive::new(Decoder::new(r).map_err(
+            |e| GzipError(crate_name.clone(), e),
+        )?);
+        for entry in archive.entries().map_err(
+            |e| TarError(crate_name.clone(), e),
+        )?
+        {
+            let mut entry = entry.map_err(|e| TarError(crate_name.clone(), e))?;
+            let is_metadata = entry
+                .path()
+                .map_err(|e| TarPathError(crate_name.clone(), e))?
+                .file_name()
+                .map_or(false, |file_name| file_name == METADATA_NAME);
+            if !is_metadata {
+                continue;
+            }
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents).map_err(
+                |e| TarError(crate_name.clone(), e),
+            )?;
+            return toml::from_str::<ArchiveMetadata>(&contents)
+                .map(Some)
+                .map_err(|e| MetadataError(crate_name, e));
+        }
+        Ok(None)
+    }
+
+    /// Reads a whole archive, so it can be checked against the lockfile
+    /// before anything is unpacked.
+    fn read_archive<R: Read>(mut r: R) -> io::Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        r.read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    /// Checks an archive against the digest its index lists.
+    fn check_digest(
+        crate_name: &str,
+        sha256: Option<&String>,
+        contents: &[u8],
     ) -> Result<(), DepotError> {
-        match package.data {
-            PackageData::File(source) => {
-                let source = File::open(&source.source).context((&*crate_name, source))?;
-                Self::unpack(crate_name, source, dest)?;
+        if let Some(expected) = sha256 {
+            let actual = lock::sha256(contents);
+            if actual != *expected {
+                return Err(DepotError::DigestMismatch(
+                    crate_name.to_owned(),
+                    expected.clone(),
+                    actual,
+                ));
             }
-            PackageData::Url(source) => {
-                let source = self.http_client
-                    .as_ref()
-                    .map_err(Arc::clone)?
-                    .execute(Request::new(Method::Get, source.source.clone()))
-                    .context((&*crate_name, source))?;
-                Self::unpack(crate_name, source, dest)?;
+        }
+        Ok(())
+    }
+
+    /// The source of a file package as recorded in the lockfile, relative to
+    /// the lockfile if possible so it doesn't depend on the checkout.
+    fn lock_source(lock: &Lockfile, source: &FileData) -> String {
+        let path = source.source.strip_prefix(lock.dir()).unwrap_or(&source.source);
+        path.to_string_lossy().replace('\\', "/")
+    }
+
+    /// Checks the `export.toml` of an archive against the package, before
+    /// anything is unpacked. Names match when they only differ in `-` and
+    /// `_`, like cargo's crate names do.
+    fn check_metadata(
+        crate_name: &str,
+        package: &Package,
+        metadata: &ArchiveMetadata,
+    ) -> Result<(), DepotError> {
+        if let Some(ref name) = metadata.name {
+            let normalize = |name: &str| name.replace('-', "_");
+            if normalize(name) != normalize(crate_name) &&
+                normalize(name) != normalize(&package.lib_name)
+            {
+                return Err(DepotError::WrongPackage(crate_name.to_owned(), name.clone()));
             }
         }
+        if let Some(ref version) = metadata.version {
+            check_archive(
+                crate_name,
+                version,
+                package.package_version.as_ref().map(AsRef::as_ref),
+                package.version.as_ref().map(AsRef::as_ref),
+            )?;
+        }
         Ok(())
     }
 
-    #[cfg(not(feature = "reqwest"))]
     fn deliver_helper(
         &self,
         crate_name: String,
         package: Package,
-        dest: PathBuf,
+        naming: &ArtifactNaming,
+        dest: &Recipient,
+        lock: Option<&mut Lockfile>,
+        receipt: &mut Receipt,
     ) -> Result<(), DepotError> {
-        match package.data {
-            PackageData::File(source) => {
-                let source = File::open(&source.source).context((&*crate_name, source))?;
-                Self::unpack(crate_name, source, dest)?;
+        let on_missing = package.on_missing;
+        let contents = match package.data {
+            PackageData::File(ref source) => {
+                report::rerun_if_changed(&source.source);
+                let contents = match File::open(&source.source) {
+                    Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                        let available = Self::available(&source.source);
+                        let path = source.source.display().to_string();
+                        return Self::missing(crate_name, path, available, on_missing, dest);
+                    }
+                    file => file,
+                }.and_then(Self::read_archive)
+                    .context((&*crate_name, source))?;
+                Self::check_digest(&crate_name, package.sha256.as_ref(), &contents)?;
+                if let Some(lock) = lock {
+                    lock.check(&crate_name, &Self::lock_source(lock, source), &contents)?;
+                }
+                contents
+            }
+            #[cfg(feature = "reqwest")]
+            PackageData::Url(ref source) => {
+                let contents = match self.fetch_url(&crate_name, source)? {
+                    Fetched::Archive(contents) => contents,
+                    Fetched::Missing(available) => {
+                        let url = source.source.to_string();
+                        return Self::missing(crate_name, url, available, on_missing, dest);
+                    }
+                };
+                Self::check_digest(&crate_name, package.sha256.as_ref(), &contents)?;
+                if let Some(lock) = lock {
+                    lock.check(&crate_name, source.source.as_str(), &contents)?;
+                }
+                contents
             }
+            PackageData::Missing(MissingData { ref source, ref available }) => {
+                let (source, available) = (source.clone(), available.clone());
+                return Self::missing(crate_name, source, available, on_missing, dest);
+            }
+        };
+        Self::decrypt_unpack(&crate_name, &package, &contents, naming, dest, receipt)
+    }
+
+    /// Downloads a url source, or for `oci://` the layer of its image
+    /// manifest for the target.
+    #[cfg(feature = "reqwest")]
+    fn fetch_url(&self, crate_name: &str, source: &UrlData) -> Result<Fetched, DepotError> {
+        let client = self.http_client.as_ref().map_err(|e| DepotError::TlsError(Arc::clone(e)))?;
+        if source.source.scheme() == "oci" {
+            let target = self.target.as_ref().map(AsRef::as_ref);
+            return oci::fetch(client, &Env::process(), &source.source, target).map_err(|e| {
+                DepotError::OciError(crate_name.to_owned(), source.clone(), e)
+            });
+        }
+
+        let token = source.source.host_str().and_then(|host| Env::process().token(host));
+        let mut request = Request::new(Method::Get, source.source.clone());
+        if let Some(token) = token {
+            request.headers_mut().set(Authorization(Bearer { token }));
         }
+        let response = client.execute(request).map_err(|e| {
+            DepotError::HttpError(crate_name.to_owned(), source.clone(), e)
+        })?;
+        if response.status() == StatusCode::NotFound {
+            return Ok(Fetched::Missing(Vec::new()));
+        }
+        if !response.status().is_success() {
+            return Err(DepotError::HttpStatusError(
+                crate_name.to_owned(),
+                source.clone(),
+                response.status(),
+            ));
+        }
+        let contents = Self::read_archive(response).map_err(|e| {
+            DepotError::HttpReadError(crate_name.to_owned(), source.clone(), e)
+        })?;
+        Ok(Fetched::Archive(contents))
     }
 }
 
@@ -228,15 +874,17 @@ mod test {
     use std::collections::HashMap;
     use std::fs::{create_dir_all, File};
     use std::io::{Read, Write, Seek, SeekFrom, Error, Cursor};
-    use std::path::Path;
+    use std::path::{Path, PathBuf};
 
     use libflate::gzip::Encoder;
     use tar::{Builder, Header};
     use tempdir::TempDir;
 
-    use super::Depot;
-    use recipients::Recipients;
-    use manifest::{Packages, Package, PackageData, FileData};
+    use super::{Depot, DepotError, MissingPolicy};
+    use lock::{self, LockError, LockMode, Lockfile};
+    use version::VersionError;
+    use recipients::{DuplicatePolicy, Recipients};
+    use manifest::{Packages, Package, PackageData, FileData, SourcePackage};
 
     fn append_sized<W: Write, P: AsRef<Path>, R: AsRef<[u8]>>(
         builder: &mut Builder<W>,
@@ -254,21 +902,86 @@ mod test {
         builder.append(&header, data)
     }
 
+    /// A build script's output and deps dirs in a temporary directory, with
+    /// the dummy rlib of `dhltest` to deliver onto.
+    struct Fixture {
+        base_dir: TempDir,
+        deps_dir: PathBuf,
+        out_dir: PathBuf,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let base_dir = TempDir::new("example").unwrap();
+            let deps_dir = base_dir.path().join("deps");
+            let out_dir = base_dir.path().join("build").join("example").join("out");
+            create_dir_all(&out_dir).unwrap();
+            create_dir_all(&deps_dir).unwrap();
+            File::create(deps_dir.join("libdhltest-c000l0ff.rlib")).unwrap();
+            Fixture {
+                base_dir,
+                deps_dir,
+                out_dir,
+            }
+        }
+
+        /// The artifacts in the deps dir, which the test has to have written
+        /// by now.
+        fn recipients(&self) -> Recipients {
+            Recipients::with_env(&self.out_dir, self.path(), "x86_64-unknown-linux-gnu").unwrap()
+        }
+
+        fn path(&self) -> &Path {
+            self.base_dir.path()
+        }
+
+        /// The contents of a file in the deps dir.
+        fn read_dep(&self, name: &str) -> String {
+            let mut s = String::new();
+            File::open(self.deps_dir.join(name)).unwrap().read_to_string(&mut s).unwrap();
+            s
+        }
+
+        fn close(self) {
+            self.base_dir.close().unwrap();
+        }
+    }
+
+    /// A package with nothing but its data and library name.
+    fn package(lib_name: &str, data: PackageData) -> Package {
+        Package {
+            data,
+            version: None,
+            package_version: None,
+            lib_name: lib_name.into(),
+            hash: None,
+            link_libs: Vec::new(),
+            on_missing: MissingPolicy::Error,
+            index: None,
+            sha256: None,
+            key: None,
+        }
+    }
+
+    fn packages(packages: Vec<(&str, Package)>) -> Packages {
+        Packages {
+            packages: packages.into_iter().map(|(name, package)| (name.into(), package)).collect(),
+            from_source: HashMap::new(),
+            duplicates: DuplicatePolicy::Error,
+        }
+    }
+
+    fn file(source: &Path) -> PackageData {
+        PackageData::File(FileData { source: source.to_path_buf() })
+    }
+
 
     #[test]
     fn verify_file_delivery() {
-        let base_dir = TempDir::new("example").unwrap();
-        let private_dir = base_dir.path().join("private");
-        let deps_dir = base_dir.path().join("deps");
-        let out_dir = base_dir.path().join("build").join("example").join("out");
-        create_dir_all(&out_dir).unwrap();
-        create_dir_all(&deps_dir).unwrap();
+        let fixture = Fixture::new();
+        let private_dir = fixture.path().join("private");
         create_dir_all(&private_dir).unwrap();
 
-        let dhltest_target = deps_dir.join("libdhltest-c000l0ff.rlib");
-        let dhltest_dash_target = deps_dir.join("libdhltest_dash-d15ea5e.rlib");
-        let dhltest_underscore_target = deps_dir.join("libdhltest_underscore-deadbeef.rlib");
-
         let dep1_name = "libbytes-f6610c9d61c318a7.rlib";
         let dep1_data = "test1";
         let dep2_name = "libcfg_if-8132ccc150e6610a.rlib";
@@ -279,9 +992,8 @@ mod test {
         let dep4_data = "test4";
 
         {
-            File::create(&dhltest_target).unwrap();
-            File::create(&dhltest_dash_target).unwrap();
-            File::create(&dhltest_underscore_target).unwrap();
+            File::create(fixture.deps_dir.join("libdhltest_dash-d15ea5e.rlib")).unwrap();
+            File::create(fixture.deps_dir.join("libdhltest_underscore-deadbeef.rlib")).unwrap();
         }
 
         let dhltest_source = private_dir.join("dhltest.tar.gz");
@@ -312,75 +1024,546 @@ mod test {
             tar.into_inner().unwrap().finish().unwrap();
         }
 
-        let recipients = Recipients::with_env(&out_dir, base_dir.path()).unwrap();
+        let packages = packages(vec![
+            ("dhltest", package("dhltest", file(&dhltest_source))),
+            ("dhltest-dash", package("dhltest_dash", file(&dhltest_dash_source))),
+            (
+                "dhltest_underscore",
+                package("dhltest_underscore", file(&dhltest_underscore_source)),
+            ),
+        ]);
 
-        let mut packages = HashMap::new();
-        packages.insert(
-            "dhltest".into(),
-            Package {
-                data: PackageData::File(FileData { source: dhltest_source.clone() }),
-                version: None,
-            },
-        );
-        packages.insert(
-            "dhltest-dash".into(),
-            Package {
-                data: PackageData::File(FileData { source: dhltest_dash_source.clone() }),
-                version: None,
-            },
-        );
-        packages.insert(
-            "dhltest_underscore".into(),
-            Package {
-                data: PackageData::File(FileData { source: dhltest_underscore_source.clone() }),
-                version: None,
-            },
+        let depot = Depot::new();
+        depot.deliver(&fixture.recipients(), packages).unwrap();
+
+        assert_eq!(fixture.read_dep(dep1_name), dep1_data);
+        assert_eq!(fixture.read_dep(dep2_name), dep2_data);
+        assert_eq!(fixture.read_dep(dep3_name), dep3_data);
+        assert_eq!(fixture.read_dep(dep4_name), dep4_data);
+
+        assert_eq!(fixture.read_dep("libdhltest-c000l0ff.rlib"), "test5");
+        assert_eq!(fixture.read_dep("libdhltest_dash-d15ea5e.rlib"), "test6");
+        assert_eq!(fixture.read_dep("libdhltest_underscore-deadbeef.rlib"), "test7");
+
+        fixture.close();
+    }
+
+    #[test]
+    fn verify_native_delivery() {
+        let fixture = Fixture::new();
+        File::create(fixture.deps_dir.join("libdhltest-c000l0ff.so")).unwrap();
+
+        let source = fixture.path().join("dhltest.tar.gz");
+        {
+            let file = File::create(&source).unwrap();
+            let gz = Encoder::new(file).unwrap();
+            let mut tar = Builder::new(gz);
+            append_sized(&mut tar, "export.rlib", "rlib").unwrap();
+            append_sized(&mut tar, "export.so", "dylib").unwrap();
+            append_sized(&mut tar, "export.a", "staticlib").unwrap();
+            tar.into_inner().unwrap().finish().unwrap();
+        }
+
+        let depot = Depot::new();
+        let packages = packages(vec![("dhltest", package("dhltest", file(&source)))]);
+        depot.deliver(&fixture.recipients(), packages).unwrap();
+
+        assert_eq!(fixture.read_dep("libdhltest-c000l0ff.rlib"), "rlib");
+        assert_eq!(fixture.read_dep("libdhltest-c000l0ff.so"), "dylib");
+        assert!(!fixture.deps_dir.join("export.a").exists());
+
+        fixture.close();
+    }
+
+    #[test]
+    fn verify_link_delivery() {
+        let fixture = Fixture::new();
+
+        let source = fixture.path().join("dhltest.tar.gz");
+        {
+            let file = File::create(&source).unwrap();
+            let gz = Encoder::new(file).unwrap();
+            let mut tar = Builder::new(gz);
+            append_sized(&mut tar, "export.rlib", "rlib").unwrap();
+            append_sized(&mut tar, "export.toml", r#"link-libs = ["static=native"]"#).unwrap();
+            append_sized(&mut tar, "native/libnative.a", "native").unwrap();
+            tar.into_inner().unwrap().finish().unwrap();
+        }
+
+        let package = Package {
+            link_libs: vec!["dylib=extra".into(), "static=native".into()],
+            ..package("dhltest", file(&source))
+        };
+        let depot = Depot::new();
+        let receipt = depot
+            .deliver(&fixture.recipients(), packages(vec![("dhltest", package)]))
+            .unwrap();
+
+        let native_dir = fixture.out_dir.join("native").join("dhltest");
+        assert_eq!(receipt.link_search, vec![native_dir.clone()]);
+        assert_eq!(receipt.link_libs, vec!["dylib=extra", "static=native"]);
+
+        let mut s = String::new();
+        File::open(native_dir.join("libnative.a")).unwrap().read_to_string(&mut s).unwrap();
+        assert_eq!(s, "native");
+
+        assert!(!fixture.deps_dir.join("export.toml").exists());
+        assert!(!fixture.deps_dir.join("libnative.a").exists());
+
+        fixture.close();
+    }
+
+    #[test]
+    fn verify_version_check() {
+        let fixture = Fixture::new();
+
+        let source = fixture.path().join("dhltest.tar.gz");
+        let archive = |metadata: &str| {
+            let file = File::create(&source).unwrap();
+            let gz = Encoder::new(file).unwrap();
+            let mut tar = Builder::new(gz);
+            append_sized(&mut tar, "export.toml", metadata).unwrap();
+            append_sized(&mut tar, "export.rlib", "rlib").unwrap();
+            tar.into_inner().unwrap().finish().unwrap();
+        };
+
+        let package = |version: &str, package_version: &str| {
+            let package = Package {
+                version: Some(version.into()),
+                package_version: Some(package_version.into()),
+                ..package("dhltest", file(&source))
+            };
+            packages(vec![("dhltest", package)])
+        };
+
+        let depot = Depot::new();
+
+        // Nothing is unpacked from an archive of another package
+        archive("name = \"other\"\nversion = \"2.0.0\"");
+        match depot.deliver(&fixture.recipients(), package("^2.0", "2.0.0")) {
+            Err(DepotError::WrongPackage(ref crate_name, ref name))
+                if crate_name == "dhltest" && name == "other" => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(fixture.read_dep("libdhltest-c000l0ff.rlib"), "");
+
+        archive("name = \"dhltest\"\nversion = \"2.0.0\"");
+        depot.deliver(&fixture.recipients(), package("^2.0", "2.0.0")).unwrap();
+        match depot.deliver(&fixture.recipients(), package("^1.0", "1.0.0")) {
+            Err(DepotError::VersionError(VersionError::Unsatisfied(..))) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        match depot.deliver(&fixture.recipients(), package("^2.0", "2.0.1")) {
+            Err(DepotError::VersionError(VersionError::Mismatch(..))) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        match depot.deliver(&fixture.recipients(), package("^2.0", "1.0.0")) {
+            Err(DepotError::VersionError(VersionError::Unsatisfied(_, "dummy crate", ..))) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        fixture.close();
+    }
+
+    #[test]
+    fn verify_locked_delivery() {
+        let fixture = Fixture::new();
+        let private_dir = fixture.path().join("private");
+        let lock_file = fixture.path().join("dhl.lock");
+        create_dir_all(&private_dir).unwrap();
+
+        let source = private_dir.join("dhltest.tar.gz");
+        let archive = |data: &str| {
+            let file = File::create(&source).unwrap();
+            let gz = Encoder::new(file).unwrap();
+            let mut tar = Builder::new(gz);
+            append_sized(&mut tar, "export.rlib", data).unwrap();
+            tar.into_inner().unwrap().finish().unwrap();
+        };
+
+        let packages = || packages(vec![("dhltest", package("dhltest", file(&source)))]);
+        let lock = |mode: LockMode| {
+            Lockfile::with_env(
+                &lock_file,
+                "x86_64-unknown-linux-gnu".into(),
+                "debug".into(),
+                "rustc 1.21.0".into(),
+                mode,
+            ).unwrap()
+        };
+
+        let depot = Depot::new();
+        archive("v1");
+        let mut l = lock(LockMode::Record);
+        depot.deliver_locked(&fixture.recipients(), packages(), &mut l).unwrap();
+        l.save().unwrap();
+        assert_eq!(
+            lock(LockMode::Locked).get("dhltest").unwrap().source,
+            "private/dhltest.tar.gz"
         );
 
+        archive("v2");
+        match depot.deliver_locked(&fixture.recipients(), packages(), &mut lock(LockMode::Locked)) {
+            Err(DepotError::LockError(LockError::Changed(..))) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(fixture.read_dep("libdhltest-c000l0ff.rlib"), "v1");
+
+        depot
+            .deliver_locked(&fixture.recipients(), packages(), &mut lock(LockMode::Update))
+            .unwrap();
+
+        fixture.close();
+    }
+
+    #[test]
+    fn verify_from_source() {
+        let fixture = Fixture::new();
+        let src_dir = fixture.path().join("priv").join("src");
+        create_dir_all(&src_dir).unwrap();
+
+        let lib = src_dir.join("lib.rs");
+        let rlib = fixture.deps_dir.join("libdhltest-c000l0ff.rlib");
+        File::create(fixture.deps_dir.join("dhltest-c000l0ff.d"))
+            .unwrap()
+            .write_all(
+                format!("{}: {}\n\n{}:\n", rlib.display(), lib.display(), lib.display())
+                    .as_bytes(),
+            )
+            .unwrap();
+
+        let packages = || {
+            let mut from_source = HashMap::new();
+            from_source.insert(
+                "dhltest".into(),
+                SourcePackage {
+                    lib_name: "dhltest".into(),
+                    hash: None,
+                },
+            );
+            Packages {
+                from_source,
+                ..packages(Vec::new())
+            }
+        };
+
         let depot = Depot::new();
-        depot.deliver(&recipients, Packages { packages }).unwrap();
+        File::create(&lib)
+            .unwrap()
+            .write_all(b"// The dummy\n/* is empty */\n")
+            .unwrap();
+        match depot.deliver(&fixture.recipients(), packages()) {
+            Err(DepotError::DummyNotReplaced(_, sources)) => assert_eq!(sources, vec![lib.clone()]),
+            r => panic!("unexpected result {:?}", r),
+        }
 
+        File::create(&lib)
+            .unwrap()
+            .write_all(b"//! The real crate\npub fn answer() -> u32 { 42 }\n")
+            .unwrap();
+        depot.deliver(&fixture.recipients(), packages()).unwrap();
+        assert_eq!(fixture.read_dep("libdhltest-c000l0ff.rlib"), "");
+
+        fixture.close();
+    }
+
+    #[test]
+    fn verify_missing_archive() {
+        let fixture = Fixture::new();
+        let libs_dir = fixture.path().join("libs");
+        for build in ["x86_64-unknown-linux-gnu/1.20", "x86_64-unknown-linux-gnu/1.19"].iter() {
+            create_dir_all(libs_dir.join(build)).unwrap();
+            File::create(libs_dir.join(build).join("exported.tar.gz")).unwrap();
+        }
+        create_dir_all(libs_dir.join("i686-unknown-linux-gnu").join("1.20")).unwrap();
+        // Build output isn't searched for builds
+        create_dir_all(libs_dir.join("target").join("1.20")).unwrap();
+        File::create(libs_dir.join("target/1.20/exported.tar.gz")).unwrap();
+
+        let packages = |build: &str, on_missing: MissingPolicy| {
+            let package = Package {
+                link_libs: vec!["static=native".into()],
+                on_missing,
+                ..package("dhltest", file(&libs_dir.join(build).join("exported.tar.gz")))
+            };
+            packages(vec![("dhltest", package)])
+        };
+
+        let depot = Depot::new();
+        let (linux, error) = ("x86_64-unknown-linux-gnu/1.21", MissingPolicy::Error);
+        match depot.deliver(&fixture.recipients(), packages(linux, error)) {
+            Err(DepotError::MissingArchive(_, _, available)) => {
+                assert_eq!(available, vec!["1.19", "1.20"])
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+        let aarch64 = "aarch64-unknown-linux-gnu/1.21";
+        match depot.deliver(&fixture.recipients(), packages(aarch64, error)) {
+            Err(DepotError::MissingArchive(_, _, available)) => {
+                assert_eq!(
+                    available,
+                    vec!["x86_64-unknown-linux-gnu/1.19", "x86_64-unknown-linux-gnu/1.20"]
+                )
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        let receipt = depot
+            .deliver(&fixture.recipients(), packages(linux, MissingPolicy::Warn))
+            .unwrap();
+        assert!(receipt.link_libs.is_empty());
+
+        // The dummy wasn't replaced, and has no dep-info to tell otherwise
+        depot
+            .deliver(&fixture.recipients(), packages(linux, MissingPolicy::FallbackSource))
+            .unwrap();
+
+        fixture.close();
+    }
+
+    #[test]
+    fn verify_digest() {
+        let fixture = Fixture::new();
+
+        let source = fixture.path().join("dhltest.tar.gz");
         {
-            let mut s = String::new();
+            let file = File::create(&source).unwrap();
+            let gz = Encoder::new(file).unwrap();
+            let mut tar = Builder::new(gz);
+            append_sized(&mut tar, "export.rlib", "rlib").unwrap();
+            tar.into_inner().unwrap().finish().unwrap();
+        }
+        let mut contents = Vec::new();
+        File::open(&source).unwrap().read_to_end(&mut contents).unwrap();
+        let digest = lock::sha256(&contents);
 
-            s.clear();
-            let mut file = File::open(&deps_dir.join(dep1_name)).unwrap();
-            file.read_to_string(&mut s).unwrap();
-            assert_eq!(s, dep1_data);
+        let packages = |sha256: &str| {
+            let package = Package {
+                sha256: Some(sha256.into()),
+                ..package("dhltest", file(&source))
+            };
+            packages(vec![("dhltest", package)])
+        };
 
-            s.clear();
-            let mut file = File::open(&deps_dir.join(dep2_name)).unwrap();
-            file.read_to_string(&mut s).unwrap();
-            assert_eq!(s, dep2_data);
+        let depot = Depot::new();
+        match depot.deliver(&fixture.recipients(), packages("d15ea5e")) {
+            Err(DepotError::DigestMismatch(_, expected, actual)) => {
+                assert_eq!(expected, "d15ea5e");
+                assert_eq!(actual, digest);
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+        match depot.deliver(&fixture.recipients(), packages(&digest.to_uppercase())) {
+            Err(DepotError::DigestMismatch(..)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        depot.deliver(&fixture.recipients(), packages(&digest)).unwrap();
 
-            s.clear();
-            let mut file = File::open(&deps_dir.join(dep3_name)).unwrap();
-            file.read_to_string(&mut s).unwrap();
-            assert_eq!(s, dep3_data);
+        fixture.close();
+    }
 
-            s.clear();
-            let mut file = File::open(&deps_dir.join(dep4_name)).unwrap();
-            file.read_to_string(&mut s).unwrap();
-            assert_eq!(s, dep4_data);
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn verify_encrypted_delivery() {
+        use encryption::{Key, encrypt};
+        use manifest::Secret;
 
+        let fixture = Fixture::new();
 
+        let mut archive = Vec::new();
+        {
+            let gz = Encoder::new(&mut archive).unwrap();
+            let mut tar = Builder::new(gz);
+            append_sized(&mut tar, "export.rlib", "secret rlib").unwrap();
+            tar.into_inner().unwrap().finish().unwrap();
+        }
+        let secret = Key::generate_secret().unwrap();
+        let source = fixture.path().join("dhltest.tar.gz.enc");
+        encrypt(&secret.public(), &archive[..], File::create(&source).unwrap()).unwrap();
+
+        let packages = |key: Option<String>| {
+            let package = Package {
+                key: key.map(Secret),
+                ..package("dhltest", file(&source))
+            };
+            packages(vec![("dhltest", package)])
+        };
+
+        let depot = Depot::new();
+        match depot.deliver(&fixture.recipients(), packages(None)) {
+            Err(DepotError::MissingKey(..)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        let wrong = Key::generate_secret().unwrap().to_string();
+        match depot.deliver(&fixture.recipients(), packages(Some(wrong))) {
+            Err(DepotError::WrongKey(..)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        match depot.deliver(&fixture.recipients(), packages(Some("not a key".into()))) {
+            Err(DepotError::EncryptionError(..)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        depot.deliver(&fixture.recipients(), packages(Some(secret.to_string()))).unwrap();
+        assert_eq!(fixture.read_dep("libdhltest-c000l0ff.rlib"), "secret rlib");
+
+        fixture.close();
+    }
+
+    #[cfg(feature = "reqwest")]
+    #[test]
+    fn verify_url_delivery() {
+        use std::thread;
+
+        use reqwest::Url;
+
+        use manifest::UrlData;
+        use serve::Server;
+
+        let fixture = Fixture::new();
+        let served_dir = fixture.path().join("served");
+        create_dir_all(&served_dir).unwrap();
+        {
+            let file = File::create(served_dir.join("dhltest.tar.gz")).unwrap();
+            let gz = Encoder::new(file).unwrap();
+            let mut tar = Builder::new(gz);
+            append_sized(&mut tar, "export.rlib", "rlib").unwrap();
+            tar.into_inner().unwrap().finish().unwrap();
+        }
+
+        let server = Server::bind("127.0.0.1:0", &served_dir).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run(|_| {}));
 
-            s.clear();
-            let mut file = File::open(&dhltest_target).unwrap();
-            file.read_to_string(&mut s).unwrap();
-            assert_eq!(s, "test5");
+        let packages = |file: &str| {
+            let source = Url::parse(&format!("http://{}/{}", addr, file)).unwrap();
+            let data = PackageData::Url(UrlData { source });
+            packages(vec![("dhltest", package("dhltest", data))])
+        };
 
-            s.clear();
-            let mut file = File::open(&dhltest_dash_target).unwrap();
-            file.read_to_string(&mut s).unwrap();
-            assert_eq!(s, "test6");
+        let depot = Depot::new();
+        depot.deliver(&fixture.recipients(), packages("dhltest.tar.gz")).unwrap();
+        assert_eq!(fixture.read_dep("libdhltest-c000l0ff.rlib"), "rlib");
+
+        match depot.deliver(&fixture.recipients(), packages("missing.tar.gz")) {
+            Err(DepotError::MissingArchive(..)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        fixture.close();
+    }
+
+    #[cfg(feature = "reqwest")]
+    #[test]
+    fn verify_oci_delivery() {
+        use std::thread;
+
+        use reqwest::Url;
 
-            s.clear();
-            let mut file = File::open(&dhltest_underscore_target).unwrap();
-            file.read_to_string(&mut s).unwrap();
-            assert_eq!(s, "test7");
+        use lock::sha256;
+        use manifest::UrlData;
+        use oci::{OCI_LAYER_MEDIA_TYPE, OCI_TARGET_ANNOTATION};
+        use serve::Server;
+
+        let fixture = Fixture::new();
+        let repo_dir = fixture.path().join("served").join("v2").join("team").join("dhltest");
+        create_dir_all(repo_dir.join("blobs")).unwrap();
+        create_dir_all(repo_dir.join("manifests")).unwrap();
+
+        // Stand in for a registry with the files it would serve
+        let mut layers = Vec::new();
+        let builds = [("oci-test-target", "oci rlib"), ("elsewhere", "other")];
+        for &(target, contents) in builds.iter() {
+            let mut archive = Vec::new();
+            {
+                let gz = Encoder::new(&mut archive).unwrap();
+                let mut tar = Builder::new(gz);
+                append_sized(&mut tar, "export.rlib", contents).unwrap();
+                tar.into_inner().unwrap().finish().unwrap();
+            }
+            let digest = format!("sha256:{}", sha256(&archive));
+            File::create(repo_dir.join("blobs").join(&digest))
+                .unwrap()
+                .write_all(&archive)
+                .unwrap();
+            layers.push(format!(
+                r#"{{"mediaType": "{}", "digest": "{}", "size": {},
+                    "annotations": {{"{}": "{}"}}}}"#,
+                OCI_LAYER_MEDIA_TYPE,
+                digest,
+                archive.len(),
+                OCI_TARGET_ANNOTATION,
+                target
+            ));
+        }
+        let manifest = format!(r#"{{"schemaVersion": 2, "layers": [{}]}}"#, layers.join(", "));
+        let manifest_digest = format!("sha256:{}", sha256(manifest.as_bytes()));
+        for name in ["1.0", manifest_digest.as_str()].iter() {
+            File::create(repo_dir.join("manifests").join(name))
+                .unwrap()
+                .write_all(manifest.as_bytes())
+                .unwrap();
+        }
+        // A manifest that doesn't match the digest it's fetched by
+        let wrong_digest = format!("sha256:{}", sha256(b"other manifest"));
+        File::create(repo_dir.join("manifests").join(&wrong_digest))
+            .unwrap()
+            .write_all(manifest.as_bytes())
+            .unwrap();
+        // A layer whose blob doesn't match its digest
+        let bad_digest = format!("sha256:{}", sha256(b"expected"));
+        File::create(repo_dir.join("blobs").join(&bad_digest))
+            .unwrap()
+            .write_all(b"tampered")
+            .unwrap();
+        File::create(repo_dir.join("manifests").join("bad"))
+            .unwrap()
+            .write_all(
+                format!(
+                    r#"{{"schemaVersion": 2, "layers": [{{"mediaType": "{}", "digest": "{}"}}]}}"#,
+                    OCI_LAYER_MEDIA_TYPE,
+                    bad_digest
+                ).as_bytes(),
+            )
+            .unwrap();
+
+        let server = Server::bind("127.0.0.1:0", fixture.path().join("served")).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run(|_| {}));
+
+        let packages = |reference: &str| {
+            let source = Url::parse(&format!("oci://{}/team/dhltest{}", addr, reference)).unwrap();
+            let data = PackageData::Url(UrlData { source });
+            packages(vec![("dhltest", package("dhltest", data))])
+        };
+
+        let depot = |target: &str| Depot { target: Some(target.into()), ..Depot::new() };
+        depot("oci-test-target").deliver(&fixture.recipients(), packages(":1.0")).unwrap();
+        assert_eq!(fixture.read_dep("libdhltest-c000l0ff.rlib"), "oci rlib");
+        let pinned = format!("@{}", manifest_digest);
+        depot("oci-test-target").deliver(&fixture.recipients(), packages(&pinned)).unwrap();
+
+        match depot("oci-test-target").deliver(&fixture.recipients(), packages(":bad")) {
+            Err(DepotError::OciError(..)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        let wrong = format!("@{}", wrong_digest);
+        match depot("oci-test-target").deliver(&fixture.recipients(), packages(&wrong)) {
+            Err(DepotError::OciError(..)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        match depot("oci-test-target").deliver(&fixture.recipients(), packages(":2.0")) {
+            Err(DepotError::MissingArchive(..)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        match depot("oci-test-missing").deliver(&fixture.recipients(), packages(":1.0")) {
+            Err(DepotError::MissingArchive(_, _, available)) => {
+                assert_eq!(available, vec!["oci-test-target", "elsewhere"])
+            }
+            r => panic!("unexpected result {:?}", r),
         }
 
-        base_dir.close().unwrap();
+        fixture.close();
     }
 }
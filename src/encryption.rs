@@ -0,0 +1,441 @@
+use std::cmp::min;
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce, Tag};
+use chacha20poly1305::Key as CipherKey;
+use chacha20poly1305::aead::AeadInPlace;
+use getrandom::getrandom;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use metadata::ENCRYPTED_MAGIC;
+
+const SYMMETRIC_PREFIX: &'static str = "dhl-symmetric-";
+const SECRET_PREFIX: &'static str = "dhl-secret-";
+const PUBLIC_PREFIX: &'static str = "dhl-public-";
+
+const KIND_SYMMETRIC: u8 = 0;
+const KIND_X25519: u8 = 1;
+
+const KEY_SIZE: usize = 32;
+const MAC_SIZE: usize = 32;
+const TAG_SIZE: usize = 16;
+/// The plaintext size of every chunk but the last, which is always shorter.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum EncryptionError {
+        InvalidKey(reason: &'static str) {
+            description("invalid key")
+            display("Invalid key: {}", reason)
+        }
+        WrongKey(reason: &'static str) {
+            description("wrong key")
+            display("The archive was not encrypted with this key: {}", reason)
+        }
+        Corrupt(reason: &'static str) {
+            description("corrupt encrypted archive")
+            display("The encrypted archive is corrupt: {}", reason)
+        }
+        Io(err: io::Error) {
+            from()
+            description("io error")
+            display("I/O error: {}", err)
+            cause(err)
+        }
+    }
+}
+
+/// A key archives are encrypted with or to, written as `dhl-symmetric-`,
+/// `dhl-secret-` or `dhl-public-` followed by 64 hex digits.
+///
+/// A symmetric key both encrypts and decrypts. A public key only encrypts,
+/// and only its secret key decrypts.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Key {
+    Symmetric([u8; KEY_SIZE]),
+    Secret([u8; KEY_SIZE]),
+    Public([u8; KEY_SIZE]),
+}
+
+impl Key {
+    pub fn parse(key: &str) -> Result<Self, EncryptionError> {
+        let key = key.trim();
+        let (constructor, hex): (fn([u8; KEY_SIZE]) -> Key, &str) =
+            if key.starts_with(SYMMETRIC_PREFIX) {
+                (Key::Symmetric, &key[SYMMETRIC_PREFIX.len()..])
+            } else if key.starts_with(SECRET_PREFIX) {
+                (Key::Secret, &key[SECRET_PREFIX.len()..])
+            } else if key.starts_with(PUBLIC_PREFIX) {
+                (Key::Public, &key[PUBLIC_PREFIX.len()..])
+            } else {
+                return Err(EncryptionError::InvalidKey(
+                    "expected dhl-symmetric-, dhl-secret- or dhl-public-",
+                ));
+            };
+        if hex.len() != KEY_SIZE * 2 || !hex.chars().all(|c| c.is_digit(16)) {
+            return Err(EncryptionError::InvalidKey("expected 64 hex digits"));
+        }
+        let mut bytes = [0; KEY_SIZE];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap();
+        }
+        Ok(constructor(bytes))
+    }
+
+    /// A new random symmetric key.
+    pub fn generate_symmetric() -> Result<Self, EncryptionError> {
+        Ok(Key::Symmetric(random()?))
+    }
+
+    /// A new random secret key, whose public key can be handed out.
+    pub fn generate_secret() -> Result<Self, EncryptionError> {
+        Ok(Key::Secret(random()?))
+    }
+
+    /// The key to encrypt with, the public key of a secret key.
+    pub fn public(&self) -> Key {
+        match *self {
+            Key::Secret(ref secret) => Key::Public(public_key(secret)),
+            ref key => key.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (prefix, bytes) = match *self {
+            Key::Symmetric(ref bytes) => (SYMMETRIC_PREFIX, bytes),
+            Key::Secret(ref bytes) => (SECRET_PREFIX, bytes),
+            Key::Public(ref bytes) => (PUBLIC_PREFIX, bytes),
+        };
+        f.write_str(prefix)?;
+        for b in bytes.iter() {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// Never prints the key itself.
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Key::Symmetric(_) => f.write_str("Key::Symmetric(..)"),
+            Key::Secret(_) => f.write_str("Key::Secret(..)"),
+            Key::Public(ref bytes) => write!(f, "Key::Public({:?})", bytes),
+        }
+    }
+}
+
+fn random() -> Result<[u8; KEY_SIZE], EncryptionError> {
+    let mut bytes = [0; KEY_SIZE];
+    getrandom(&mut bytes).map_err(|e| io::Error::new(ErrorKind::Other, e.to_string()))?;
+    Ok(bytes)
+}
+
+fn public_key(secret: &[u8; KEY_SIZE]) -> [u8; KEY_SIZE] {
+    PublicKey::from(&StaticSecret::from(*secret)).to_bytes()
+}
+
+fn shared_secret(secret: &[u8; KEY_SIZE], public: &[u8; KEY_SIZE]) -> [u8; KEY_SIZE] {
+    StaticSecret::from(*secret)
+        .diffie_hellman(&PublicKey::from(*public))
+        .to_bytes()
+}
+
+/// The keys for the header MAC and the payload.
+fn derive(ikm: &[u8], salt: &[u8]) -> ([u8; KEY_SIZE], [u8; KEY_SIZE]) {
+    let hkdf = Hkdf::<Sha256>::new(Some(salt), ikm);
+    let (mut mac_key, mut payload_key) = ([0; KEY_SIZE], [0; KEY_SIZE]);
+    hkdf.expand(b"dhl header", &mut mac_key).expect("a key is a valid HKDF length");
+    hkdf.expand(b"dhl payload", &mut payload_key).expect("a key is a valid HKDF length");
+    (mac_key, payload_key)
+}
+
+/// The MAC of the header, which `verify_slice` checks in constant time.
+fn header_mac(mac_key: &[u8], kind: u8, share: &[u8]) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(mac_key).expect("HMAC takes any key");
+    mac.update(ENCRYPTED_MAGIC);
+    mac.update(&[kind]);
+    mac.update(share);
+    mac
+}
+
+fn cipher(payload_key: &[u8; KEY_SIZE]) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(CipherKey::from_slice(payload_key))
+}
+
+/// The nonce of a chunk, its big endian index and whether it's the last.
+fn nonce(counter: u64, last: bool) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    for (i, b) in nonce[4..11].iter_mut().enumerate() {
+        *b = (counter >> (8 * (6 - i))) as u8;
+    }
+    nonce[11] = last as u8;
+    nonce
+}
+
+/// Reads until `buf` is full or the reader is exhausted.
+fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match r.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+/// Encrypts an archive with a symmetric key, or to the public key of a
+/// secret key.
+pub fn encrypt<R: Read, W: Write>(key: &Key, mut r: R, mut w: W) -> Result<(), EncryptionError> {
+    let (kind, share, (mac_key, payload_key)) = match key.public() {
+        Key::Symmetric(ref symmetric) => {
+            let salt = random()?;
+            (KIND_SYMMETRIC, salt, derive(symmetric, &salt))
+        }
+        Key::Public(ref public) => {
+            let ephemeral = random()?;
+            let share = public_key(&ephemeral);
+            let shared = shared_secret(&ephemeral, public);
+            (KIND_X25519, share, derive(&shared, &[&share[..], &public[..]].concat()))
+        }
+        Key::Secret(_) => unreachable!(),
+    };
+    w.write_all(ENCRYPTED_MAGIC)?;
+    w.write_all(&[kind])?;
+    w.write_all(&share)?;
+    w.write_all(&header_mac(&mac_key, kind, &share).finalize().into_bytes())?;
+
+    let cipher = cipher(&payload_key);
+    let mut chunk = vec![0; CHUNK_SIZE];
+    let mut counter = 0;
+    loop {
+        let len = read_full(&mut r, &mut chunk)?;
+        let last = len < CHUNK_SIZE;
+        let nonce = nonce(counter, last);
+        let tag = cipher
+            .encrypt_in_place_detached(Nonce::from_slice(&nonce), &[], &mut chunk[..len])
+            .map_err(|_| EncryptionError::Corrupt("the archive is too large to encrypt"))?;
+        w.write_all(&chunk[..len])?;
+        w.write_all(&tag)?;
+        if last {
+            return Ok(());
+        }
+        counter += 1;
+    }
+}
+
+/// Checks that an encrypted archive was encrypted with `key`, and decrypts
+/// it as it's read. A chunk that fails to authenticate is an `InvalidData`
+/// read error.
+pub fn decrypt<R: Read>(key: &Key, mut r: R) -> Result<Decryptor<R>, EncryptionError> {
+    let mut header = [0; 1 + KEY_SIZE + MAC_SIZE];
+    let mut magic = vec![0; ENCRYPTED_MAGIC.len()];
+    if read_full(&mut r, &mut magic)? != magic.len() || magic != ENCRYPTED_MAGIC {
+        return Err(EncryptionError::Corrupt("not an encrypted archive"));
+    }
+    if read_full(&mut r, &mut header)? != header.len() {
+        return Err(EncryptionError::Corrupt("truncated header"));
+    }
+    let (kind, rest) = header.split_at(1);
+    let (share, mac) = rest.split_at(KEY_SIZE);
+    let mut share_bytes = [0; KEY_SIZE];
+    share_bytes.copy_from_slice(share);
+
+    let (mac_key, payload_key) = match (kind[0], key) {
+        (KIND_SYMMETRIC, &Key::Symmetric(ref symmetric)) => derive(symmetric, share),
+        (KIND_X25519, &Key::Secret(ref secret)) => {
+            let public = public_key(secret);
+            let shared = shared_secret(secret, &share_bytes);
+            derive(&shared, &[share, &public[..]].concat())
+        }
+        (_, &Key::Public(_)) => {
+            return Err(EncryptionError::InvalidKey("a public key can only encrypt"))
+        }
+        (KIND_SYMMETRIC, _) => {
+            return Err(EncryptionError::WrongKey("it was encrypted with a symmetric key"))
+        }
+        (KIND_X25519, _) => {
+            return Err(EncryptionError::WrongKey("it was encrypted to a public key"))
+        }
+        _ => return Err(EncryptionError::Corrupt("unknown key kind")),
+    };
+    if header_mac(&mac_key, kind[0], share).verify_slice(mac).is_err() {
+        return Err(EncryptionError::WrongKey("the header doesn't authenticate"));
+    }
+    Ok(Decryptor {
+        inner: r,
+        cipher: cipher(&payload_key),
+        counter: 0,
+        sealed: vec![0; CHUNK_SIZE + TAG_SIZE],
+        plain: Vec::new(),
+        pos: 0,
+        done: false,
+    })
+}
+
+/// Decrypts an encrypted archive as it's read.
+pub struct Decryptor<R> {
+    inner: R,
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+    sealed: Vec<u8>,
+    plain: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: Read> Decryptor<R> {
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let corrupt = |reason| {
+            io::Error::new(ErrorKind::InvalidData, EncryptionError::Corrupt(reason))
+        };
+        let len = read_full(&mut self.inner, &mut self.sealed)?;
+        if len < TAG_SIZE {
+            return Err(corrupt("truncated"));
+        }
+        let last = len < self.sealed.len();
+        let (ciphertext, tag) = self.sealed[..len].split_at(len - TAG_SIZE);
+        self.plain.clear();
+        self.plain.extend_from_slice(ciphertext);
+        let nonce = nonce(self.counter, last);
+        self.cipher
+            .decrypt_in_place_detached(
+                Nonce::from_slice(&nonce),
+                &[],
+                &mut self.plain,
+                Tag::from_slice(tag),
+            )
+            .map_err(|_| corrupt("a chunk doesn't authenticate"))?;
+        // Nothing may follow the last chunk
+        if last && read_full(&mut self.inner, &mut [0])? != 0 {
+            return Err(corrupt("data follows the last chunk"));
+        }
+        self.counter += 1;
+        self.pos = 0;
+        self.done = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Decryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.plain.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+        let len = min(buf.len(), self.plain.len() - self.pos);
+        buf[..len].copy_from_slice(&self.plain[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::{ErrorKind, Read};
+
+    use metadata::is_encrypted;
+    use super::{CHUNK_SIZE, EncryptionError, Key, decrypt, encrypt};
+
+    fn round_trip(encrypt_key: &Key, decrypt_key: &Key, plain: &[u8]) {
+        let mut sealed = Vec::new();
+        encrypt(encrypt_key, plain, &mut sealed).unwrap();
+        assert!(is_encrypted(&sealed));
+        let mut opened = Vec::new();
+        decrypt(decrypt_key, &sealed[..])
+            .unwrap()
+            .read_to_end(&mut opened)
+            .unwrap();
+        assert_eq!(opened, plain);
+    }
+
+    #[test]
+    fn keys() {
+        let secret = Key::generate_secret().unwrap();
+        assert_eq!(Key::parse(&secret.to_string()).unwrap(), secret);
+        let public = secret.public();
+        assert!(public.to_string().starts_with("dhl-public-"));
+        assert_eq!(Key::parse(&public.to_string()).unwrap(), public);
+        assert!(!format!("{:?}", secret).contains(&secret.to_string()[11..]));
+
+        assert!(Key::parse("dhl-symmetric-00").is_err());
+        assert!(Key::parse(&format!("dhl-other-{}", "0".repeat(64))).is_err());
+        assert!(Key::parse(&format!("dhl-symmetric-{}", "g".repeat(64))).is_err());
+    }
+
+    #[test]
+    fn round_trips() {
+        let symmetric = Key::generate_symmetric().unwrap();
+        let secret = Key::generate_secret().unwrap();
+        let large = (0..CHUNK_SIZE * 2 + 7).map(|i| i as u8).collect::<Vec<_>>();
+        for plain in [&b""[..], b"rlib", &large[..CHUNK_SIZE], &large].iter() {
+            round_trip(&symmetric, &symmetric, plain);
+            round_trip(&secret.public(), &secret, plain);
+            round_trip(&secret, &secret, plain);
+        }
+    }
+
+    #[test]
+    fn wrong_keys() {
+        let symmetric = Key::generate_symmetric().unwrap();
+        let secret = Key::generate_secret().unwrap();
+        let mut sealed = Vec::new();
+        encrypt(&symmetric, &b"rlib"[..], &mut sealed).unwrap();
+
+        let other = Key::generate_symmetric().unwrap();
+        match decrypt(&other, &sealed[..]) {
+            Err(EncryptionError::WrongKey(_)) => {}
+            r => panic!("unexpected result {:?}", r.map(|_| ())),
+        }
+        match decrypt(&secret, &sealed[..]) {
+            Err(EncryptionError::WrongKey(_)) => {}
+            r => panic!("unexpected result {:?}", r.map(|_| ())),
+        }
+        match decrypt(&secret.public(), &sealed[..]) {
+            Err(EncryptionError::InvalidKey(_)) => {}
+            r => panic!("unexpected result {:?}", r.map(|_| ())),
+        }
+
+        // Tampering with the payload is caught as it's read
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        let mut opened = Vec::new();
+        let err = decrypt(&symmetric, &sealed[..])
+            .unwrap()
+            .read_to_end(&mut opened)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        // And appending to it
+        let mut extended = Vec::new();
+        encrypt(&symmetric, &b"rlib"[..], &mut extended).unwrap();
+        extended.push(0);
+        let err = decrypt(&symmetric, &extended[..])
+            .unwrap()
+            .read_to_end(&mut opened)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        // As is dropping the last chunk
+        let mut sealed = Vec::new();
+        encrypt(&symmetric, &vec![0; CHUNK_SIZE][..], &mut sealed).unwrap();
+        let truncated = sealed.len() - 16;
+        assert!(decrypt(&symmetric, &sealed[..truncated])
+            .unwrap()
+            .read_to_end(&mut opened)
+            .is_err());
+    }
+}
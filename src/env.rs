@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::env::{self, VarError};
+use std::ffi::OsString;
+use std::sync::Arc;
+
+use report::rerun_if_env_changed;
+
+/// The environment variables dhl reads: the process environment, where
+/// every variable read is reported so cargo reruns the build script when it
+/// changes, or a fixed set of variables.
+#[derive(Debug, Clone)]
+pub struct Env {
+    vars: Option<Arc<HashMap<String, String>>>,
+}
+
+impl Env {
+    /// The environment of the process.
+    pub fn process() -> Self {
+        Env { vars: None }
+    }
+
+    /// Only the given variables, regardless of the process environment.
+    pub fn with_vars<I, K, V>(vars: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let vars = vars.into_iter().map(|(k, v)| (k.into(), v.into())).collect();
+        Env { vars: Some(Arc::new(vars)) }
+    }
+
+    /// Like `std::env::var`.
+    pub fn var(&self, key: &str) -> Result<String, VarError> {
+        match self.vars {
+            Some(ref vars) => vars.get(key).cloned().ok_or(VarError::NotPresent),
+            None => {
+                rerun_if_env_changed(key);
+                env::var(key)
+            }
+        }
+    }
+
+    /// Like `std::env::var_os`.
+    pub fn var_os(&self, key: &str) -> Option<OsString> {
+        match self.vars {
+            Some(ref vars) => vars.get(key).map(OsString::from),
+            None => {
+                rerun_if_env_changed(key);
+                env::var_os(key)
+            }
+        }
+    }
+
+    /// The bearer token to send to `host`, from `DHL_TOKEN_<HOST>` with the
+    /// host uppercased and anything but letters and digits replaced by `_`,
+    /// e.g. `DHL_TOKEN_EXAMPLE_COM`. A token is never sent to other hosts.
+    pub fn token(&self, host: &str) -> Option<String> {
+        let host: String = host.chars()
+            .flat_map(char::to_uppercase)
+            .map(|c| match c {
+                'A'..='Z' | '0'..='9' => c,
+                _ => '_',
+            })
+            .collect();
+        self.var(&format!("DHL_TOKEN_{}", host)).ok()
+    }
+
+    /// The names of the variables starting with `prefix`, without it. These
+    /// aren't reported, since a new one can't be watched for.
+    pub fn names_with_prefix(&self, prefix: &str) -> Vec<String> {
+        let names = match self.vars {
+            Some(ref vars) => vars.keys().cloned().collect::<Vec<_>>(),
+            None => env::vars_os()
+                .filter_map(|(key, _)| key.into_string().ok())
+                .collect(),
+        };
+        names
+            .into_iter()
+            .filter(|name| name.starts_with(prefix))
+            .map(|name| name[prefix.len()..].to_owned())
+            .collect()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::Env;
+
+    #[test]
+    fn token_per_host() {
+        let env = Env::with_vars(vec![("DHL_TOKEN_EXAMPLE_COM", "secret")]);
+        assert_eq!(env.token("example.com"), Some("secret".into()));
+        assert_eq!(env.token("Example.Com"), Some("secret".into()));
+        assert_eq!(env.token("other.example.com"), None);
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::HashSet;
+
+use handlebars::{Handlebars, Helper, HelperDef, JsonRender, RenderContext, RenderError};
+
+use env::Env;
+
+/// The parameters of a helper, rendered to strings. Undefined variables
+/// are `None`.
+type Params = [Option<String>];
+
+type InlineHelper = fn(&Params) -> Result<String, String>;
+
+/// Registers the built-in helpers, returning their names. `env` reads
+/// variables from `vars`.
+pub fn register(engine: &mut Handlebars, vars: &Env) -> HashSet<String> {
+    const HELPERS: [(&'static str, InlineHelper); 7] = [
+        ("replace", replace),
+        ("lower", lower),
+        ("upper", upper),
+        ("semver_major", semver_major),
+        ("semver_minor", semver_minor),
+        ("default", default),
+        ("eq", eq),
+    ];
+    let mut names = HashSet::new();
+    for &(name, helper) in HELPERS.iter() {
+        engine.register_helper(name, inline(helper));
+        names.insert(name.to_owned());
+    }
+    let vars = vars.clone();
+    engine.register_helper("env", inline(move |params: &Params| env(&vars, params)));
+    names.insert("env".to_owned());
+    names
+}
+
+/// Adapts a function of the rendered parameters into a helper that writes
+/// its result.
+fn inline<F>(f: F) -> Box<HelperDef>
+where
+    F: Fn(&Params) -> Result<String, String> + Send + Sync + 'static,
+{
+    Box::new(move |h: &Helper, _: &Handlebars, rc: &mut RenderContext| -> Result<(), RenderError> {
+        let params = h.params()
+            .iter()
+            .map(|p| {
+                let value = p.value();
+                if value.is_null() {
+                    None
+                } else {
+                    Some(value.render())
+                }
+            })
+            .collect::<Vec<_>>();
+        let rendered = f(&params).map_err(
+            |e| RenderError::new(format!("{}: {}", h.name(), e)),
+        )?;
+        rc.writer.write_all(rendered.as_bytes())?;
+        Ok(())
+    })
+}
+
+fn arity(params: &Params, min: usize, max: usize) -> Result<(), String> {
+    if params.len() < min || params.len() > max {
+        Err(if min == max {
+            format!("expected {} parameters, got {}", min, params.len())
+        } else {
+            format!(
+                "expected {} to {} parameters, got {}",
+                min,
+                max,
+                params.len()
+            )
+        })
+    } else {
+        Ok(())
+    }
+}
+
+fn param(params: &Params, i: usize) -> &str {
+    params.get(i).and_then(Option::as_ref).map_or(
+        "",
+        AsRef::as_ref,
+    )
+}
+
+/// `{{replace target "x86_64-pc-windows-msvc" "win64"}}`
+fn replace(params: &Params) -> Result<String, String> {
+    arity(params, 3, 3)?;
+    Ok(param(params, 0).replace(param(params, 1), param(params, 2)))
+}
+
+/// `{{lower profile}}`
+fn lower(params: &Params) -> Result<String, String> {
+    arity(params, 1, 1)?;
+    Ok(param(params, 0).to_lowercase())
+}
+
+/// `{{upper profile}}`
+fn upper(params: &Params) -> Result<String, String> {
+    arity(params, 1, 1)?;
+    Ok(param(params, 0).to_uppercase())
+}
+
+/// Picks a component out of a version or requirement, e.g. `3` and `2` of
+/// `^3.2`. Missing components are `0`.
+fn semver_part(params: &Params, i: usize) -> Result<String, String> {
+    arity(params, 1, 1)?;
+    let version = param(params, 0);
+    let numbers = version.trim_start_matches(|c: char| !c.is_digit(10));
+    if numbers.is_empty() {
+        return Err(format!("'{}' is not a version", version));
+    }
+    match numbers.split('.').nth(i) {
+        Some(part) => {
+            part.parse::<u64>().map(|n| n.to_string()).map_err(|_| {
+                format!("'{}' is not a version", version)
+            })
+        }
+        None => Ok("0".to_owned()),
+    }
+}
+
+/// `{{semver_major version}}`
+fn semver_major(params: &Params) -> Result<String, String> {
+    semver_part(params, 0)
+}
+
+/// `{{semver_minor version}}`
+fn semver_minor(params: &Params) -> Result<String, String> {
+    semver_part(params, 1)
+}
+
+/// `{{default channel "stable"}}`, the first parameter unless it's
+/// undefined or empty.
+fn default(params: &Params) -> Result<String, String> {
+    arity(params, 2, 2)?;
+    Ok(
+        if param(params, 0).is_empty() {
+            param(params, 1)
+        } else {
+            param(params, 0)
+        }.to_owned(),
+    )
+}
+
+/// `{{#if (eq target_os "windows")}}`, `true` if both parameters are equal
+/// and empty otherwise, so it can be used as a condition.
+fn eq(params: &Params) -> Result<String, String> {
+    arity(params, 2, 2)?;
+    Ok(
+        if param(params, 0) == param(params, 1) {
+            "true"
+        } else {
+            ""
+        }.to_owned(),
+    )
+}
+
+/// `{{env "VAR"}}` or `{{env "VAR" "fallback"}}`, the value of an environment
+/// variable, or the fallback (empty by default) if it isn't set.
+fn env(vars: &Env, params: &Params) -> Result<String, String> {
+    arity(params, 1, 2)?;
+    Ok(vars.var(param(params, 0)).unwrap_or_else(
+        |_| param(params, 1).to_owned(),
+    ))
+}
@@ -0,0 +1,485 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "reqwest")]
+use std::io::Read;
+#[cfg(feature = "reqwest")]
+use reqwest::{self, StatusCode, Url, UrlError};
+use semver::{Version, VersionReq};
+use serde_json;
+use toml::{self, de, ser};
+
+use manifest::{ManifestCreationError, read_file};
+use report;
+use version::VersionError;
+use {rustc_version, var_os_or};
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum IndexError {
+        EnvError(name: &'static str) {
+            description("environment variable error")
+            display("Undefined environment variable '{}'", name)
+        }
+        RustcError(err: io::Error) {
+            description("rustc version error")
+            display("Unable to determine rustc version: {}", err)
+            cause(err)
+        }
+        Read(location: String, err: ManifestCreationError) {
+            description("index read error")
+            display("Failed to read index '{}': {}", location, err)
+            cause(err)
+        }
+        Toml(location: String, err: de::Error) {
+            description("index parse error")
+            display("Failed to parse index '{}': {}", location, err)
+            cause(err)
+        }
+        Json(location: String, err: serde_json::Error) {
+            description("index parse error")
+            display("Failed to parse index '{}': {}", location, err)
+            cause(err)
+        }
+        TomlSerialize(location: String, err: ser::Error) {
+            description("index serialize error")
+            display("Failed to serialize index '{}': {}", location, err)
+            cause(err)
+        }
+        JsonSerialize(location: String, err: serde_json::Error) {
+            description("index serialize error")
+            display("Failed to serialize index '{}': {}", location, err)
+            cause(err)
+        }
+        Version(err: VersionError) {
+            from()
+            description("index version error")
+            display("Invalid index entry: {}", err)
+            cause(err)
+        }
+        #[cfg(feature = "reqwest")]
+        Url(location: String, err: UrlError) {
+            description("index url error")
+            display("Failed to parse index url '{}': {}", location, err)
+            cause(err)
+        }
+        #[cfg(feature = "reqwest")]
+        Http(location: String, err: reqwest::Error) {
+            description("index download error")
+            display("Failed to download index '{}': {}", location, err)
+            cause(err)
+        }
+        #[cfg(feature = "reqwest")]
+        HttpRead(location: String, err: io::Error) {
+            description("index download error")
+            display("Failed to download index '{}': {}", location, err)
+            cause(err)
+        }
+        #[cfg(feature = "reqwest")]
+        HttpStatus(location: String, status: StatusCode) {
+            description("index download error")
+            display("Failed to download index '{}': {}", location, status)
+        }
+    }
+}
+
+/// A build of a crate listed in an index.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub name: String,
+    pub version: String,
+    pub target: String,
+    /// The rustc version, e.g. `1.21.0`.
+    pub rustc: String,
+    /// The profile, if the build is only for one.
+    pub profile: Option<String>,
+    /// The archive, relative to the index.
+    pub source: String,
+    pub sha256: Option<String>,
+}
+
+impl IndexEntry {
+    /// Whether both entries are the same build of the same version.
+    pub fn is_same_build(&self, other: &IndexEntry) -> bool {
+        (&self.name, &self.version, &self.target, &self.rustc, &self.profile) ==
+            (&other.name, &other.version, &other.target, &other.rustc, &other.profile)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct TomlIndex {
+    #[serde(default)]
+    package: Vec<IndexEntry>,
+}
+
+/// What an index entry has to match.
+#[derive(Debug, Clone)]
+pub struct Build {
+    pub target: String,
+    pub profile: String,
+    /// The rustc version, e.g. `1.21.0`.
+    pub rustc: String,
+}
+
+impl Build {
+    /// The build of the current build script, from `TARGET`, `PROFILE` and
+    /// `RUSTC`.
+    pub fn from_env() -> Result<Self, IndexError> {
+        let target = var_os_or("TARGET", IndexError::EnvError)?;
+        let profile = var_os_or("PROFILE", IndexError::EnvError)?;
+        Self::new(
+            target.to_string_lossy().into_owned(),
+            profile.to_string_lossy().into_owned(),
+        )
+    }
+
+    /// A build for `target` and `profile` with the current `RUSTC`.
+    pub fn new(target: String, profile: String) -> Result<Self, IndexError> {
+        let rustc = rustc_version().map_err(IndexError::RustcError)?;
+        Ok(Self::with_rustc(target, profile, &rustc))
+    }
+
+    /// A build for `target` and `profile` with the rustc whose
+    /// `rustc --version` is `version`.
+    pub fn with_rustc(target: String, profile: String, version: &str) -> Self {
+        Build {
+            target,
+            profile,
+            // `rustc 1.21.0 (3b72af97e 2017-10-09)`
+            rustc: version.split_whitespace().nth(1).unwrap_or("").to_owned(),
+        }
+    }
+}
+
+/// A list of the available builds of crates, in TOML or, with a `.json`
+/// extension, JSON:
+///
+/// ```toml
+/// [[package]]
+/// name = "priv"
+/// version = "1.0.0"
+/// target = "x86_64-unknown-linux-gnu"
+/// rustc = "1.21.0"
+/// source = "priv-1.0.0-x86_64-unknown-linux-gnu-1.21.0.tar.gz"
+/// sha256 = "..."
+/// ```
+#[derive(Debug)]
+pub struct Index {
+    location: String,
+    /// The directory of a local index, sources are relative to it.
+    dir: PathBuf,
+    entries: Vec<IndexEntry>,
+}
+
+impl Index {
+    /// Reads an index from a path, relative to `base_dir`, or a url.
+    #[cfg(feature = "reqwest")]
+    pub fn load(location: &str, base_dir: &Path) -> Result<Self, IndexError> {
+        if !location.contains("://") || location.starts_with("file://") {
+            return Self::load_file(location, base_dir);
+        }
+
+        let url = Url::parse(location).map_err(
+            |e| IndexError::Url(location.to_owned(), e),
+        )?;
+        let mut response = reqwest::get(url).map_err(
+            |e| IndexError::Http(location.to_owned(), e),
+        )?;
+        if !response.status().is_success() {
+            return Err(IndexError::HttpStatus(location.to_owned(), response.status()));
+        }
+        let mut contents = String::new();
+        response.read_to_string(&mut contents).map_err(|e| {
+            IndexError::HttpRead(location.to_owned(), e)
+        })?;
+        Self::parse(location, PathBuf::new(), &contents)
+    }
+
+    /// Reads an index from a path, relative to `base_dir`.
+    #[cfg(not(feature = "reqwest"))]
+    pub fn load(location: &str, base_dir: &Path) -> Result<Self, IndexError> {
+        Self::load_file(location, base_dir)
+    }
+
+    fn load_file(location: &str, base_dir: &Path) -> Result<Self, IndexError> {
+        let path = base_dir.join(Path::new(if location.starts_with("file://") {
+            location.split_at("file://".len()).1
+        } else {
+            location
+        }));
+        report::rerun_if_changed(&path);
+        let contents = read_file(&path).map_err(
+            |e| IndexError::Read(location.to_owned(), e),
+        )?;
+        let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        Self::parse(location, dir, &contents)
+    }
+
+    /// An index without any entries yet.
+    pub(crate) fn empty(location: &str, dir: PathBuf) -> Self {
+        Index {
+            location: location.to_owned(),
+            dir,
+            entries: Vec::new(),
+        }
+    }
+
+    pub(crate) fn parse(location: &str, dir: PathBuf, contents: &str) -> Result<Self, IndexError> {
+        let index = if location.ends_with(".json") {
+            serde_json::from_str::<TomlIndex>(contents).map_err(|e| {
+                IndexError::Json(location.to_owned(), e)
+            })?
+        } else {
+            toml::from_str::<TomlIndex>(contents).map_err(|e| {
+                IndexError::Toml(location.to_owned(), e)
+            })?
+        };
+        Ok(Index {
+            location: location.to_owned(),
+            dir,
+            entries: index.package,
+        })
+    }
+
+    /// Where the index was loaded from.
+    pub fn location(&self) -> &str {
+        &self.location
+    }
+
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    /// Adds an entry, replacing the same build. Returns the replaced entry.
+    pub fn insert(&mut self, entry: IndexEntry) -> Option<IndexEntry> {
+        let old = match self.entries.iter().position(|e| e.is_same_build(&entry)) {
+            Some(i) => Some(self.entries.remove(i)),
+            None => None,
+        };
+        self.entries.push(entry);
+        self.entries.sort_by(|a, b| {
+            (&a.name, &a.version, &a.target, &a.rustc, &a.profile)
+                .cmp(&(&b.name, &b.version, &b.target, &b.rustc, &b.profile))
+        });
+        old
+    }
+
+    /// The index in the format of its location, JSON or TOML.
+    pub fn serialize(&self) -> Result<String, IndexError> {
+        let index = TomlIndex { package: self.entries.clone() };
+        if self.location.ends_with(".json") {
+            serde_json::to_string_pretty(&index).map_err(|e| {
+                IndexError::JsonSerialize(self.location.clone(), e)
+            })
+        } else {
+            toml::to_string(&index).map_err(
+                |e| IndexError::TomlSerialize(self.location.clone(), e),
+            )
+        }
+    }
+
+    /// The best build of a crate for `build`: the newest version that
+    /// satisfies `requirement`, or exactly `package_version` if the version
+    /// of the dummy crate is known. Builds for the profile are preferred
+    /// over builds for any profile.
+    pub fn resolve(
+        &self,
+        crate_name: &str,
+        requirement: Option<&str>,
+        package_version: Option<&str>,
+        build: &Build,
+    ) -> Result<Option<&IndexEntry>, IndexError> {
+        let parse = |version: &str| {
+            Version::parse(version).map_err(|e| {
+                VersionError::InvalidVersion(crate_name.to_owned(), version.to_owned(), e)
+            })
+        };
+        let requirement = match requirement {
+            Some(requirement) => {
+                Some(VersionReq::parse(requirement).map_err(|e| {
+                    VersionError::InvalidRequirement(
+                        crate_name.to_owned(),
+                        requirement.to_owned(),
+                        e,
+                    )
+                })?)
+            }
+            None => None,
+        };
+        let package_version = match package_version {
+            Some(version) => Some(parse(version)?),
+            None => None,
+        };
+
+        let mut best: Option<(Version, bool, &IndexEntry)> = None;
+        for entry in self.entries.iter() {
+            if entry.name != crate_name || entry.target != build.target ||
+                entry.rustc != build.rustc
+            {
+                continue;
+            }
+            if entry.profile.as_ref().map_or(false, |p| *p != build.profile) {
+                continue;
+            }
+            let version = parse(&entry.version)?;
+            if requirement.as_ref().map_or(false, |r| !r.matches(&version)) ||
+                package_version.as_ref().map_or(false, |v| *v != version)
+            {
+                continue;
+            }
+
+            let key = (version, entry.profile.is_some());
+            let better = best.as_ref().map_or(true, |&(ref version, profile, _)| {
+                key > (version.clone(), profile)
+            });
+            if better {
+                best = Some((key.0, key.1, entry));
+            }
+        }
+        Ok(best.map(|(_, _, entry)| entry))
+    }
+
+    /// Every build of a crate, to tell the user what is available when
+    /// nothing matches.
+    pub fn available(&self, crate_name: &str) -> Vec<String> {
+        let mut available = self.entries
+            .iter()
+            .filter(|e| e.name == crate_name)
+            .map(|e| {
+                let mut build = format!("{} for {} with rustc {}", e.version, e.target, e.rustc);
+                if let Some(ref profile) = e.profile {
+                    build.push_str(&format!(" ({})", profile));
+                }
+                build
+            })
+            .collect::<Vec<_>>();
+        available.sort();
+        available.dedup();
+        available
+    }
+
+    /// Where the archive of an entry is, as a path relative to the returned
+    /// directory or a url.
+    #[cfg(feature = "reqwest")]
+    pub fn source(&self, entry: &IndexEntry) -> Result<(PathBuf, String), IndexError> {
+        if !self.location.contains("://") || self.location.starts_with("file://") {
+            return Ok((self.dir.clone(), entry.source.clone()));
+        }
+        let source = Url::parse(&self.location)
+            .and_then(|url| url.join(&entry.source))
+            .map_err(|e| IndexError::Url(self.location.clone(), e))?;
+        Ok((self.dir.clone(), source.into_string()))
+    }
+
+    /// Where the archive of an entry is, as a path relative to the returned
+    /// directory.
+    #[cfg(not(feature = "reqwest"))]
+    pub fn source(&self, entry: &IndexEntry) -> Result<(PathBuf, String), IndexError> {
+        Ok((self.dir.clone(), entry.source.clone()))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::Path;
+
+    use tempdir::TempDir;
+
+    use super::{Build, Index};
+
+    const INDEX: &'static str = r#"
+[[package]]
+name = "priv"
+version = "1.0.0"
+target = "x86_64-unknown-linux-gnu"
+rustc = "1.21.0"
+source = "priv-1.0.0.tar.gz"
+
+[[package]]
+name = "priv"
+version = "1.1.0"
+target = "x86_64-unknown-linux-gnu"
+rustc = "1.21.0"
+source = "priv-1.1.0.tar.gz"
+
+[[package]]
+name = "priv"
+version = "1.1.0"
+target = "x86_64-unknown-linux-gnu"
+rustc = "1.21.0"
+profile = "release"
+source = "priv-1.1.0-release.tar.gz"
+sha256 = "d15ea5e"
+
+[[package]]
+name = "priv"
+version = "2.0.0"
+target = "x86_64-unknown-linux-gnu"
+rustc = "1.20.0"
+source = "priv-2.0.0.tar.gz"
+"#;
+
+    fn build(profile: &str) -> Build {
+        Build {
+            target: "x86_64-unknown-linux-gnu".into(),
+            profile: profile.into(),
+            rustc: "1.21.0".into(),
+        }
+    }
+
+    #[test]
+    fn resolve() {
+        let base_dir = TempDir::new("index").unwrap();
+        File::create(base_dir.path().join("index.toml"))
+            .unwrap()
+            .write_all(INDEX.as_bytes())
+            .unwrap();
+        let index = Index::load("index.toml", base_dir.path()).unwrap();
+
+        let source = |requirement, package_version, profile| {
+            index
+                .resolve("priv", requirement, package_version, &build(profile))
+                .unwrap()
+                .map(|e| e.source.clone())
+        };
+        assert_eq!(source(None, None, "debug").unwrap(), "priv-1.1.0.tar.gz");
+        assert_eq!(source(None, None, "release").unwrap(), "priv-1.1.0-release.tar.gz");
+        assert_eq!(source(Some("~1.0"), None, "debug").unwrap(), "priv-1.0.0.tar.gz");
+        assert_eq!(source(Some("^1.0"), Some("1.0.0"), "debug").unwrap(), "priv-1.0.0.tar.gz");
+        assert_eq!(source(Some("^2.0"), None, "debug"), None);
+        assert!(index.resolve("priv", Some("^^1"), None, &build("debug")).is_err());
+
+        let entry = index.resolve("priv", None, None, &build("release")).unwrap().unwrap();
+        assert_eq!(entry.sha256, Some("d15ea5e".to_owned()));
+        let (dir, source) = index.source(entry).unwrap();
+        assert_eq!(dir.join(source), base_dir.path().join("priv-1.1.0-release.tar.gz"));
+
+        assert_eq!(
+            index.available("priv"),
+            vec![
+                "1.0.0 for x86_64-unknown-linux-gnu with rustc 1.21.0",
+                "1.1.0 for x86_64-unknown-linux-gnu with rustc 1.21.0",
+                "1.1.0 for x86_64-unknown-linux-gnu with rustc 1.21.0 (release)",
+                "2.0.0 for x86_64-unknown-linux-gnu with rustc 1.20.0",
+            ]
+        );
+
+        base_dir.close().unwrap();
+    }
+
+    #[test]
+    fn json() {
+        let index = Index::parse(
+            "index.json",
+            Path::new("libs").to_path_buf(),
+            r#"{"package": [{"name": "priv", "version": "1.0.0",
+                "target": "x86_64-unknown-linux-gnu", "rustc": "1.21.0",
+                "source": "priv.tar.gz"}]}"#,
+        ).unwrap();
+        let entry = index.resolve("priv", None, None, &build("debug")).unwrap().unwrap();
+        assert_eq!(entry.source, "priv.tar.gz");
+    }
+}
@@ -1,4 +1,4 @@
-#[cfg(all(feature = "handlebars", feature = "rustc_version"))]
+#[cfg(feature = "rustc_version")]
 extern crate rustc_version;
 
 #[cfg(feature = "handlebars")]
@@ -7,6 +7,17 @@ extern crate handlebars;
 #[cfg(feature = "reqwest")]
 extern crate reqwest;
 
+#[cfg(feature = "encryption")]
+extern crate chacha20poly1305;
+#[cfg(feature = "encryption")]
+extern crate hkdf;
+#[cfg(feature = "encryption")]
+extern crate hmac;
+#[cfg(feature = "encryption")]
+extern crate x25519_dalek;
+#[cfg(feature = "encryption")]
+extern crate getrandom;
+
 #[macro_use]
 extern crate serde_derive;
 #[allow(unused_extern_crates)]
@@ -16,22 +27,68 @@ extern crate quick_error;
 extern crate toml;
 extern crate tar;
 extern crate libflate;
+extern crate semver;
+extern crate serde_json;
+extern crate sha2;
+extern crate fs2;
 
 #[cfg(test)]
 extern crate tempdir;
 
 use std::env::var_os;
 use std::ffi::{OsStr, OsString};
+use std::io;
+use std::path::Path;
+use std::process::Command;
 
+mod artifact;
+mod dependencies;
 mod depot;
+#[cfg(feature = "encryption")]
+mod encryption;
+mod env;
+mod index;
+mod lock;
+mod metadata;
+#[cfg(feature = "reqwest")]
+mod oci;
+mod publish;
 mod recipients;
+mod report;
+mod serve;
 mod manifest;
+mod version;
 #[cfg(feature = "handlebars")]
+mod helpers;
 mod template;
+#[cfg(feature = "reqwest")]
+mod vendor;
+mod wrapper;
 
-pub use recipients::{Recipients, RecipientsError};
+pub use artifact::{ArtifactKind, ArtifactName, ArtifactNaming};
+pub use recipients::{DuplicatePolicy, Recipient, Recipients, RecipientsError};
 pub use manifest::{Manifest, Packages, ManifestCreationError, ManifestInspectionError};
-pub use depot::{Depot, DepotError};
+pub use dependencies::Dependency;
+pub use depot::{Depot, DepotError, MissingPolicy, Receipt};
+#[cfg(feature = "encryption")]
+pub use encryption::{EncryptionError, Key, decrypt, encrypt};
+pub use env::Env;
+pub use index::{Build, Index, IndexEntry, IndexError};
+pub use lock::{LOCK_FILE_NAME, LockEntry, LockError, LockMode, Lockfile, update as update_lock};
+pub use version::VersionError;
+pub use metadata::{ArchiveMetadata, ENCRYPTED_MAGIC};
+#[cfg(feature = "reqwest")]
+pub use oci::{OCI_LAYER_MEDIA_TYPE, OCI_TARGET_ANNOTATION, OciError};
+pub use publish::{INDEX_NAME, PublishError, Registry};
+pub use report::Reporter;
+pub use serve::Server;
+pub use template::{PackageTemplate, TemplateEngine, TemplateGenerationError, TemplateRenderError,
+                   UndefinedVariable};
+#[cfg(feature = "reqwest")]
+pub use vendor::{Cell, VENDOR_DIR_NAME, Vendor, VendorError, VendorReport};
+pub use wrapper::{WrapperError, wrap_rustc};
+#[cfg(feature = "handlebars")]
+pub use handlebars::{Handlebars, Helper, HelperDef, JsonRender, RenderContext, RenderError};
 
 
 quick_error! {
@@ -55,6 +112,12 @@ quick_error! {
             display("failed to inspect manifest: {}", err)
             cause(err)
         }
+        LockError(err: LockError) {
+            from()
+            description("lock error")
+            display("failed to update lockfile: {}", err)
+            cause(err)
+        }
         DepotError(err: DepotError) {
             from()
             description("depot error")
@@ -68,11 +131,68 @@ fn var_os_or<K: AsRef<OsStr>, E, F: FnOnce(K) -> E>(key: K, f: F) -> Result<OsSt
     var_os(key.as_ref()).ok_or_else(|| f(key))
 }
 
+/// Runs rustc, failing if it doesn't succeed, and returns what it printed.
+fn run_rustc(rustc: &OsStr, args: &[&str]) -> io::Result<String> {
+    let output = Command::new(rustc).args(args).output()?;
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "'{} {}' failed with {}: {}",
+                Path::new(rustc).display(),
+                args.join(" "),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The output of `rustc -vV`. Its first line is the same as the output of
+/// `rustc --version`.
+fn rustc_verbose_version(rustc: &OsStr) -> io::Result<String> {
+    run_rustc(rustc, &["-vV"])
+}
+
+/// The `CARGO_CFG_*` variables cargo sets for the output of
+/// `rustc --print cfg`: `key` becomes an empty variable, and the values of
+/// `key="value"` are joined by commas.
+fn cfg_vars(cfg: &str) -> Vec<(String, String)> {
+    let mut vars: Vec<(String, String)> = Vec::new();
+    for line in cfg.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let (key, value) = match line.find('=') {
+            Some(i) => (&line[..i], line[i + 1..].trim_matches('"')),
+            None => (line, ""),
+        };
+        let var = format!("CARGO_CFG_{}", key.to_uppercase());
+        match vars.iter().position(|&(ref v, _)| *v == var) {
+            Some(i) if !value.is_empty() => {
+                vars[i].1.push(',');
+                vars[i].1.push_str(value);
+            }
+            Some(_) => {}
+            None => vars.push((var, value.to_owned())),
+        }
+    }
+    vars
+}
+
+/// The output of `$RUSTC --version`, e.g. `rustc 1.21.0 (3b72af97e 2017-10-09)`.
+fn rustc_version() -> io::Result<String> {
+    let rustc = var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+    let verbose = rustc_verbose_version(&rustc)?;
+    Ok(verbose.lines().next().unwrap_or("").trim().to_owned())
+}
+
 pub fn simply_deliver() -> Result<(), Error> {
     let depot = Depot::new();
     let manifest = Manifest::produce()?;
     let recipients = Recipients::new()?;
+    let mut lock = Lockfile::new(manifest.lock_file.clone())?;
     let packages = manifest.inspect()?;
-    depot.deliver(&recipients, packages)?;
+    let receipt = depot.deliver_locked(&recipients, packages, &mut lock)?;
+    lock.save()?;
+    receipt.print_link_directives();
     Ok(())
 }
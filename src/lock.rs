@@ -0,0 +1,525 @@
+use std::env::temp_dir;
+use std::ffi::OsString;
+use std::fs::canonicalize;
+use std::fs::{File, OpenOptions, rename};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use fs2::FileExt;
+use sha2::{Digest, Sha256};
+use toml::{self, de, ser};
+
+use env::Env;
+use manifest::{ManifestCreationError, read_file};
+use {rustc_version, var_os_or};
+
+/// The name of the lockfile, kept next to `Cargo.lock`.
+pub const LOCK_FILE_NAME: &'static str = "dhl.lock";
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum LockError {
+        EnvError(name: &'static str) {
+            description("environment variable error")
+            display("Undefined environment variable '{}'", name)
+        }
+        RustcError(err: io::Error) {
+            description("rustc version error")
+            display("Unable to determine rustc version: {}", err)
+            cause(err)
+        }
+        Read(path: PathBuf, err: ManifestCreationError) {
+            description("lockfile read error")
+            display("Failed to read '{}': {}", path.display(), err)
+            cause(err)
+        }
+        Parse(path: PathBuf, err: de::Error) {
+            description("lockfile parse error")
+            display("Failed to parse '{}': {}", path.display(), err)
+            cause(err)
+        }
+        Serialize(path: PathBuf, err: ser::Error) {
+            description("lockfile serialize error")
+            display("Failed to serialize '{}': {}", path.display(), err)
+            cause(err)
+        }
+        Lock(path: PathBuf, err: io::Error) {
+            description("lockfile lock error")
+            display("Failed to lock '{}': {}", path.display(), err)
+            cause(err)
+        }
+        Write(path: PathBuf, err: io::Error) {
+            description("lockfile write error")
+            display("Failed to write '{}': {}", path.display(), err)
+            cause(err)
+        }
+        Missing(crate_name: String) {
+            description("package missing from the lockfile")
+            display("crate '{}' is not in {} and DHL_LOCKED is set", crate_name, LOCK_FILE_NAME)
+        }
+        Changed(crate_name: String, locked: LockEntry, source: String, sha256: String) {
+            description("package differs from the lockfile")
+            display("crate '{}' resolved to '{}' (sha256 {}), but {} has '{}' (sha256 {}), \
+                run `dhl update {}` to accept the change",
+                crate_name, source, sha256, LOCK_FILE_NAME, locked.source, locked.sha256,
+                crate_name)
+        }
+    }
+}
+
+/// How a `Lockfile` treats packages that differ from, or are missing from,
+/// the lockfile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// Fail on changed packages and record missing ones.
+    Record,
+    /// Fail on changed and missing packages, like `cargo --locked`. Set with
+    /// `DHL_LOCKED`.
+    Locked,
+    /// Record every package, replacing changed ones. Set with `DHL_UPDATE`.
+    Update,
+}
+
+impl LockMode {
+    pub(crate) fn from_env() -> Self {
+        let env = Env::process();
+        if env.var_os("DHL_LOCKED").is_some() {
+            LockMode::Locked
+        } else if env.var_os("DHL_UPDATE").is_some() {
+            LockMode::Update
+        } else {
+            LockMode::Record
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LockEntry {
+    pub name: String,
+    pub target: String,
+    pub profile: String,
+    pub rustc: String,
+    pub source: String,
+    pub sha256: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct TomlLockfile {
+    #[serde(default)]
+    package: Vec<LockEntry>,
+}
+
+/// The resolved source and digest of every package, for each combination
+/// of target, profile and rustc it was built with.
+#[derive(Debug)]
+pub struct Lockfile {
+    path: PathBuf,
+    target: String,
+    profile: String,
+    rustc: String,
+    mode: LockMode,
+    entries: Vec<LockEntry>,
+    /// The entries recorded since the lockfile was read.
+    recorded: Vec<LockEntry>,
+    /// The crates removed since the lockfile was read, `None` if none were
+    /// and empty if all were.
+    removed: Option<Vec<String>>,
+}
+
+impl Lockfile {
+    /// Opens the lockfile for the current build, using `TARGET`, `PROFILE`
+    /// and `RUSTC` from the build script environment.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Result<Self, LockError> {
+        let target = var_os_or("TARGET", LockError::EnvError)?;
+        let profile = var_os_or("PROFILE", LockError::EnvError)?;
+        let rustc = rustc_version().map_err(LockError::RustcError)?;
+        Self::with_env(
+            path,
+            target.to_string_lossy().into_owned(),
+            profile.to_string_lossy().into_owned(),
+            rustc,
+            LockMode::from_env(),
+        )
+    }
+
+    pub fn with_env<P: Into<PathBuf>>(
+        path: P,
+        target: String,
+        profile: String,
+        rustc: String,
+        mode: LockMode,
+    ) -> Result<Self, LockError> {
+        let path = path.into();
+        let entries = Self::read(&path)?;
+        Ok(Lockfile {
+            path,
+            target,
+            profile,
+            rustc,
+            mode,
+            entries,
+            recorded: Vec::new(),
+            removed: None,
+        })
+    }
+
+    fn read(path: &Path) -> Result<Vec<LockEntry>, LockError> {
+        if !path.is_file() {
+            return Ok(Vec::new());
+        }
+        let contents = read_file(path).map_err(
+            |e| LockError::Read(path.to_path_buf(), e),
+        )?;
+        let lockfile = toml::from_str::<TomlLockfile>(&contents).map_err(
+            |e| LockError::Parse(path.to_path_buf(), e),
+        )?;
+        Ok(lockfile.package)
+    }
+
+    /// The directory sources are recorded relative to.
+    pub fn dir(&self) -> &Path {
+        self.path.parent().unwrap_or_else(|| Path::new(""))
+    }
+
+    /// The entry of a crate for the current target, profile and rustc.
+    pub fn get(&self, crate_name: &str) -> Option<&LockEntry> {
+        self.entries.iter().find(|e| self.matches(e, crate_name))
+    }
+
+    fn matches(&self, entry: &LockEntry, crate_name: &str) -> bool {
+        entry.name == crate_name && entry.target == self.target &&
+            entry.profile == self.profile && entry.rustc == self.rustc
+    }
+
+    /// Checks the resolved source and contents of a package against the
+    /// lockfile, recording it if allowed by the `LockMode`.
+    pub fn check(&mut self, crate_name: &str, source: &str, contents: &[u8]) -> Result<(), LockError> {
+        let sha256 = sha256(contents);
+        let position = self.entries.iter().position(
+            |e| self.matches(e, crate_name),
+        );
+
+        match (position, self.mode) {
+            (Some(i), mode) => {
+                let unchanged = {
+                    let locked = &self.entries[i];
+                    locked.source == source && locked.sha256 == sha256
+                };
+                if unchanged {
+                    return Ok(());
+                }
+                if mode != LockMode::Update {
+                    return Err(LockError::Changed(
+                        crate_name.to_owned(),
+                        self.entries[i].clone(),
+                        source.to_owned(),
+                        sha256,
+                    ));
+                }
+                self.entries.remove(i);
+            }
+            (None, LockMode::Locked) => return Err(LockError::Missing(crate_name.to_owned())),
+            (None, _) => {}
+        }
+
+        let entry = LockEntry {
+            name: crate_name.to_owned(),
+            target: self.target.clone(),
+            profile: self.profile.clone(),
+            rustc: self.rustc.clone(),
+            source: source.to_owned(),
+            sha256,
+        };
+        self.entries.push(entry.clone());
+        self.recorded.push(entry);
+        Ok(())
+    }
+
+    /// Removes the entries of the given crates, or all entries if `crates`
+    /// is empty, for every target, profile and rustc.
+    pub fn remove(&mut self, crates: &[String]) {
+        let before = self.entries.len();
+        Self::retain(&mut self.entries, crates);
+        Self::retain(&mut self.recorded, crates);
+        if self.entries.len() == before {
+            return;
+        }
+        self.removed = Some(match self.removed.take() {
+            Some(ref removed) if removed.is_empty() || crates.is_empty() => Vec::new(),
+            Some(mut removed) => {
+                removed.extend(crates.iter().cloned());
+                removed
+            }
+            None => crates.to_vec(),
+        });
+    }
+
+    /// Drops the entries of the given crates, or all entries if `crates` is
+    /// empty.
+    fn retain(entries: &mut Vec<LockEntry>, crates: &[String]) {
+        entries.retain(|e| !crates.is_empty() && !crates.contains(&e.name));
+    }
+
+    /// Writes the lockfile if anything changed. Other builds may save it at
+    /// the same time, e.g. those of other workspace members, so the changes
+    /// are merged into the lockfile on disk while holding a lock on its
+    /// `guard_path`, and the lockfile is replaced in one rename.
+    pub fn save(&mut self) -> Result<(), LockError> {
+        if self.recorded.is_empty() && self.removed.is_none() {
+            return Ok(());
+        }
+        let guard_path = self.guard_path();
+        let guard = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .open(&guard_path)
+            .and_then(|f| f.lock_exclusive().map(|_| f))
+            .map_err(|e| LockError::Lock(guard_path.clone(), e))?;
+
+        let mut entries = Self::read(&self.path)?;
+        if let Some(ref removed) = self.removed {
+            Self::retain(&mut entries, removed);
+        }
+        for entry in self.recorded.iter() {
+            entries.retain(|e| {
+                (&e.name, &e.target, &e.profile, &e.rustc) !=
+                    (&entry.name, &entry.target, &entry.profile, &entry.rustc)
+            });
+            entries.push(entry.clone());
+        }
+        entries.sort_by(|a, b| {
+            (&a.name, &a.target, &a.profile, &a.rustc).cmp(&(&b.name, &b.target, &b.profile, &b.rustc))
+        });
+        let contents = toml::to_string(&TomlLockfile { package: entries.clone() }).map_err(
+            |e| LockError::Serialize(self.path.clone(), e),
+        )?;
+        let temp_path = self.sibling("tmp");
+        File::create(&temp_path)
+            .and_then(|mut f| {
+                f.write_all(b"# This file is automatically @generated by dhl.\n")?;
+                f.write_all(contents.as_bytes())?;
+                f.sync_all()
+            })
+            .and_then(|_| rename(&temp_path, &self.path))
+            .map_err(|e| LockError::Write(self.path.clone(), e))?;
+        drop(guard);
+
+        self.entries = entries;
+        self.recorded.clear();
+        self.removed = None;
+        Ok(())
+    }
+
+    /// The file locked while saving, in the temporary directory so nothing
+    /// is left next to the lockfile. The lockfile itself can't be locked,
+    /// since it's replaced while others wait on it, and neither can a guard
+    /// that's deleted afterwards.
+    fn guard_path(&self) -> PathBuf {
+        // The lockfile may not exist yet, its directory does
+        let path = match (self.path.parent(), self.path.file_name()) {
+            (Some(dir), Some(name)) => canonicalize(dir).map(|dir| dir.join(name)).ok(),
+            _ => None,
+        }.unwrap_or_else(|| self.path.clone());
+        let key = sha256(path.to_string_lossy().as_bytes());
+        temp_dir().join(format!("dhl-{}.lock", &key[..16]))
+    }
+
+    /// The path of the lockfile with another extension appended, e.g.
+    /// `dhl.lock.tmp`.
+    fn sibling(&self, extension: &str) -> PathBuf {
+        let mut path = OsString::from(self.path.as_os_str());
+        path.push(".");
+        path.push(extension);
+        PathBuf::from(path)
+    }
+}
+
+/// Removes the entries of the given crates from a lockfile, or all entries
+/// if `crates` is empty, so the next build records them again. Returns the
+/// number of entries removed.
+pub fn update<P: Into<PathBuf>>(path: P, crates: &[String]) -> Result<usize, LockError> {
+    let path = path.into();
+    let entries = Lockfile::read(&path)?;
+    let mut lock = Lockfile {
+        path,
+        target: String::new(),
+        profile: String::new(),
+        rustc: String::new(),
+        mode: LockMode::Update,
+        entries,
+        recorded: Vec::new(),
+        removed: None,
+    };
+    let before = lock.entries.len();
+    lock.remove(crates);
+    let removed = before - lock.entries.len();
+    lock.save()?;
+    Ok(removed)
+}
+
+/// The lowercase hex SHA-256 digest of some bytes.
+pub fn sha256(contents: &[u8]) -> String {
+    hex(&Sha256::digest(contents))
+}
+
+/// Like `sha256`, reading the bytes from `reader` rather than holding them.
+pub fn sha256_of<R: Read>(mut reader: R) -> io::Result<String> {
+    let mut hashing = Hashing::new(io::sink());
+    io::copy(&mut reader, &mut hashing)?;
+    Ok(hashing.finish().1)
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A writer that computes the SHA-256 digest of everything written through
+/// it.
+pub struct Hashing<W> {
+    inner: W,
+    digest: Sha256,
+}
+
+impl<W: Write> Hashing<W> {
+    pub fn new(inner: W) -> Self {
+        Hashing {
+            inner,
+            digest: Sha256::default(),
+        }
+    }
+
+    /// The writer and the lowercase hex digest of what was written.
+    pub fn finish(self) -> (W, String) {
+        (self.inner, hex(&self.digest.finalize()))
+    }
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.digest.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::fs::read_dir;
+
+    use tempdir::TempDir;
+
+    use super::{LockError, LockMode, Lockfile, sha256, update};
+
+    fn open(path: &::std::path::Path, rustc: &str, mode: LockMode) -> Lockfile {
+        Lockfile::with_env(
+            path,
+            "x86_64-unknown-linux-gnu".into(),
+            "release".into(),
+            rustc.into(),
+            mode,
+        ).unwrap()
+    }
+
+    #[test]
+    fn digest() {
+        assert_eq!(
+            sha256(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn record_and_enforce() {
+        let base_dir = TempDir::new("lock").unwrap();
+        let path = base_dir.path().join("dhl.lock");
+
+        let mut lock = open(&path, "rustc 1.21.0", LockMode::Locked);
+        match lock.check("priv", "libs/priv.tar.gz", b"v1") {
+            Err(LockError::Missing(..)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        let mut lock = open(&path, "rustc 1.21.0", LockMode::Record);
+        lock.check("priv", "libs/priv.tar.gz", b"v1").unwrap();
+        lock.save().unwrap();
+
+        let mut lock = open(&path, "rustc 1.21.0", LockMode::Locked);
+        lock.check("priv", "libs/priv.tar.gz", b"v1").unwrap();
+        match lock.check("priv", "libs/priv.tar.gz", b"v2") {
+            Err(LockError::Changed(..)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // A different toolchain gets its own entry
+        let mut lock = open(&path, "rustc 1.22.0", LockMode::Record);
+        assert!(lock.get("priv").is_none());
+        lock.check("priv", "libs/priv-1.22.tar.gz", b"v3").unwrap();
+        lock.save().unwrap();
+
+        let mut lock = open(&path, "rustc 1.21.0", LockMode::Update);
+        lock.check("priv", "libs/priv.tar.gz", b"v2").unwrap();
+        lock.save().unwrap();
+
+        let mut lock = open(&path, "rustc 1.21.0", LockMode::Locked);
+        lock.check("priv", "libs/priv.tar.gz", b"v2").unwrap();
+        lock.remove(&["priv".to_owned()]);
+        lock.save().unwrap();
+
+        let lock = open(&path, "rustc 1.22.0", LockMode::Locked);
+        assert!(lock.get("priv").is_none());
+
+        let mut lock = open(&path, "rustc 1.21.0", LockMode::Record);
+        lock.check("priv", "libs/priv.tar.gz", b"v1").unwrap();
+        lock.check("other", "libs/other.tar.gz", b"v1").unwrap();
+        lock.save().unwrap();
+        assert_eq!(update(&path, &["other".to_owned()]).unwrap(), 1);
+        assert_eq!(update(&path, &[]).unwrap(), 1);
+        assert!(open(&path, "rustc 1.21.0", LockMode::Record).get("priv").is_none());
+
+        base_dir.close().unwrap();
+    }
+
+    #[test]
+    fn merge_saves() {
+        let base_dir = TempDir::new("lock").unwrap();
+        let path = base_dir.path().join("dhl.lock");
+
+        // Builds that opened the lockfile at the same time keep each other's
+        // entries
+        let mut first = open(&path, "rustc 1.21.0", LockMode::Record);
+        let mut second = open(&path, "rustc 1.21.0", LockMode::Record);
+        first.check("priv", "libs/priv.tar.gz", b"v1").unwrap();
+        second.check("other", "libs/other.tar.gz", b"v1").unwrap();
+        first.save().unwrap();
+        second.save().unwrap();
+
+        let mut lock = open(&path, "rustc 1.21.0", LockMode::Locked);
+        lock.check("priv", "libs/priv.tar.gz", b"v1").unwrap();
+        lock.check("other", "libs/other.tar.gz", b"v1").unwrap();
+
+        // Removals only drop the entries of the removed crates
+        let mut first = open(&path, "rustc 1.21.0", LockMode::Update);
+        let mut second = open(&path, "rustc 1.21.0", LockMode::Update);
+        first.remove(&["priv".to_owned()]);
+        second.check("other", "libs/other.tar.gz", b"v2").unwrap();
+        second.save().unwrap();
+        first.save().unwrap();
+
+        let mut lock = open(&path, "rustc 1.21.0", LockMode::Locked);
+        assert!(lock.get("priv").is_none());
+        lock.check("other", "libs/other.tar.gz", b"v2").unwrap();
+        // Nothing but the lockfile is left in the workspace
+        let names = read_dir(base_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["dhl.lock"]);
+
+        base_dir.close().unwrap();
+    }
+}
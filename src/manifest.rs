@@ -1,18 +1,23 @@
 use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
 use std::io::{self, BufReader, Read};
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 
-#[cfg(feature = "handlebars")]
-use handlebars::TemplateRenderError;
 #[cfg(feature = "reqwest")]
 use reqwest::{Url, UrlError};
 use toml::{self, de};
 use quick_error::ResultExt;
 
 use var_os_or;
-#[cfg(feature = "handlebars")]
-use template::{TemplateEngine, TemplateGenerationError};
+use env::Env;
+use report;
+use dependencies::{Dependencies, TomlDependencies, TomlDependency};
+use lock::LOCK_FILE_NAME;
+use depot::MissingPolicy;
+use index::{Build, Index, IndexError};
+use recipients::DuplicatePolicy;
+use template::{TemplateEngine, TemplateGenerationError, TemplateRenderError, UndefinedVariable};
 
 quick_error! {
     #[derive(Debug)]
@@ -34,10 +39,24 @@ quick_error! {
             display("TOML error: {}", err)
             cause(err)
         }
+        InvalidEnv(name: &'static str, value: String) {
+            description("invalid environment variable")
+            display("Invalid value '{}' for environment variable '{}'", value, name)
+        }
+        InvalidSubstitution(key: String, reason: &'static str) {
+            description("invalid substitution")
+            display("Invalid substitution '{}': {}", key, reason)
+        }
+        InvalidPackage(crate_name: String, reason: &'static str) {
+            description("invalid package")
+            display("Invalid package '{}': {}", crate_name, reason)
+        }
     }
 }
 
-#[cfg(all(feature = "handlebars", feature = "reqwest"))]
+/// The name of the standalone config file next to `Cargo.toml`.
+pub const CONFIG_FILE_NAME: &'static str = "dhl.toml";
+
 quick_error! {
     #[derive(Debug)]
     pub enum ManifestInspectionError {
@@ -54,137 +73,222 @@ quick_error! {
             display("crate '{}' failed to render from '{:?}': {}", crate_name, source, err)
             cause(err)
         }
-        Url(crate_name: String, source: UninspectedPackage, err: UrlError) {
-            context(context: (&'a str, &'a UninspectedPackage), err: UrlError) ->
-                (context.0.to_owned(), context.1.clone(), err)
-            description("crate source url failed to parse")
-            display("crate '{}' url failed to parse from '{:?}': {}", crate_name, source, err)
+        UndefinedVariable(crate_name: String, err: UndefinedVariable) {
+            description("crate source template uses an undefined variable")
+            display("crate '{}' failed to render its source: {}", crate_name, err)
             cause(err)
         }
-    }
-}
-
-#[cfg(all(not(feature = "handlebars"), feature = "reqwest"))]
-quick_error! {
-    #[derive(Debug)]
-    pub enum ManifestInspectionError {
-        #[cfg()]
+        Index(crate_name: String, err: IndexError) {
+            description("crate index failed to resolve")
+            display("crate '{}' failed to resolve from its index: {}", crate_name, err)
+            cause(err)
+        }
+        #[cfg(feature = "reqwest")]
         Url(crate_name: String, source: UninspectedPackage, err: UrlError) {
-            context(context: (&'a str, &'a UninspectedPackage), err: UrlError) ->
-                (context.0.to_owned(), context.1.clone(), err)
             description("crate source url failed to parse")
             display("crate '{}' url failed to parse from '{:?}': {}", crate_name, source, err)
             cause(err)
         }
     }
 }
-#[cfg(all(feature = "handlebars", not(feature = "reqwest")))]
-quick_error! {
-    #[derive(Debug)]
-    pub enum ManifestInspectionError {
-        TemplateGeneration(err: TemplateGenerationError) {
-            from()
-            description("template failed to generate")
-            display("Template generation error: {}", err)
-            cause(err)
-        }
-        TemplateRender(crate_name: String, source: UninspectedPackage, err: TemplateRenderError) {
-            context(context: (&'a str, &'a UninspectedPackage), err: TemplateRenderError) ->
-                (context.0.to_owned(), context.1.clone(), err)
-            description("crate source template failed to render")
-            display("crate '{}' failed to render from '{:?}': {}", crate_name, source, err)
-            cause(err)
-        }
-    }
-}
-#[cfg(all(not(feature = "handlebars"), not(feature = "reqwest")))]
-quick_error! {
-    #[derive(Debug)]
-    pub enum ManifestInspectionError {
-    }
-}
-
 #[derive(Deserialize, Debug)]
 struct Toml {
+    #[serde(default)]
     package: TomlPackage,
-    dependencies: HashMap<String, TomlDependency>,
+    #[serde(flatten)]
+    dependencies: TomlDependencies,
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(untagged)]
-enum TomlDependency {
-    String(String),
-    Table {
-        version: Option<String>,
-        path: Option<String>,
-    },
+#[derive(Deserialize, Debug, Default)]
+struct TomlPackage {
+    workspace: Option<String>,
+    metadata: Option<TomlMetadata>,
 }
 
 #[derive(Deserialize, Debug)]
-struct TomlPackage {
-    metadata: TomlPackageMetadata,
+struct TomlMetadata {
+    dhl: Option<TomlDhl>,
 }
 
+/// Just enough of a `Cargo.toml` to find a workspace root.
 #[derive(Deserialize, Debug)]
-struct TomlPackageMetadata {
-    dhl: TomlDhl,
+struct TomlWorkspaceRoot {
+    workspace: Option<TomlWorkspace>,
 }
 
-#[cfg(feature = "handlebars")]
 #[derive(Deserialize, Debug)]
+struct TomlWorkspace {
+    #[serde(default)]
+    members: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+    #[serde(default)]
+    dependencies: HashMap<String, TomlDependency>,
+    metadata: Option<TomlMetadata>,
+}
+
+#[derive(Deserialize, Debug, Default)]
 struct TomlDhl {
     substitutions: Option<HashMap<String, TomlDhlSubstitution>>,
-    packages: HashMap<String, String>,
+    strict: Option<bool>,
+    duplicates: Option<DuplicatePolicy>,
+    #[serde(rename = "from-source")]
+    from_source: Option<Vec<String>>,
+    #[serde(rename = "vendor-dir")]
+    vendor_dir: Option<String>,
+    #[serde(default)]
+    packages: HashMap<String, TomlDhlPackage>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum TomlDhlPackage {
+    String(String),
+    Table {
+        source: Option<String>,
+        index: Option<String>,
+        hash: Option<String>,
+        #[serde(default, rename = "link-libs")]
+        link_libs: Vec<String>,
+        #[serde(default, rename = "on-missing")]
+        on_missing: MissingPolicy,
+        #[serde(default)]
+        substitutions: HashMap<String, TomlDhlSubstitution>,
+        key: Option<String>,
+    },
 }
 
-#[cfg(feature = "handlebars")]
 #[derive(Deserialize, Debug)]
 #[serde(untagged)]
 enum TomlDhlSubstitution {
     String(String),
     Table {
-        value: String,
+        value: TomlDhlSubstitutionValue,
         #[serde(default)]
         env: bool,
+        default: Option<String>,
+        required: Option<bool>,
     },
 }
 
-#[cfg(not(feature = "handlebars"))]
+/// A value, or with `env = true`, environment variables to try in order.
 #[derive(Deserialize, Debug)]
-struct TomlDhl {
-    packages: HashMap<String, String>,
+#[serde(untagged)]
+enum TomlDhlSubstitutionValue {
+    String(String),
+    List(Vec<String>),
+}
+
+/// A dhl config, and the directory relative paths in it are resolved against.
+#[derive(Debug)]
+struct DhlLayer {
+    dir: PathBuf,
+    dhl: TomlDhl,
 }
 
 #[derive(Debug, Clone)]
 pub struct Manifest {
     pub packages: HashMap<String, UninspectedPackage>,
-    #[cfg(feature = "handlebars")]
+    pub duplicates: DuplicatePolicy,
     pub substitutions: HashMap<String, Substitution>,
+    /// Whether sources may only use defined variables.
+    pub strict: bool,
+    /// Packages built from their real source instead of being hijacked.
+    pub from_source: Vec<String>,
+    /// Where `dhl vendor` put the archives of url sources, which are used
+    /// instead of downloading them.
+    pub vendor_dir: Option<PathBuf>,
     pub manifest_dir: PathBuf,
+    /// The `dhl.lock` next to the `Cargo.lock` of the workspace or package.
+    pub lock_file: PathBuf,
+    /// The environment the manifest was read with, which templates read too.
+    env: Env,
 }
 
 #[derive(Debug, Clone)]
 pub struct UninspectedPackage {
     pub version: Option<String>,
+    /// The actual version of the dummy crate.
+    pub package_version: Option<String>,
+    /// The name of the library the dummy crate builds, which its artifacts
+    /// are named after.
+    pub lib_name: String,
+    /// The directory of the dummy crate, if it's a path dependency.
+    pub path: Option<PathBuf>,
     pub source: String,
+    /// Whether `source` is an index to resolve the archive from.
+    pub from_index: bool,
+    /// The directory of the manifest that declared the package, relative
+    /// sources are resolved against it.
+    pub base_dir: PathBuf,
+    /// The metadata hash of the dummy crate's artifacts, to pick between
+    /// several builds of it in `deps`, a template like `source`.
+    pub hash: Option<String>,
+    /// Native libraries to link, in the `[KIND=]NAME` form of
+    /// `cargo:rustc-link-lib`.
+    pub link_libs: Vec<String>,
+    /// Substitutions of this package only, layered over the global ones.
+    pub substitutions: HashMap<String, Substitution>,
+    pub on_missing: MissingPolicy,
+    /// The key encrypted archives are decrypted with, a template like
+    /// `source`.
+    pub key: Option<Secret>,
+}
+
+/// A key, or a template of one, which `Debug` doesn't print, since
+/// packages end up in error messages.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(pub String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
 }
 
-#[cfg(feature = "handlebars")]
 #[derive(Debug, Clone)]
 pub enum Substitution {
     Value(String),
-    EnvironmentVariable(String),
+    /// The first of `keys` that is set, else `default`. If neither is
+    /// available the substitution is an error if `required`, and empty
+    /// otherwise.
+    EnvironmentVariable {
+        keys: Vec<String>,
+        default: Option<String>,
+        required: bool,
+    },
 }
 
 #[derive(Debug, Clone)]
 pub struct Packages {
     pub(super) packages: HashMap<String, Package>,
+    pub(super) from_source: HashMap<String, SourcePackage>,
+    pub(super) duplicates: DuplicatePolicy,
+}
+
+/// A package built from its real source, which is only checked to not be
+/// the dummy crate.
+#[derive(Debug, Clone)]
+pub struct SourcePackage {
+    pub lib_name: String,
+    pub hash: Option<String>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Package {
     pub version: Option<String>,
+    pub package_version: Option<String>,
+    pub lib_name: String,
     pub data: PackageData,
+    pub hash: Option<String>,
+    pub link_libs: Vec<String>,
+    pub on_missing: MissingPolicy,
+    /// The index the package was resolved from.
+    pub index: Option<String>,
+    /// The digest the archive must have, from an index.
+    pub sha256: Option<String>,
+    /// The key to decrypt the archive with, if it's encrypted.
+    pub key: Option<Secret>,
 }
 
 #[derive(Debug, Clone)]
@@ -192,6 +296,8 @@ pub enum PackageData {
     File(FileData),
     #[cfg(feature = "reqwest")]
     Url(UrlData),
+    /// An index without a build for the current target and rustc.
+    Missing(MissingData),
 }
 
 
@@ -200,12 +306,105 @@ pub struct FileData {
     pub source: PathBuf,
 }
 
+#[derive(Debug, Clone)]
+pub struct MissingData {
+    /// The index.
+    pub source: String,
+    /// The builds the index does have.
+    pub available: Vec<String>,
+}
+
 #[cfg(feature = "reqwest")]
 #[derive(Debug, Clone)]
 pub struct UrlData {
     pub source: Url,
 }
 
+/// Where `dhl vendor` puts the file at an `http` or `https` url,
+/// `<vendor_dir>/<host>/<path>`.
+pub(crate) fn vendor_path(vendor_dir: &Path, url: &str) -> Option<PathBuf> {
+    let rest = if url.starts_with("http://") {
+        &url["http://".len()..]
+    } else if url.starts_with("https://") {
+        &url["https://".len()..]
+    } else {
+        return None;
+    };
+    let rest = rest.split(&['?', '#'][..]).next().unwrap_or("");
+    let mut parts = rest.split('/');
+    let host = match parts.next() {
+        Some(host) if !host.is_empty() => host.replace(':', "_"),
+        _ => return None,
+    };
+    let mut path = vendor_dir.join(host);
+    for part in parts.filter(|p| !p.is_empty() && *p != ".") {
+        if part == ".." {
+            return None;
+        }
+        path.push(part);
+    }
+    Some(path)
+}
+
+pub(crate) fn read_file(path: &Path) -> Result<String, ManifestCreationError> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    Ok(contents)
+}
+
+/// Reads a file the manifest depends on, so the build script reruns when it
+/// changes.
+fn read_input(path: &Path) -> Result<String, ManifestCreationError> {
+    report::rerun_if_changed(path);
+    read_file(path)
+}
+
+/// Resolves `.` and `..` in a path without touching the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if normalized.file_name().is_some() => {
+                normalized.pop();
+            }
+            component => normalized.push(component.as_os_str()),
+        }
+    }
+    normalized
+}
+
+/// Whether a relative path matches a `members` pattern of a workspace, where
+/// `*` and `?` match within a single component.
+fn glob_matches(pattern: &str, path: &Path) -> bool {
+    fn matches(pattern: &[char], name: &[char]) -> bool {
+        match pattern.split_first() {
+            None => name.is_empty(),
+            Some((&'*', rest)) => (0..name.len() + 1).any(|i| matches(rest, &name[i..])),
+            Some((&'?', rest)) => !name.is_empty() && matches(rest, &name[1..]),
+            Some((c, rest)) => name.first() == Some(c) && matches(rest, &name[1..]),
+        }
+    }
+
+    let pattern = normalize(Path::new(pattern));
+    let mut patterns = pattern.components();
+    let mut names = path.components();
+    loop {
+        match (patterns.next(), names.next()) {
+            (None, None) => return true,
+            (Some(p), Some(n)) => {
+                let p = p.as_os_str().to_string_lossy().chars().collect::<Vec<_>>();
+                let n = n.as_os_str().to_string_lossy().chars().collect::<Vec<_>>();
+                if !matches(&p, &n) {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+    }
+}
+
 impl Manifest {
     // produce
     pub fn produce() -> Result<Self, ManifestCreationError> {
@@ -213,216 +412,596 @@ impl Manifest {
             "CARGO_MANIFEST_DIR",
             ManifestCreationError::EnvError,
         )?);
-        let manifest_file = manifest_dir.join(Path::new("Cargo.toml"));
-        Self::produce_from_file(manifest_dir, manifest_file)
+        Self::produce_from_dir(manifest_dir)
+    }
+
+    /// Reads the manifest of the package in `manifest_dir`, for use outside
+    /// a build script.
+    pub fn produce_from_dir<P: Into<PathBuf>>(manifest_dir: P) -> Result<Self, ManifestCreationError> {
+        Self::produce_from_dir_with_env(manifest_dir, Env::process())
+    }
 
+    /// Like `produce_from_dir`, reading the environment from `env`.
+    pub(crate) fn produce_from_dir_with_env<P: Into<PathBuf>>(
+        manifest_dir: P,
+        env: Env,
+    ) -> Result<Self, ManifestCreationError> {
+        let manifest_dir = manifest_dir.into();
+        let manifest_file = manifest_dir.join(Path::new("Cargo.toml"));
+        Self::produce_from_file(manifest_dir, manifest_file, env)
     }
 
     fn produce_from_file(
         manifest_dir: PathBuf,
         manifest_file: PathBuf,
+        env: Env,
     ) -> Result<Self, ManifestCreationError> {
-        let mut manifest_file = BufReader::new(File::open(manifest_file)?);
-        let mut contents = String::new();
-        manifest_file.read_to_string(&mut contents)?;
-        Self::produce_from_string(manifest_dir, contents)
+        let contents = toml::from_str::<Toml>(&*read_input(&manifest_file)?)?;
+
+        let mut below = Vec::new();
+        below.extend(Self::read_config(Self::user_config_file(&env))?);
+
+        let mut workspace_dependencies = None;
+        if let Some((dir, workspace)) = Self::find_workspace(&manifest_dir, &contents)? {
+            if let Some(dhl) = workspace.metadata.and_then(|m| m.dhl) {
+                below.push(DhlLayer {
+                    dir: dir.clone(),
+                    dhl,
+                });
+            }
+            workspace_dependencies = Some((dir, workspace.dependencies));
+        }
+
+        let above = Self::read_config(Some(manifest_dir.join(CONFIG_FILE_NAME)))?;
+
+        Self::produce_from_toml(manifest_dir, contents, below, above, workspace_dependencies, env)
     }
 
     fn produce_from_string(
         manifest_dir: PathBuf,
         contents: String,
+        env: Env,
     ) -> Result<Self, ManifestCreationError> {
-        Self::produce_from_toml(manifest_dir, toml::from_str::<Toml>(&*contents)?)
+        let contents = toml::from_str::<Toml>(&*contents)?;
+        Self::produce_from_toml(manifest_dir, contents, Vec::new(), None, None, env)
     }
 
-    #[cfg(feature = "handlebars")]
-    fn produce_from_toml(
-        manifest_dir: PathBuf,
-        contents: Toml,
-    ) -> Result<Self, ManifestCreationError> {
-        let Toml {
-            package: TomlPackage {
-                metadata: TomlPackageMetadata {
-                    dhl: TomlDhl {
-                        substitutions,
-                        packages,
-                    },
-                },
-            },
-            dependencies,
-        } = contents;
+    /// The user-level config file, `$DHL_CONFIG` if set, otherwise
+    /// `dhl/config.toml` in `$XDG_CONFIG_HOME` or `~/.config`.
+    fn user_config_file(env: &Env) -> Option<PathBuf> {
+        if let Some(file) = env.var_os("DHL_CONFIG") {
+            return Some(PathBuf::from(file));
+        }
+        let config_dir = env.var_os("XDG_CONFIG_HOME").map(PathBuf::from).or_else(|| {
+            env.var_os("HOME").or_else(|| env.var_os("USERPROFILE")).map(|home| {
+                PathBuf::from(home).join(".config")
+            })
+        });
+        config_dir.map(|dir| dir.join("dhl").join("config.toml"))
+    }
+
+    /// Reads a standalone config file, if it exists.
+    fn read_config(file: Option<PathBuf>) -> Result<Option<DhlLayer>, ManifestCreationError> {
+        let file = match file {
+            Some(ref file) if file.is_file() => file,
+            _ => return Ok(None),
+        };
+        let dhl = toml::from_str::<TomlDhl>(&*read_input(file)?)?;
+        let dir = file.parent().map(Path::to_path_buf).unwrap_or_default();
+        Ok(Some(DhlLayer { dir, dhl }))
+    }
 
-        let packages = Self::load_packages(packages, dependencies);
-
-        let substitutions = match substitutions {
-            Some(s) => {
-                s.into_iter()
-                    .map(|(k, v)| {
-                        (
-                            k,
-                            match v {
-                                TomlDhlSubstitution::String(v) => Substitution::Value(v),
-                                TomlDhlSubstitution::Table {
-                                    value: v,
-                                    env: true,
-                                } => Substitution::EnvironmentVariable(v),
-                                TomlDhlSubstitution::Table {
-                                    value: v,
-                                    env: false,
-                                } => Substitution::Value(v),
-                            },
-                        )
-                    })
-                    .collect()
+    /// The settings overridden by `DHL_*` environment variables.
+    fn env_layer(manifest_dir: &Path, env: &Env) -> Result<DhlLayer, ManifestCreationError> {
+        let mut dhl = TomlDhl::default();
+        if let Some(duplicates) = env.var_os("DHL_DUPLICATES") {
+            let duplicates = duplicates.to_string_lossy().into_owned();
+            dhl.duplicates = Some(duplicates.parse().map_err(|_| {
+                ManifestCreationError::InvalidEnv("DHL_DUPLICATES", duplicates)
+            })?);
+        }
+        if let Some(from_source) = env.var_os("DHL_FROM_SOURCE") {
+            dhl.from_source = Some(
+                from_source
+                    .to_string_lossy()
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(ToOwned::to_owned)
+                    .collect(),
+            );
+        }
+        if let Some(vendor_dir) = env.var_os("DHL_VENDOR_DIR") {
+            dhl.vendor_dir = Some(vendor_dir.to_string_lossy().into_owned());
+        }
+        if let Some(strict) = env.var_os("DHL_STRICT") {
+            let strict = strict.to_string_lossy().into_owned();
+            dhl.strict = Some(strict.parse().map_err(|_| {
+                ManifestCreationError::InvalidEnv("DHL_STRICT", strict)
+            })?);
+        }
+        Ok(DhlLayer {
+            dir: manifest_dir.to_path_buf(),
+            dhl,
+        })
+    }
+
+    /// Finds the `[workspace]` the package belongs to, by walking up to the
+    /// first `Cargo.toml` with one, or by following `package.workspace`.
+    fn find_workspace(
+        manifest_dir: &Path,
+        contents: &Toml,
+    ) -> Result<Option<(PathBuf, TomlWorkspace)>, ManifestCreationError> {
+        let (root_dir, root) = if let Some(ref workspace) = contents.package.workspace {
+            let root_dir = manifest_dir.join(workspace);
+            let root = toml::from_str::<TomlWorkspaceRoot>(
+                &*read_input(&root_dir.join("Cargo.toml"))?,
+            )?;
+            match root.workspace {
+                Some(root) => (root_dir, root),
+                None => return Ok(None),
             }
-            None => {
-                let mut map = HashMap::new();
-                map.insert(
-                    "target".to_owned(),
-                    Substitution::EnvironmentVariable("TARGET".to_owned()),
-                );
-                map.insert(
-                    "profile".to_owned(),
-                    Substitution::EnvironmentVariable("PROFILE".to_owned()),
-                );
-                map
+        } else {
+            let mut dir = Some(manifest_dir);
+            loop {
+                let root_dir = match dir {
+                    Some(d) => d,
+                    None => return Ok(None),
+                };
+                dir = root_dir.parent();
+
+                let root_file = root_dir.join("Cargo.toml");
+                if !root_file.is_file() {
+                    continue;
+                }
+                let root = toml::from_str::<TomlWorkspaceRoot>(&*read_input(&root_file)?)?;
+                if let Some(root) = root.workspace {
+                    break (root_dir.to_path_buf(), root);
+                }
             }
         };
 
-        Ok(Manifest {
-            packages,
-            substitutions,
-            manifest_dir,
-        })
+        // Like cargo, only the root package and packages matched by
+        // `members` are part of the workspace, and excluded ones never are
+        let member = {
+            let root_dir = normalize(&root_dir);
+            match normalize(manifest_dir).strip_prefix(&root_dir) {
+                Ok(relative_dir) => {
+                    relative_dir.as_os_str().is_empty() ||
+                        (root.members.iter().any(|m| glob_matches(m, relative_dir)) &&
+                             !root.exclude.iter().any(|e| relative_dir.starts_with(e)))
+                }
+                Err(_) => false,
+            }
+        };
+        if !member {
+            return Ok(None);
+        }
+
+        Ok(Some((root_dir, root)))
     }
 
-    #[cfg(not(feature = "handlebars"))]
+    /// Merges the package's `[package.metadata.dhl]` with the layers `below`
+    /// and `above` it, and the environment overrides.
     fn produce_from_toml(
         manifest_dir: PathBuf,
         contents: Toml,
+        below: Vec<DhlLayer>,
+        above: Option<DhlLayer>,
+        workspace_dependencies: Option<(PathBuf, HashMap<String, TomlDependency>)>,
+        env: Env,
     ) -> Result<Self, ManifestCreationError> {
-        let Toml{
-            package: TomlPackagePackage{
-                metadata: TomlPackageMetadata{
-                    dhl: TomlDhl{packages}
-                }
-            },
+        let Toml {
+            package: TomlPackage { metadata, .. },
             dependencies,
         } = contents;
 
-        let packages = Self::load_packages(packages, dependencies);
+        let member = metadata.and_then(|m| m.dhl).map(|dhl| {
+            DhlLayer {
+                dir: manifest_dir.clone(),
+                dhl,
+            }
+        });
+
+        let overrides = Self::env_layer(&manifest_dir, &env)?;
+
+        // Later layers override earlier ones
+        let mut packages = HashMap::new();
+        let mut duplicates = None;
+        let mut substitutions = HashMap::new();
+        let mut strict = None;
+        let mut from_source = None;
+        let mut vendor_dir = None;
+        let layers = below.into_iter().chain(member).chain(above).chain(Some(overrides));
+        for DhlLayer { dir, dhl } in layers {
+            if let Some(s) = dhl.substitutions {
+                substitutions.extend(Self::load_substitutions(s)?);
+            }
+            strict = dhl.strict.or(strict);
+            duplicates = dhl.duplicates.or(duplicates);
+            from_source = dhl.from_source.or(from_source);
+            vendor_dir = dhl.vendor_dir.map(|d| dir.join(d)).or(vendor_dir);
+            for (k, package) in dhl.packages {
+                packages.insert(k, (dir.clone(), package));
+            }
+        }
+
+        let lock_dir = workspace_dependencies
+            .as_ref()
+            .map_or(&manifest_dir, |&(ref dir, _)| dir)
+            .clone();
+        report::rerun_if_changed(&lock_dir.join("Cargo.lock"));
+        let dependencies = Dependencies::new(
+            manifest_dir.clone(),
+            dependencies,
+            workspace_dependencies,
+            env.clone(),
+        )?;
+        let packages = Self::load_packages(packages, &dependencies, &manifest_dir, &env)?;
 
         Ok(Manifest {
             packages,
+            duplicates: duplicates.unwrap_or_default(),
+            substitutions,
+            strict: strict.unwrap_or(true),
+            from_source: from_source.unwrap_or_default(),
+            vendor_dir,
             manifest_dir,
+            lock_file: lock_dir.join(LOCK_FILE_NAME),
+            env,
         })
     }
 
+    fn load_substitutions(
+        substitutions: HashMap<String, TomlDhlSubstitution>,
+    ) -> Result<HashMap<String, Substitution>, ManifestCreationError> {
+        use self::TomlDhlSubstitutionValue::{List, String as Single};
+        use self::ManifestCreationError::InvalidSubstitution;
+
+        let mut loaded = HashMap::with_capacity(substitutions.len());
+        for (k, v) in substitutions.into_iter() {
+            let substitution = match v {
+                TomlDhlSubstitution::String(v) => Substitution::Value(v),
+                TomlDhlSubstitution::Table {
+                    value,
+                    env: true,
+                    default,
+                    required,
+                } => {
+                    let keys = match value {
+                        Single(key) => vec![key],
+                        List(keys) => keys,
+                    };
+                    if keys.is_empty() {
+                        return Err(InvalidSubstitution(k, "no environment variables listed"));
+                    }
+                    Substitution::EnvironmentVariable {
+                        keys,
+                        default,
+                        required: required.unwrap_or(true),
+                    }
+                }
+                TomlDhlSubstitution::Table {
+                    value: Single(v),
+                    env: false,
+                    default: None,
+                    required: None,
+                } => Substitution::Value(v),
+                TomlDhlSubstitution::Table { value: List(_), env: false, .. } => {
+                    return Err(InvalidSubstitution(k, "only `env = true` accepts a list"));
+                }
+                TomlDhlSubstitution::Table { env: false, .. } => {
+                    return Err(InvalidSubstitution(
+                        k,
+                        "`default` and `required` need `env = true`",
+                    ));
+                }
+            };
+            loaded.insert(k, substitution);
+        }
+        Ok(loaded)
+    }
+
+    /// Resolves packages against their dependencies. `DHL_SOURCE_<CRATE>`,
+    /// with the crate name uppercased and `-` replaced by `_`, replaces a
+    /// package's source, relative to the manifest dir. `DHL_KEY_<CRATE>`
+    /// likewise replaces its key.
     fn load_packages(
-        packages: HashMap<String, String>,
-        mut dependencies: HashMap<String, TomlDependency>,
-    ) -> HashMap<String, UninspectedPackage> {
-        packages
-            .into_iter()
-            .map(|(k, source)| {
-                let version =
-                    if let Some(TomlDependency::Table { version, .. }) = dependencies.remove(&k) {
-                        version
-                    } else {
-                        None
+        packages: HashMap<String, (PathBuf, TomlDhlPackage)>,
+        dependencies: &Dependencies,
+        manifest_dir: &Path,
+        env: &Env,
+    ) -> Result<HashMap<String, UninspectedPackage>, ManifestCreationError> {
+        let mut uninspected = HashMap::with_capacity(packages.len());
+        for (k, (base_dir, package)) in packages.into_iter() {
+            let (version, lib_name, package_version, path) = match dependencies.get(&k) {
+                Some(dependency) => {
+                    if let Some(ref path) = dependency.path {
+                        report::rerun_if_changed(&path.join("Cargo.toml"));
+                    }
+                    (
+                        dependency.version.clone(),
+                        dependency.lib_name()?,
+                        dependencies.package_version(&dependency)?,
+                        dependency.path,
+                    )
+                }
+                None => (None, k.replace('-', "_"), None, None),
+            };
+
+            let package = match package {
+                TomlDhlPackage::String(source) => {
+                    let on_missing = MissingPolicy::default();
+                    (source, false, None, Vec::new(), on_missing, HashMap::new(), None)
+                }
+                TomlDhlPackage::Table {
+                    source,
+                    index,
+                    hash,
+                    link_libs,
+                    on_missing,
+                    substitutions,
+                    key,
+                } => {
+                    let (source, from_index) = match (source, index) {
+                        (Some(source), None) => (source, false),
+                        (None, Some(index)) => (index, true),
+                        _ => {
+                            return Err(ManifestCreationError::InvalidPackage(
+                                k,
+                                "exactly one of `source` and `index` is required",
+                            ))
+                        }
                     };
+                    let substitutions = Self::load_substitutions(substitutions)?;
+                    (source, from_index, hash, link_libs, on_missing, substitutions, key)
+                }
+            };
+            let (source, from_index, hash, link_libs, on_missing, substitutions, key) = package;
 
-                let v = UninspectedPackage { version, source };
-                (k, v)
-            })
-            .collect()
+            let (mut source, mut from_index, mut base_dir) = (source, from_index, base_dir);
+            let env_name = k.to_uppercase().replace('-', "_");
+            if let Some(overridden) = env.var_os(&format!("DHL_SOURCE_{}", env_name)) {
+                source = overridden.to_string_lossy().into_owned();
+                from_index = false;
+                base_dir = manifest_dir.to_path_buf();
+            }
+            let key = env.var_os(&format!("DHL_KEY_{}", env_name))
+                .map(|key| key.to_string_lossy().into_owned())
+                .or(key)
+                .map(Secret);
+
+            let v = UninspectedPackage {
+                version,
+                package_version,
+                lib_name,
+                path,
+                source,
+                from_index,
+                base_dir,
+                hash,
+                link_libs,
+                substitutions,
+                on_missing,
+                key,
+            };
+            uninspected.insert(k, v);
+        }
+        Ok(uninspected)
+    }
+
+    /// Creates the engine sources are rendered with, so a build script can
+    /// register its own helpers before calling `inspect_with`.
+    pub fn template_engine(&self) -> Result<TemplateEngine, TemplateGenerationError> {
+        TemplateEngine::with_env(self.substitutions.clone(), self.env.clone())
     }
 
-    #[cfg(feature = "handlebars")]
     pub fn inspect(self) -> Result<Packages, ManifestInspectionError> {
-        let template = TemplateEngine::new(self.substitutions)?;
+        let template = self.template_engine()?;
+        self.inspect_with(&template)
+    }
+
+    pub fn inspect_with(
+        self,
+        template: &TemplateEngine,
+    ) -> Result<Packages, ManifestInspectionError> {
+        self.inspect_build(template, None)
+    }
+
+    /// Like `inspect_with`, but resolves indexes for `build` instead of the
+    /// build script's target, profile and rustc.
+    pub fn inspect_for(
+        self,
+        template: &TemplateEngine,
+        build: Build,
+    ) -> Result<Packages, ManifestInspectionError> {
+        self.inspect_build(template, Some(build))
+    }
+
+    fn inspect_build(
+        self,
+        template: &TemplateEngine,
+        mut build: Option<Build>,
+    ) -> Result<Packages, ManifestInspectionError> {
         let mut packages = HashMap::with_capacity(self.packages.len());
+        let mut from_source = HashMap::new();
+        let mut indexes: HashMap<(PathBuf, String), Index> = HashMap::new();
+        let vendor_dir = self.vendor_dir.as_ref().map(PathBuf::as_path);
+        let strict = self.strict;
+        for crate_name in self.from_source.iter() {
+            if !self.packages.contains_key(crate_name) {
+                report::warning(&format!(
+                    "'{}' is listed in from-source, but isn't one of the dhl packages",
+                    crate_name
+                ));
+            }
+        }
         for (crate_name, package) in self.packages.into_iter() {
-            let source = {
-                let source = package.source.as_ref();
+            let built_from_source = self.from_source.contains(&crate_name);
+            let (source, key, hash) = {
+                let template = template.for_package(&package.substitutions)?;
                 let version = package.version.as_ref().map(AsRef::as_ref);
-                template.render(source, version).context((
+                let render = |source: &str| -> Result<String, ManifestInspectionError> {
+                    if strict {
+                        template.check(source, version).map_err(|e| {
+                            ManifestInspectionError::UndefinedVariable(crate_name.clone(), e)
+                        })?;
+                    }
+                    Ok(template.render(source, version).context((
+                        crate_name.as_ref(),
+                        &package,
+                    ))?)
+                };
+                let hash = match package.hash {
+                    Some(ref hash) => Some(render(hash)?),
+                    None => None,
+                };
+                if built_from_source {
+                    (String::new(), None, hash)
+                } else {
+                    let key = match package.key {
+                        Some(ref key) => Some(Secret(render(&key.0)?)),
+                        None => None,
+                    };
+                    (render(&package.source)?, key, hash)
+                }
+            };
+
+            if built_from_source {
+                from_source.insert(
+                    crate_name,
+                    SourcePackage {
+                        lib_name: package.lib_name,
+                        hash,
+                    },
+                );
+                continue;
+            }
+
+            let (data, index, sha256) = if package.from_index {
+                let index_error = |e| ManifestInspectionError::Index(crate_name.clone(), e);
+                if build.is_none() {
+                    build = Some(Build::from_env().map_err(&index_error)?);
+                }
+                let key = (package.base_dir.clone(), source);
+                if !indexes.contains_key(&key) {
+                    let index = match vendor_dir.and_then(|d| vendor_path(d, &key.1)) {
+                        Some(path) => Index::load(&path.to_string_lossy(), Path::new("")),
+                        None => Index::load(&key.1, &key.0),
+                    }.map_err(&index_error)?;
+                    indexes.insert(key.clone(), index);
+                }
+                let index = &indexes[&key];
+
+                let entry = index
+                    .resolve(
+                        &crate_name,
+                        package.version.as_ref().map(AsRef::as_ref),
+                        package.package_version.as_ref().map(AsRef::as_ref),
+                        build.as_ref().unwrap(),
+                    )
+                    .map_err(&index_error)?;
+                match entry {
+                    Some(entry) => {
+                        let (dir, archive) = index.source(entry).map_err(&index_error)?;
+                        let data = Self::inspect_package_data(
+                            vendor_dir,
+                            &dir,
+                            crate_name.as_ref(),
+                            &package,
+                            archive.as_ref(),
+                        )?;
+                        (data, Some(key.1), entry.sha256.clone())
+                    }
+                    None => {
+                        let data = PackageData::Missing(MissingData {
+                            source: index.location().to_owned(),
+                            available: index.available(&crate_name),
+                        });
+                        (data, Some(key.1), None)
+                    }
+                }
+            } else {
+                let data = Self::inspect_package_data(
+                    vendor_dir,
+                    package.base_dir.as_ref(),
                     crate_name.as_ref(),
                     &package,
-                ))?
+                    source.as_ref(),
+                )?;
+                (data, None, None)
             };
 
-            let data = Self::inspect_package_data_helper(
-                self.manifest_dir.as_ref(),
-                crate_name.as_ref(),
-                &package,
-                source.as_ref(),
-            )?;
-
             packages.insert(
                 crate_name,
                 Package {
                     version: package.version,
+                    package_version: package.package_version,
+                    lib_name: package.lib_name,
                     data,
+                    hash,
+                    link_libs: package.link_libs,
+                    on_missing: package.on_missing,
+                    index,
+                    sha256,
+                    key,
                 },
             );
         }
-        Ok(Packages { packages })
+        Ok(Packages {
+            packages,
+            from_source,
+            duplicates: self.duplicates,
+        })
     }
 
-    #[cfg(not(feature = "handlebars"))]
-    pub fn inspect(self) -> Result<Packages, ParseError> {
-        let mut packages = HashMap::with_capacity(self.packages.len());
-        for (crate_name, package) in self.packages.into_iter() {
-            let data = Self::inspect_package_data_helper(
-                self.manifest_dir.as_ref(),
-                crate_name.as_ref(),
-                &package,
-                package.source.as_ref(),
-            )?;
-
-            packages.insert(
-                crate_name,
-                Package {
-                    version: package.version,
-                    data,
-                },
-            );
+    /// The data of a source, or of its vendored copy.
+    fn inspect_package_data(
+        vendor_dir: Option<&Path>,
+        base_dir: &Path,
+        crate_name: &str,
+        package: &UninspectedPackage,
+        source: &str,
+    ) -> Result<PackageData, ManifestInspectionError> {
+        match vendor_dir.and_then(|d| vendor_path(d, source)) {
+            Some(source) => Ok(PackageData::File(FileData { source })),
+            None => Self::inspect_package_data_helper(base_dir, crate_name, package, source),
         }
-        Ok(Packages { packages })
     }
 
     #[cfg(feature = "reqwest")]
     fn inspect_package_data_helper(
-        manifest_dir: &Path,
+        base_dir: &Path,
         crate_name: &str,
         package: &UninspectedPackage,
         source: &str,
     ) -> Result<PackageData, ManifestInspectionError> {
-        // Start at the manifest dir and join. Absolute paths will just replace it.
+        // Start at the base dir and join. Absolute paths will just replace it.
         Ok(if source.starts_with("file://") {
             PackageData::File(FileData {
-                source: manifest_dir.join(Path::new(source.split_at("file://".len()).1)),
+                source: base_dir.join(Path::new(source.split_at("file://".len()).1)),
             })
         } else if source.contains("://") {
             PackageData::Url(UrlData {
-                source: Url::parse(source).context((crate_name, package))?,
+                source: Url::parse(source).map_err(|e| {
+                    ManifestInspectionError::Url(crate_name.to_owned(), package.clone(), e)
+                })?,
             })
         } else {
-            PackageData::File(FileData { source: manifest_dir.join(Path::new(source)) })
+            PackageData::File(FileData { source: base_dir.join(Path::new(source)) })
         })
     }
 
     #[cfg(not(feature = "reqwest"))]
     fn inspect_package_data_helper(
-        manifest_dir: &Path,
+        base_dir: &Path,
         _crate_name: &str,
-        package: &UninspectedPackage,
+        _package: &UninspectedPackage,
         source: &str,
     ) -> Result<PackageData, ManifestInspectionError> {
-        // Start at the manifest dir and join. Absolute paths will just replace it.
+        // Start at the base dir and join. Absolute paths will just replace it.
         Ok(PackageData::File(FileData {
-            source: manifest_dir.join(Path::new(if source.starts_with("file://") {
+            source: base_dir.join(Path::new(if source.starts_with("file://") {
                 source.split_at("file://".len()).1
             } else {
                 source
@@ -434,12 +1013,25 @@ impl Manifest {
 
 #[cfg(test)]
 mod test {
-    use std::path::PathBuf;
+    use std::fs::{create_dir_all, File};
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
 
+    use tempdir::TempDir;
     use toml;
 
+    use depot::MissingPolicy;
+    use env::Env;
+    use index::Build;
+    use recipients::DuplicatePolicy;
     use super::{Toml, Manifest};
 
+    /// Only `vars`, so neither the user's config nor their environment can
+    /// leak into a test.
+    fn env(vars: &[(&str, &str)]) -> Env {
+        Env::with_vars(vars.iter().cloned())
+    }
+
     const MANIFEST_1: &'static str = r#"
 [package]
 name = "test"
@@ -459,6 +1051,9 @@ priv = { path = "priv" }
 priv = "file://lib/libpriv.tar.gz"
 priv2 = "./lib/libpriv2.tar.gz"
 priv3 = "http://example.com/libpriv.tar.gz"
+priv4 = { source = "./lib/libpriv4.tar.gz", link-libs = ["static=priv4_native"] }
+priv5 = { source = "./lib/libpriv5.tar.gz", hash = "d15ea5e" }
+priv6 = { source = "./lib/libpriv6.tar.gz", on-missing = "fallback-source" }
 "#;
 
     #[test]
@@ -468,7 +1063,336 @@ priv3 = "http://example.com/libpriv.tar.gz"
 
     #[test]
     fn simple_manifest() {
-        Manifest::produce_from_string(PathBuf::new(), MANIFEST_1.into()).unwrap();
+        let m = Manifest::produce_from_string(PathBuf::new(), MANIFEST_1.into(), env(&[]))
+            .unwrap();
+        assert!(m.packages["priv"].link_libs.is_empty());
+        assert_eq!(m.packages["priv4"].source, "./lib/libpriv4.tar.gz");
+        assert_eq!(m.packages["priv4"].link_libs, vec!["static=priv4_native"]);
+        assert_eq!(m.packages["priv5"].hash, Some("d15ea5e".to_owned()));
+        assert_eq!(m.packages["priv5"].lib_name, "priv5");
+        assert_eq!(m.packages["priv5"].on_missing, MissingPolicy::Error);
+        assert_eq!(m.packages["priv6"].on_missing, MissingPolicy::FallbackSource);
+        assert_eq!(m.packages["priv"].version, None);
+        assert_eq!(m.duplicates, DuplicatePolicy::Error);
     }
 
+
+    const WORKSPACE: &'static str = r#"
+[workspace]
+members = ["member", "nested/*", "excluded"]
+exclude = ["excluded"]
+
+[workspace.metadata.dhl]
+duplicates = "newest"
+
+[workspace.metadata.dhl.packages]
+priv = "./libs/libpriv.tar.gz"
+priv2 = "./libs/libpriv2.tar.gz"
+"#;
+
+    const MEMBER: &'static str = r#"
+[package]
+name = "member"
+version = "1.0.0"
+authors = [""]
+
+[dependencies]
+priv = { version = "^1.2", path = "../priv" }
+
+[package.metadata.dhl.packages]
+priv2 = "./libs/libpriv2.tar.gz"
+"#;
+
+    const BARE_MEMBER: &'static str = r#"
+[package]
+name = "member"
+version = "1.0.0"
+authors = [""]
+
+[dependencies]
+"#;
+
+    fn write_file(path: &Path, contents: &str) {
+        create_dir_all(path.parent().unwrap()).unwrap();
+        File::create(path).unwrap().write_all(contents.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn workspace_manifest() {
+        let root_dir = TempDir::new("workspace").unwrap();
+        let member_dir = root_dir.path().join("member");
+        let nested_dir = root_dir.path().join("nested").join("member");
+        let excluded_dir = root_dir.path().join("excluded");
+        let stray_dir = root_dir.path().join("stray");
+        write_file(&root_dir.path().join("Cargo.toml"), WORKSPACE);
+        write_file(&member_dir.join("Cargo.toml"), MEMBER);
+        write_file(&nested_dir.join("Cargo.toml"), BARE_MEMBER);
+        write_file(&excluded_dir.join("Cargo.toml"), BARE_MEMBER);
+        write_file(&stray_dir.join("Cargo.toml"), BARE_MEMBER);
+        let produce = |dir: &PathBuf| {
+            Manifest::produce_from_file(dir.clone(), dir.join("Cargo.toml"), env(&[])).unwrap()
+        };
+
+        let m = produce(&member_dir);
+        assert_eq!(m.duplicates, DuplicatePolicy::Newest);
+        assert_eq!(m.packages["priv"].base_dir, root_dir.path());
+        assert_eq!(m.packages["priv"].version, Some("^1.2".to_owned()));
+        assert_eq!(m.packages["priv2"].base_dir, member_dir);
+
+        let m = produce(&nested_dir);
+        assert_eq!(m.packages.len(), 2);
+        assert_eq!(m.packages["priv2"].base_dir, root_dir.path());
+
+        let m = produce(&excluded_dir);
+        assert!(m.packages.is_empty());
+        assert_eq!(m.duplicates, DuplicatePolicy::Error);
+
+        // Not a member either
+        let m = produce(&stray_dir);
+        assert!(m.packages.is_empty());
+
+        root_dir.close().unwrap();
+    }
+
+    #[test]
+    fn empty_manifest() {
+        let m = Manifest::produce_from_string(
+            PathBuf::new(),
+            "[package]\nname = \"test\"\n".into(),
+            env(&[]),
+        ).unwrap();
+        assert!(m.packages.is_empty());
+    }
+
+    #[test]
+    fn config_file_manifest() {
+        let package_dir = TempDir::new("package").unwrap();
+        let user_config = package_dir.path().join("user").join("config.toml");
+        write_file(&user_config, "strict = false\n");
+        write_file(&package_dir.path().join("Cargo.toml"), MEMBER);
+        write_file(
+            &package_dir.path().join("dhl.toml"),
+            r#"
+duplicates = "newest"
+
+[packages]
+priv = "./dhl-libs/libpriv.tar.gz"
+"#,
+        );
+
+        let m = Manifest::produce_from_file(
+            package_dir.path().to_path_buf(),
+            package_dir.path().join("Cargo.toml"),
+            env(&[("DHL_CONFIG", user_config.to_str().unwrap())]),
+        ).unwrap();
+        assert_eq!(m.duplicates, DuplicatePolicy::Newest);
+        assert!(!m.strict);
+        assert_eq!(m.packages["priv"].source, "./dhl-libs/libpriv.tar.gz");
+        assert_eq!(m.packages["priv"].version, Some("^1.2".to_owned()));
+        assert_eq!(m.packages["priv2"].source, "./libs/libpriv2.tar.gz");
+
+        package_dir.close().unwrap();
+    }
+
+    #[test]
+    fn env_substitutions() {
+        use super::Substitution;
+
+        let m = Manifest::produce_from_string(
+            PathBuf::new(),
+            r#"
+[package.metadata.dhl.substitutions]
+plain = "value"
+single = { value = "A", env = true }
+chain = { value = ["A", "B"], env = true, default = "c" }
+optional = { value = "A", env = true, required = false }
+"#.into(),
+            env(&[]),
+        ).unwrap();
+        match m.substitutions["chain"] {
+            Substitution::EnvironmentVariable {
+                ref keys,
+                ref default,
+                required: true,
+            } => {
+                assert_eq!(*keys, vec!["A", "B"]);
+                assert_eq!(*default, Some("c".to_owned()));
+            }
+            ref s => panic!("unexpected substitution {:?}", s),
+        }
+        match m.substitutions["optional"] {
+            Substitution::EnvironmentVariable { required: false, .. } => {}
+            ref s => panic!("unexpected substitution {:?}", s),
+        }
+
+        let invalid = [
+            r#"x = { value = ["A"] }"#,
+            r#"x = { value = "A", default = "b" }"#,
+            r#"x = { value = [], env = true }"#,
+        ];
+        for substitution in invalid.iter() {
+            let contents = format!("[package.metadata.dhl.substitutions]\n{}\n", substitution);
+            assert!(Manifest::produce_from_string(PathBuf::new(), contents, env(&[])).is_err());
+        }
+    }
+
+    #[test]
+    fn package_substitutions() {
+        use super::{PackageData, Secret};
+
+        let m = Manifest::produce_from_string(
+            PathBuf::from("base"),
+            r#"
+[package.metadata.dhl.substitutions]
+vendor = "acme"
+
+[package.metadata.dhl.packages]
+shared = "./libs/{{vendor}}.tar.gz"
+own = { source = "./libs/{{arch}}.tar.gz", substitutions = { vendor = "other", arch = "{{vendor}}64" } }
+dhl-test-overridden = { source = "./libs/overridden.tar.gz", key = "dhl-symmetric-0" }
+"#.into(),
+            env(
+                &[
+                    ("DHL_SOURCE_DHL_TEST_OVERRIDDEN", "/override/{{vendor}}.tar.gz"),
+                    ("DHL_KEY_DHL_TEST_OVERRIDDEN", "dhl-symmetric-{{vendor}}"),
+                ],
+            ),
+        ).unwrap();
+
+        let packages = m.inspect().unwrap().packages;
+        let source = |name: &str| match packages[name].data {
+            PackageData::File(ref f) => f.source.clone(),
+            #[allow(unreachable_patterns)]
+            _ => panic!("expected a file"),
+        };
+        assert_eq!(source("shared"), Path::new("base/./libs/acme.tar.gz"));
+        assert_eq!(source("own"), Path::new("base/./libs/other64.tar.gz"));
+        assert_eq!(source("dhl-test-overridden"), Path::new("/override/acme.tar.gz"));
+        assert_eq!(packages["shared"].key, None);
+        assert_eq!(
+            packages["dhl-test-overridden"].key,
+            Some(Secret("dhl-symmetric-acme".to_owned()))
+        );
+    }
+
+    #[test]
+    fn from_source() {
+        let contents = r#"
+[package.metadata.dhl]
+from-source = ["built"]
+
+[package.metadata.dhl.substitutions]
+built_hash = "d15ea5e"
+
+[package.metadata.dhl.packages]
+built = { source = "./libs/{{undefined}}.tar.gz", hash = "{{built_hash}}" }
+hijacked = "./libs/hijacked.tar.gz"
+"#;
+        let m = Manifest::produce_from_string(PathBuf::new(), contents.into(), env(&[])).unwrap();
+        assert_eq!(m.from_source, vec!["built"]);
+        let packages = m.inspect().unwrap();
+        assert_eq!(packages.from_source["built"].lib_name, "built");
+        assert_eq!(packages.from_source["built"].hash, Some("d15ea5e".to_owned()));
+        assert!(!packages.packages.contains_key("built"));
+        assert!(packages.packages.contains_key("hijacked"));
+    }
+
+    #[test]
+    fn index() {
+        use super::PackageData;
+
+        let dir = TempDir::new("dhl-test-manifest").unwrap();
+        write_file(
+            &dir.path().join("index/index.toml"),
+            r#"
+[[package]]
+name = "priv"
+version = "1.0.0"
+target = "dhl-test-target"
+rustc = "1.21.0"
+source = "priv-1.0.0.tar.gz"
+sha256 = "d15ea5e"
+
+[[package]]
+name = "other"
+version = "1.0.0"
+target = "elsewhere"
+rustc = "1.21.0"
+source = "other-1.0.0.tar.gz"
+"#,
+        );
+        let contents = r#"
+[package.metadata.dhl.packages]
+priv = { index = "./index/index.toml" }
+other = { index = "./index/index.toml" }
+"#;
+        let m = Manifest::produce_from_string(dir.path().into(), contents.into(), env(&[]))
+            .unwrap();
+        assert!(m.packages["priv"].from_index);
+
+        let template = m.template_engine().unwrap();
+        let build = Build {
+            target: "dhl-test-target".to_owned(),
+            profile: "debug".to_owned(),
+            rustc: "1.21.0".to_owned(),
+        };
+        let packages = m.inspect_for(&template, build).unwrap().packages;
+
+        match packages["priv"].data {
+            PackageData::File(ref f) => {
+                assert_eq!(f.source, dir.path().join("index/priv-1.0.0.tar.gz"))
+            }
+            ref data => panic!("expected a file, got {:?}", data),
+        }
+        assert_eq!(packages["priv"].sha256, Some("d15ea5e".to_owned()));
+        match packages["other"].data {
+            PackageData::Missing(ref missing) => assert_eq!(missing.available.len(), 1),
+            ref data => panic!("expected a missing build, got {:?}", data),
+        }
+
+        let contents = r#"
+[package.metadata.dhl.packages]
+priv = { source = "./priv.tar.gz", index = "./index/index.toml" }
+"#;
+        assert!(
+            Manifest::produce_from_string(dir.path().into(), contents.into(), env(&[])).is_err()
+        );
+    }
+
+    #[test]
+    fn vendor_dir() {
+        use super::{PackageData, vendor_path};
+
+        let base = Path::new("/base");
+        assert_eq!(
+            vendor_path(base, "http://host:80/a/./b.tar.gz?x=1#y"),
+            Some(base.join("host_80/a/b.tar.gz"))
+        );
+        assert_eq!(vendor_path(base, "https://host/../b.tar.gz"), None);
+        assert_eq!(vendor_path(base, "./libs/b.tar.gz"), None);
+
+        let dir = TempDir::new("dhl-test-manifest").unwrap();
+        let contents = r#"
+[package.metadata.dhl]
+vendor-dir = "v"
+
+[package.metadata.dhl.packages]
+remote = "http://host:80/a/b.tar.gz"
+local = "./libs/c.tar.gz"
+"#;
+        let m = Manifest::produce_from_string(dir.path().into(), contents.into(), env(&[]))
+            .unwrap();
+        assert_eq!(m.vendor_dir, Some(dir.path().join("v")));
+        let packages = m.inspect().unwrap().packages;
+        match packages["remote"].data {
+            PackageData::File(ref f) => {
+                assert_eq!(f.source, dir.path().join("v/host_80/a/b.tar.gz"))
+            }
+            ref data => panic!("expected the vendored file, got {:?}", data),
+        }
+        match packages["local"].data {
+            PackageData::File(ref f) => assert_eq!(f.source, dir.path().join("libs/c.tar.gz")),
+            ref data => panic!("expected a file, got {:?}", data),
+        }
+    }
 }
@@ -0,0 +1,66 @@
+/// The name of the archive entry holding the `ArchiveMetadata`.
+pub const METADATA_NAME: &'static str = "export.toml";
+
+/// The directory within an archive holding native libraries.
+pub const NATIVE_DIR: &'static str = "native";
+
+/// What every encrypted archive starts with.
+pub const ENCRYPTED_MAGIC: &'static [u8] = b"dhl-encrypted/v1\n";
+
+/// Whether an archive is encrypted, which is known even without the
+/// `encryption` feature.
+pub fn is_encrypted(contents: &[u8]) -> bool {
+    contents.starts_with(ENCRYPTED_MAGIC)
+}
+
+/// Metadata an exported archive carries about itself, read from its
+/// `export.toml` entry.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ArchiveMetadata {
+    /// The name of the exported package.
+    pub name: Option<String>,
+    /// The version of the exported package, checked against the dependency's
+    /// version requirement and the dummy crate.
+    pub version: Option<String>,
+    /// The target triple the archive was built for, listed in the index by
+    /// `dhl publish`.
+    pub target: Option<String>,
+    /// The rustc version the archive was built with, e.g. `1.21.0`.
+    pub rustc: Option<String>,
+    /// The profile the archive was built with, if it's only for one.
+    pub profile: Option<String>,
+    /// Native libraries to link, in the `[KIND=]NAME` form of
+    /// `cargo:rustc-link-lib`.
+    #[serde(default, rename = "link-libs")]
+    pub link_libs: Vec<String>,
+}
+
+
+#[cfg(test)]
+mod test {
+    use toml;
+
+    use super::ArchiveMetadata;
+
+    #[test]
+    fn parse_metadata() {
+        let m = toml::from_str::<ArchiveMetadata>("").unwrap();
+        assert!(m.link_libs.is_empty());
+
+        let m = toml::from_str::<ArchiveMetadata>(
+            r#"
+name = "priv"
+version = "1.0.0"
+target = "x86_64-unknown-linux-gnu"
+rustc = "1.21.0"
+link-libs = ["static=foo", "bar"]
+"#,
+        ).unwrap();
+        assert_eq!(m.name, Some("priv".to_owned()));
+        assert_eq!(m.target, Some("x86_64-unknown-linux-gnu".to_owned()));
+        assert_eq!(m.rustc, Some("1.21.0".to_owned()));
+        assert_eq!(m.profile, None);
+        assert_eq!(m.version, Some("1.0.0".to_owned()));
+        assert_eq!(m.link_libs, vec!["static=foo", "bar"]);
+    }
+}
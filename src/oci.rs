@@ -0,0 +1,504 @@
+use std::collections::HashMap;
+use std::env::temp_dir;
+use std::fs::{File, OpenOptions, remove_file};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use reqwest::{self, Client as HttpClient, Method, Request, Response, StatusCode, Url, UrlError};
+use reqwest::header::{Authorization, Bearer};
+use serde_json;
+
+use env::Env;
+use lock::{self, Hashing};
+
+/// The media type of a layer holding an exported archive.
+pub const OCI_LAYER_MEDIA_TYPE: &'static str = "application/vnd.dhl.archive.v1.tar+gzip";
+
+/// The layer annotation naming the target an archive was built for.
+pub const OCI_TARGET_ANNOTATION: &'static str = "dhl.target";
+
+/// The media types of layers that can hold an archive.
+const ARCHIVE_MEDIA_TYPES: [&'static str; 2] = [
+    OCI_LAYER_MEDIA_TYPE,
+    "application/vnd.oci.image.layer.v1.tar+gzip",
+];
+
+const MANIFEST_MEDIA_TYPE: &'static str = "application/vnd.oci.image.manifest.v1+json";
+
+/// The largest image manifest or token response read.
+const MAX_DOCUMENT: u64 = 4 * 1024 * 1024;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum OciError {
+        InvalidReference(reference: String) {
+            description("invalid oci reference")
+            display("Invalid OCI reference '{}', expected oci://registry/repository:tag", reference)
+        }
+        Url(url: String, err: UrlError) {
+            description("oci url error")
+            display("Failed to parse url '{}': {}", url, err)
+            cause(err)
+        }
+        Http(url: String, err: reqwest::Error) {
+            description("oci request error")
+            display("Failed to request '{}': {}", url, err)
+            cause(err)
+        }
+        HttpRead(url: String, err: io::Error) {
+            description("oci read error")
+            display("Failed to download '{}': {}", url, err)
+            cause(err)
+        }
+        HttpStatus(url: String, status: StatusCode) {
+            description("oci status error")
+            display("Failed to download '{}': {}", url, status)
+        }
+        TooLarge(url: String) {
+            description("oci document too large")
+            display("'{}' is larger than {} bytes", url, MAX_DOCUMENT)
+        }
+        Authentication(url: String) {
+            description("oci authentication error")
+            display("'{}' asked for authentication other than a bearer token", url)
+        }
+        Token(url: String, err: serde_json::Error) {
+            description("oci token error")
+            display("Invalid token response from '{}': {}", url, err)
+            cause(err)
+        }
+        Manifest(url: String, err: serde_json::Error) {
+            description("oci manifest error")
+            display("Invalid OCI image manifest at '{}': {}", url, err)
+            cause(err)
+        }
+        Spool(path: PathBuf, err: io::Error) {
+            description("oci spool error")
+            display("Failed to spool a layer to '{}': {}", path.display(), err)
+            cause(err)
+        }
+        UnsupportedDigest(digest: String) {
+            description("unsupported oci digest")
+            display("Digest '{}' is not a sha256 digest", digest)
+        }
+        SizeMismatch(digest: String, size: u64) {
+            description("oci layer size mismatch")
+            display("Layer {} is not the {} bytes its descriptor lists", digest, size)
+        }
+        DigestMismatch(digest: String, actual: String) {
+            description("oci digest mismatch")
+            display("{} was downloaded with sha256 {}", digest, actual)
+        }
+    }
+}
+
+/// `oci://registry/repository:tag`, or `@sha256:...` for a digest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    pub registry: String,
+    pub repository: String,
+    pub reference: String,
+}
+
+impl Reference {
+    pub fn parse(url: &Url) -> Result<Self, OciError> {
+        let invalid = || OciError::InvalidReference(url.to_string());
+        let registry = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_owned(),
+            (None, _) => return Err(invalid()),
+        };
+        let path = url.path().trim_start_matches('/');
+        let split = path.rfind('@').or_else(|| {
+            let name_start = path.rfind('/').map_or(0, |i| i + 1);
+            path[name_start..].rfind(':').map(|i| name_start + i)
+        });
+        let (repository, reference) = match split {
+            Some(i) => (&path[..i], &path[i + 1..]),
+            None => (path, "latest"),
+        };
+        if repository.is_empty() || reference.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Reference {
+            registry,
+            repository: repository.to_owned(),
+            reference: reference.to_owned(),
+        })
+    }
+
+    /// Registries on the local machine are spoken to over plain http, like
+    /// docker does, everything else over https.
+    fn base(&self) -> String {
+        let local = ["localhost", "127.0.0.1", "[::1]"].iter().any(|host| {
+            self.registry == *host || self.registry.starts_with(&format!("{}:", host))
+        });
+        let scheme = if local { "http" } else { "https" };
+        format!("{}://{}/v2/{}", scheme, self.registry, self.repository)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OciManifest {
+    layers: Vec<OciDescriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OciDescriptor {
+    #[serde(rename = "mediaType")]
+    media_type: String,
+    digest: String,
+    #[serde(default)]
+    size: Option<u64>,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+}
+
+/// The answer of a token realm, which names the token either way.
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+/// What a source had.
+#[derive(Debug)]
+pub enum Fetched {
+    /// The archive, for `oci://` the verified contents of the selected layer.
+    Archive(Vec<u8>),
+    /// Nothing at the source, or no layer for the target, with the targets
+    /// the manifest does have.
+    Missing(Vec<String>),
+}
+
+/// The archive layer annotated with `target`, or else the one archive layer
+/// that isn't annotated with any target. Layers of other media types are
+/// never picked.
+fn select<'a>(layers: &'a [OciDescriptor], target: Option<&str>) -> Option<&'a OciDescriptor> {
+    let archives = || {
+        layers.iter().filter(|layer| {
+            ARCHIVE_MEDIA_TYPES.contains(&layer.media_type.as_str())
+        })
+    };
+    let annotated = archives().find(|layer| {
+        let annotation = layer.annotations.get(OCI_TARGET_ANNOTATION);
+        target.is_some() && annotation.map(String::as_str) == target
+    });
+    annotated.or_else(|| {
+        archives().find(|layer| !layer.annotations.contains_key(OCI_TARGET_ANNOTATION))
+    })
+}
+
+/// Fetches the archive layer for `target` from the manifest at `url`.
+/// `DHL_TOKEN_<HOST>` of the registry is sent as a bearer token.
+pub fn fetch(
+    client: &HttpClient,
+    env: &Env,
+    url: &Url,
+    target: Option<&str>,
+) -> Result<Fetched, OciError> {
+    let reference = Reference::parse(url)?;
+    let base = reference.base();
+    let mut session = Session {
+        client,
+        env,
+        token: url.host_str().and_then(|host| env.token(host)),
+        challenged: false,
+    };
+
+    let manifest_url = format!("{}/manifests/{}", base, reference.reference);
+    let contents = match session.get(&manifest_url, Some(MANIFEST_MEDIA_TYPE))? {
+        Some(response) => read_document(&manifest_url, response)?,
+        None => return Ok(Fetched::Missing(Vec::new())),
+    };
+    // Tags can't contain `:`, so this is a digest the manifest must match
+    if reference.reference.contains(':') {
+        check_digest(&reference.reference, &lock::sha256(&contents))?;
+    }
+    let manifest = serde_json::from_slice::<OciManifest>(&contents).map_err(|e| {
+        OciError::Manifest(manifest_url.clone(), e)
+    })?;
+    let layer = match select(&manifest.layers, target) {
+        Some(layer) => layer,
+        None => {
+            let available = manifest
+                .layers
+                .iter()
+                .filter(|layer| ARCHIVE_MEDIA_TYPES.contains(&layer.media_type.as_str()))
+                .filter_map(|layer| layer.annotations.get(OCI_TARGET_ANNOTATION).cloned())
+                .collect();
+            return Ok(Fetched::Missing(available));
+        }
+    };
+
+    if !layer.digest.starts_with("sha256:") {
+        return Err(OciError::UnsupportedDigest(layer.digest.clone()));
+    }
+    let blob_url = format!("{}/blobs/{}", base, layer.digest);
+    let blob = match session.get(&blob_url, None)? {
+        Some(blob) => blob,
+        None => return Err(OciError::HttpStatus(blob_url, StatusCode::NotFound)),
+    };
+    spool(&blob_url, layer, blob).map(Fetched::Archive)
+}
+
+/// Checks content against a `sha256:` digest.
+fn check_digest(digest: &str, actual: &str) -> Result<(), OciError> {
+    if !digest.starts_with("sha256:") {
+        return Err(OciError::UnsupportedDigest(digest.to_owned()));
+    }
+    if actual != &digest["sha256:".len()..] {
+        return Err(OciError::DigestMismatch(digest.to_owned(), actual.to_owned()));
+    }
+    Ok(())
+}
+
+/// Reads a manifest or token response, which are small.
+fn read_document(url: &str, response: Response) -> Result<Vec<u8>, OciError> {
+    let mut contents = Vec::new();
+    response.take(MAX_DOCUMENT + 1).read_to_end(&mut contents).map_err(|e| {
+        OciError::HttpRead(url.to_owned(), e)
+    })?;
+    if contents.len() as u64 > MAX_DOCUMENT {
+        return Err(OciError::TooLarge(url.to_owned()));
+    }
+    Ok(contents)
+}
+
+/// Downloads a layer into a temporary file, and only reads it back once its
+/// size and digest check out.
+fn spool<R: Read>(url: &str, layer: &OciDescriptor, blob: R) -> Result<Vec<u8>, OciError> {
+    let dir = temp_dir();
+    let mut n = 0;
+    let (file, path) = loop {
+        let path = dir.join(format!("dhl-layer-{}.tmp", n));
+        match OpenOptions::new().read(true).write(true).create_new(true).open(&path) {
+            Ok(file) => break (file, path),
+            Err(ref e) if e.kind() == ErrorKind::AlreadyExists => n += 1,
+            Err(e) => return Err(OciError::Spool(path, e)),
+        }
+    };
+    let result = read_verified(url, layer, blob, file, &path);
+    let _ = remove_file(&path);
+    result
+}
+
+fn read_verified<R: Read>(
+    url: &str,
+    layer: &OciDescriptor,
+    blob: R,
+    file: File,
+    path: &Path,
+) -> Result<Vec<u8>, OciError> {
+    // Reading one byte past the listed size is enough to know it's wrong
+    let limit = layer.size.map_or(u64::max_value(), |size| size.saturating_add(1));
+    let mut hashing = Hashing::new(file);
+    let copied = io::copy(&mut blob.take(limit), &mut hashing).map_err(|e| {
+        OciError::HttpRead(url.to_owned(), e)
+    })?;
+    if let Some(size) = layer.size {
+        if copied != size {
+            return Err(OciError::SizeMismatch(layer.digest.clone(), size));
+        }
+    }
+    let (mut file, actual) = hashing.finish();
+    check_digest(&layer.digest, &actual)?;
+
+    let mut contents = Vec::new();
+    file.seek(SeekFrom::Start(0))
+        .and_then(|_| file.read_to_end(&mut contents))
+        .map_err(|e| OciError::Spool(path.to_owned(), e))?;
+    Ok(contents)
+}
+
+/// Requests to a registry, with the token it asked for once it has.
+struct Session<'a> {
+    client: &'a HttpClient,
+    env: &'a Env,
+    token: Option<String>,
+    /// Whether a bearer challenge was answered already, so a token that's
+    /// still refused isn't asked for again.
+    challenged: bool,
+}
+
+impl<'a> Session<'a> {
+    /// Requests `url`, or `None` if it doesn't exist.
+    fn get(
+        &mut self,
+        url: &str,
+        accept: Option<&'static str>,
+    ) -> Result<Option<Response>, OciError> {
+        let parsed = Url::parse(url).map_err(|e| OciError::Url(url.to_owned(), e))?;
+        loop {
+            let mut request = Request::new(Method::Get, parsed.clone());
+            if let Some(ref token) = self.token {
+                request.headers_mut().set(Authorization(Bearer { token: token.clone() }));
+            }
+            if let Some(accept) = accept {
+                request.headers_mut().set_raw("Accept", accept);
+            }
+            let response = self.client.execute(request).map_err(
+                |e| OciError::Http(url.to_owned(), e),
+            )?;
+            match response.status() {
+                StatusCode::Unauthorized if !self.challenged => {
+                    self.challenged = true;
+                    let challenge = response
+                        .headers()
+                        .get_raw("WWW-Authenticate")
+                        .and_then(|raw| raw.one())
+                        .map(|value| String::from_utf8_lossy(value).into_owned());
+                    self.token = Some(self.authenticate(url, challenge)?);
+                }
+                StatusCode::NotFound => return Ok(None),
+                status if status.is_success() => return Ok(Some(response)),
+                status => return Err(OciError::HttpStatus(url.to_owned(), status)),
+            }
+        }
+    }
+
+    /// Gets a token from the realm of a bearer challenge, like docker does.
+    /// The realm is sent its own `DHL_TOKEN_<HOST>`, if there is one.
+    fn authenticate(&self, url: &str, challenge: Option<String>) -> Result<String, OciError> {
+        let unsupported = || OciError::Authentication(url.to_owned());
+        let params = challenge.as_ref().and_then(|c| bearer_challenge(c)).ok_or_else(
+            &unsupported,
+        )?;
+        let realm = params.get("realm").ok_or_else(&unsupported)?;
+        let mut realm_url = Url::parse(realm).map_err(|e| OciError::Url(realm.clone(), e))?;
+        for key in ["service", "scope"].iter() {
+            if let Some(value) = params.get(*key) {
+                realm_url.query_pairs_mut().append_pair(key, value);
+            }
+        }
+
+        let realm = realm_url.to_string();
+        let token = realm_url.host_str().and_then(|host| self.env.token(host));
+        let mut request = Request::new(Method::Get, realm_url);
+        if let Some(token) = token {
+            request.headers_mut().set(Authorization(Bearer { token }));
+        }
+        let response = self.client.execute(request).map_err(
+            |e| OciError::Http(realm.clone(), e),
+        )?;
+        if !response.status().is_success() {
+            return Err(OciError::HttpStatus(realm, response.status()));
+        }
+        let contents = read_document(&realm, response)?;
+        let token = serde_json::from_slice::<TokenResponse>(&contents).map_err(|e| {
+            OciError::Token(realm.clone(), e)
+        })?;
+        token.token.or(token.access_token).ok_or_else(&unsupported)
+    }
+}
+
+/// The parameters of a bearer `WWW-Authenticate` challenge, e.g.
+/// `Bearer realm="https://auth.example.com/token",service="example.com"`.
+fn bearer_challenge(challenge: &str) -> Option<HashMap<String, String>> {
+    let challenge = challenge.trim();
+    if !challenge.to_lowercase().starts_with("bearer ") {
+        return None;
+    }
+    let mut params = HashMap::new();
+    let mut rest = challenge["bearer ".len()..].trim_start();
+    while !rest.is_empty() {
+        let eq = rest.find('=')?;
+        let key = rest[..eq].trim().to_lowercase();
+        rest = rest[eq + 1..].trim_start();
+        let value = if rest.starts_with('"') {
+            let end = rest[1..].find('"')? + 1;
+            let value = &rest[1..end];
+            rest = &rest[end + 1..];
+            value
+        } else {
+            let end = rest.find(',').unwrap_or(rest.len());
+            let value = rest[..end].trim();
+            rest = &rest[end..];
+            value
+        };
+        params.insert(key, value.to_owned());
+        rest = rest.trim_start().trim_start_matches(',').trim_start();
+    }
+    Some(params)
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use reqwest::Url;
+
+    use super::{OCI_LAYER_MEDIA_TYPE, OCI_TARGET_ANNOTATION, OciDescriptor, Reference,
+                bearer_challenge, select};
+
+    #[test]
+    fn reference() {
+        let parse = |s: &str| Reference::parse(&Url::parse(s).unwrap());
+        assert_eq!(
+            parse("oci://localhost:5000/team/priv:1.0").unwrap(),
+            Reference {
+                registry: "localhost:5000".into(),
+                repository: "team/priv".into(),
+                reference: "1.0".into(),
+            }
+        );
+        assert_eq!(parse("oci://example.com/priv").unwrap().reference, "latest");
+        assert_eq!(
+            parse("oci://example.com/priv@sha256:abc").unwrap().reference,
+            "sha256:abc"
+        );
+        assert_eq!(
+            parse("oci://localhost:5000/priv:1").unwrap().base(),
+            "http://localhost:5000/v2/priv"
+        );
+        assert_eq!(
+            parse("oci://example.com/priv:1").unwrap().base(),
+            "https://example.com/v2/priv"
+        );
+        assert!(parse("oci://example.com/").is_err());
+    }
+
+    #[test]
+    fn select_layer() {
+        let layer = |digest: &str, media_type: &str, target: Option<&str>| {
+            let mut annotations = HashMap::new();
+            if let Some(target) = target {
+                annotations.insert(OCI_TARGET_ANNOTATION.to_owned(), target.to_owned());
+            }
+            OciDescriptor {
+                media_type: media_type.into(),
+                digest: digest.into(),
+                size: None,
+                annotations,
+            }
+        };
+        let layers = vec![
+            layer("a", "application/octet-stream", None),
+            layer("b", OCI_LAYER_MEDIA_TYPE, Some("x86_64-pc-windows-msvc")),
+            layer("c", OCI_LAYER_MEDIA_TYPE, None),
+            layer("d", "application/octet-stream", Some("x86_64-unknown-linux-gnu")),
+            layer("e", "application/vnd.oci.image.layer.v1.tar+gzip", Some("aarch64-apple-darwin")),
+        ];
+        let digest = |target| select(&layers, target).map(|l| l.digest.as_str());
+        assert_eq!(digest(Some("x86_64-unknown-linux-gnu")), Some("c"));
+        assert_eq!(digest(Some("x86_64-pc-windows-msvc")), Some("b"));
+        assert_eq!(digest(Some("aarch64-apple-darwin")), Some("e"));
+        assert_eq!(digest(Some("i686-unknown-linux-gnu")), Some("c"));
+        assert_eq!(digest(None), Some("c"));
+        assert_eq!(select(&layers[..2], Some("aarch64-apple-darwin")).map(|l| &l.digest), None);
+    }
+
+    #[test]
+    fn challenge() {
+        let params = bearer_challenge(concat!(
+            r#"Bearer realm="https://auth.example.com/token","#,
+            r#"service="example.com",scope="repository:team/priv:pull""#
+        )).unwrap();
+        assert_eq!(params["realm"], "https://auth.example.com/token");
+        assert_eq!(params["service"], "example.com");
+        assert_eq!(params["scope"], "repository:team/priv:pull");
+        assert_eq!(bearer_challenge("bearer realm=x, service = y").unwrap()["service"], "y");
+        assert!(bearer_challenge(r#"Basic realm="registry""#).is_none());
+    }
+}
@@ -0,0 +1,576 @@
+use std::fs::{File, OpenOptions, create_dir_all, remove_file, rename};
+use std::io::{self, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "reqwest")]
+use reqwest::{self, Body, Client as HttpClient, Method, Request, StatusCode, Url, UrlError};
+#[cfg(feature = "reqwest")]
+use reqwest::header::{Authorization, Bearer, ETag, IfMatch, IfNoneMatch};
+use semver::Version;
+
+use depot::{ArchiveError, Depot};
+use index::{Index, IndexEntry, IndexError};
+use lock::sha256;
+use metadata::{ArchiveMetadata, is_encrypted};
+use version::VersionError;
+
+/// The name of the index in a registry.
+pub const INDEX_NAME: &'static str = "index.toml";
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum PublishError {
+        Read(archive: PathBuf, err: io::Error) {
+            description("archive read error")
+            display("Failed to read '{}': {}", archive.display(), err)
+            cause(err)
+        }
+        Archive(err: ArchiveError) {
+            from()
+            description("archive error")
+            display("Failed to read the metadata of the archive: {}", err)
+            cause(err)
+        }
+        MissingField(archive: PathBuf, field: &'static str) {
+            description("archive metadata is incomplete")
+            display("'{}' has no {} in its export.toml, pass --{}",
+                archive.display(), field, field)
+        }
+        InvalidField(archive: PathBuf, field: &'static str, value: String) {
+            description("archive metadata can't name a file")
+            display("'{}' has {} '{}', which can't be part of a file name",
+                archive.display(), field, value)
+        }
+        Version(err: VersionError) {
+            from()
+            description("archive version error")
+            display("Invalid archive version: {}", err)
+            cause(err)
+        }
+        Duplicate(entry: IndexEntry) {
+            description("archive is already published")
+            display("{} {} for {} with rustc {} is already published, use --force to replace it",
+                entry.name, entry.version, entry.target, entry.rustc)
+        }
+        Index(err: IndexError) {
+            from()
+            description("registry index error")
+            display("Registry index error: {}", err)
+            cause(err)
+        }
+        Locked(lock: PathBuf) {
+            description("registry is locked")
+            display("'{}' exists, another publish is running or was interrupted, \
+                remove it if not", lock.display())
+        }
+        Write(path: PathBuf, err: io::Error) {
+            description("registry write error")
+            display("Failed to write '{}': {}", path.display(), err)
+            cause(err)
+        }
+        #[cfg(feature = "reqwest")]
+        Url(location: String, err: UrlError) {
+            description("registry url error")
+            display("Failed to parse registry url '{}': {}", location, err)
+            cause(err)
+        }
+        #[cfg(feature = "reqwest")]
+        Http(location: String, err: reqwest::Error) {
+            description("registry http error")
+            display("Request to '{}' failed: {}", location, err)
+            cause(err)
+        }
+        #[cfg(feature = "reqwest")]
+        HttpRead(location: String, err: io::Error) {
+            description("registry http error")
+            display("Failed to download '{}': {}", location, err)
+            cause(err)
+        }
+        #[cfg(feature = "reqwest")]
+        HttpStatus(location: String, status: StatusCode) {
+            description("registry http error")
+            display("Request to '{}' failed: {}", location, status)
+        }
+        #[cfg(feature = "reqwest")]
+        Conflict(location: String) {
+            description("registry index changed")
+            display("'{}' changed during the publish, try again", location)
+        }
+        #[cfg(not(feature = "reqwest"))]
+        Unsupported(location: String) {
+            description("registry url unsupported")
+            display("Publishing to '{}' needs dhl's reqwest feature", location)
+        }
+    }
+}
+
+/// Where `dhl publish` puts archives, a directory or, with the `reqwest`
+/// feature, an HTTP endpoint that accepts `PUT`. Either way the archives sit
+/// next to an `index.toml` listing them.
+#[derive(Debug, Clone)]
+pub struct Registry {
+    location: String,
+    #[cfg_attr(not(feature = "reqwest"), allow(dead_code))]
+    token: Option<String>,
+}
+
+/// Removes the lock of a directory registry when dropped.
+struct DirLock(PathBuf);
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.0);
+    }
+}
+
+impl Registry {
+    /// A registry at a path or url. `token` is sent as a bearer token to
+    /// HTTP registries.
+    pub fn new<S: Into<String>>(location: S, token: Option<String>) -> Self {
+        Registry {
+            location: location.into(),
+            token,
+        }
+    }
+
+    /// Uploads an archive and adds it to the index. Fields missing from the
+    /// archive's `export.toml` are taken from `overrides`, which take
+    /// precedence when set. Publishing the same build twice is an error
+    /// unless `force` is set.
+    pub fn publish(
+        &self,
+        archive: &Path,
+        overrides: &ArchiveMetadata,
+        force: bool,
+    ) -> Result<IndexEntry, PublishError> {
+        let mut contents = Vec::new();
+        File::open(archive)
+            .and_then(|mut f| f.read_to_end(&mut contents))
+            .map_err(|e| PublishError::Read(archive.to_path_buf(), e))?;
+        // Encrypted archives can only be described by `overrides`
+        let metadata = if is_encrypted(&contents) {
+            ArchiveMetadata::default()
+        } else {
+            Depot::metadata(archive.display().to_string(), Cursor::new(&contents))?
+                .unwrap_or_default()
+        };
+        let entry = Self::entry(archive, overrides, metadata, &contents)?;
+        self.publish_helper(entry.clone(), contents, force)?;
+        Ok(entry)
+    }
+
+    /// The index entry of an archive.
+    fn entry(
+        archive: &Path,
+        overrides: &ArchiveMetadata,
+        metadata: ArchiveMetadata,
+        contents: &[u8],
+    ) -> Result<IndexEntry, PublishError> {
+        let field = |overridden: &Option<String>, value: Option<String>, field| {
+            overridden.clone().or(value).ok_or_else(|| {
+                PublishError::MissingField(archive.to_path_buf(), field)
+            })
+        };
+        let name = field(&overrides.name, metadata.name, "name")?;
+        let version = field(&overrides.version, metadata.version, "version")?;
+        let target = field(&overrides.target, metadata.target, "target")?;
+        let rustc = field(&overrides.rustc, metadata.rustc, "rustc")?;
+        let profile = overrides.profile.clone().or(metadata.profile);
+        // They name the archive in the registry
+        for &(field, value) in [
+            ("name", Some(&name)),
+            ("version", Some(&version)),
+            ("target", Some(&target)),
+            ("rustc", Some(&rustc)),
+            ("profile", profile.as_ref()),
+        ].iter()
+        {
+            if let Some(value) = value {
+                if value.is_empty() || value.contains(|c| c == '/' || c == '\\') ||
+                    value.contains("..")
+                {
+                    return Err(PublishError::InvalidField(
+                        archive.to_path_buf(),
+                        field,
+                        value.clone(),
+                    ));
+                }
+            }
+        }
+        Version::parse(&version).map_err(|e| {
+            VersionError::InvalidVersion(name.clone(), version.clone(), e)
+        })?;
+
+        let mut source = format!("{}-{}-{}-{}", name, version, target, rustc);
+        if let Some(ref profile) = profile {
+            source.push('-');
+            source.push_str(profile);
+        }
+        source.push_str(".tar.gz");
+        Ok(IndexEntry {
+            name,
+            version,
+            target,
+            rustc,
+            profile,
+            source,
+            sha256: Some(sha256(contents)),
+        })
+    }
+
+    /// Adds an entry to an index, unless it's a duplicate.
+    fn add(index: &mut Index, entry: IndexEntry, force: bool) -> Result<(), PublishError> {
+        if !force {
+            if let Some(old) = index.entries().iter().find(|e| e.is_same_build(&entry)) {
+                return Err(PublishError::Duplicate(old.clone()));
+            }
+        }
+        index.insert(entry);
+        Ok(())
+    }
+
+    #[cfg(feature = "reqwest")]
+    fn publish_helper(
+        &self,
+        entry: IndexEntry,
+        contents: Vec<u8>,
+        force: bool,
+    ) -> Result<(), PublishError> {
+        if self.location.starts_with("file://") {
+            let dir = Path::new(self.location.split_at("file://".len()).1);
+            Self::publish_dir(dir, entry, &contents, force)
+        } else if self.location.contains("://") {
+            self.publish_url(entry, contents, force)
+        } else {
+            Self::publish_dir(Path::new(&self.location), entry, &contents, force)
+        }
+    }
+
+    #[cfg(not(feature = "reqwest"))]
+    fn publish_helper(
+        &self,
+        entry: IndexEntry,
+        contents: Vec<u8>,
+        force: bool,
+    ) -> Result<(), PublishError> {
+        let dir = Path::new(if self.location.starts_with("file://") {
+            self.location.split_at("file://".len()).1
+        } else if self.location.contains("://") {
+            return Err(PublishError::Unsupported(self.location.clone()));
+        } else {
+            &self.location
+        });
+        Self::publish_dir(dir, entry, &contents, force)
+    }
+
+    /// Publishes into a directory. The index is locked while it's updated,
+    /// and files are written next to their destination and renamed over it,
+    /// so readers never see a partial archive or index.
+    fn publish_dir(
+        dir: &Path,
+        entry: IndexEntry,
+        contents: &[u8],
+        force: bool,
+    ) -> Result<(), PublishError> {
+        create_dir_all(dir).map_err(|e| PublishError::Write(dir.to_path_buf(), e))?;
+        let lock_path = dir.join(format!(".{}.lock", INDEX_NAME));
+        let _lock = match OpenOptions::new().write(true).create_new(true).open(&lock_path) {
+            Ok(_) => DirLock(lock_path),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                return Err(PublishError::Locked(lock_path))
+            }
+            Err(e) => return Err(PublishError::Write(lock_path, e)),
+        };
+
+        let index_path = dir.join(INDEX_NAME);
+        let location = index_path.to_string_lossy().into_owned();
+        let mut index = if index_path.is_file() {
+            Index::load(&location, Path::new(""))?
+        } else {
+            Index::empty(&location, dir.to_path_buf())
+        };
+        let archive_path = dir.join(&entry.source);
+        Self::add(&mut index, entry, force)?;
+
+        Self::write_atomic(&archive_path, contents)?;
+        Self::write_atomic(&index_path, index.serialize()?.as_bytes())
+    }
+
+    fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), PublishError> {
+        let file_name = path.file_name().map_or("".into(), |f| f.to_string_lossy());
+        let tmp = path.with_file_name(format!(".{}.tmp", file_name));
+        File::create(&tmp)
+            .and_then(|mut f| f.write_all(contents).and_then(|_| f.sync_all()))
+            .and_then(|_| rename(&tmp, path))
+            .map_err(|e| {
+                let _ = remove_file(&tmp);
+                PublishError::Write(path.to_path_buf(), e)
+            })
+    }
+
+    /// Publishes to an HTTP registry. The index is only replaced if it
+    /// didn't change since it was downloaded, by its `ETag`.
+    #[cfg(feature = "reqwest")]
+    fn publish_url(
+        &self,
+        entry: IndexEntry,
+        contents: Vec<u8>,
+        force: bool,
+    ) -> Result<(), PublishError> {
+        let mut location = self.location.clone();
+        if !location.ends_with('/') {
+            location.push('/');
+        }
+        let base = Url::parse(&location).map_err(|e| PublishError::Url(location.clone(), e))?;
+        let join = |path: &str| {
+            base.join(path).map_err(|e| PublishError::Url(location.clone(), e))
+        };
+        let index_url = join(INDEX_NAME)?;
+        let archive_url = join(&entry.source)?;
+        let client = HttpClient::new().map_err(|e| PublishError::Http(location.clone(), e))?;
+
+        let (mut index, etag) = match self.send(
+            &client,
+            Request::new(Method::Get, index_url.clone()),
+        ) {
+            // The first publish creates the index
+            Err(PublishError::HttpStatus(_, StatusCode::NotFound)) => {
+                (Index::empty(index_url.as_str(), PathBuf::new()), None)
+            }
+            Err(e) => return Err(e),
+            Ok(mut response) => {
+                let etag = response.headers().get::<ETag>().cloned();
+                let mut body = String::new();
+                response.read_to_string(&mut body).map_err(|e| {
+                    PublishError::HttpRead(index_url.to_string(), e)
+                })?;
+                (Index::parse(index_url.as_str(), PathBuf::new(), &body)?, etag)
+            }
+        };
+        Self::add(&mut index, entry, force)?;
+
+        let mut request = Request::new(Method::Put, archive_url);
+        *request.body_mut() = Some(Body::from(contents));
+        self.send(&client, request)?;
+
+        let mut request = Request::new(Method::Put, index_url.clone());
+        match etag {
+            Some(ETag(etag)) => request.headers_mut().set(IfMatch::Items(vec![etag])),
+            None => request.headers_mut().set(IfNoneMatch::Any),
+        }
+        *request.body_mut() = Some(Body::from(index.serialize()?.into_bytes()));
+        match self.send(&client, request) {
+            Err(PublishError::HttpStatus(_, StatusCode::PreconditionFailed)) => {
+                Err(PublishError::Conflict(index_url.to_string()))
+            }
+            r => r.map(|_| ()),
+        }
+    }
+
+    /// Sends a request with the token, failing on any status but success.
+    #[cfg(feature = "reqwest")]
+    fn send(
+        &self,
+        client: &HttpClient,
+        mut request: Request,
+    ) -> Result<reqwest::Response, PublishError> {
+        if let Some(ref token) = self.token {
+            request.headers_mut().set(
+                Authorization(Bearer { token: token.clone() }),
+            );
+        }
+        let url = request.url().to_string();
+        let response = client.execute(request).map_err(
+            |e| PublishError::Http(url.clone(), e),
+        )?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(response)
+        } else {
+            Err(PublishError::HttpStatus(url, status))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::path::Path;
+
+    use libflate::gzip::Encoder;
+    use tar::{Builder, Header};
+    use tempdir::TempDir;
+
+    use index::Index;
+    use metadata::ArchiveMetadata;
+    use super::{PublishError, Registry};
+
+    fn archive(path: &Path, metadata: &str) {
+        let gz = Encoder::new(File::create(path).unwrap()).unwrap();
+        let mut tar = Builder::new(gz);
+        for &(name, data) in [("export.toml", metadata), ("export.rlib", "rlib")].iter() {
+            let mut header = Header::new_old();
+            header.set_path(name).unwrap();
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            tar.append(&header, data.as_bytes()).unwrap();
+        }
+        tar.into_inner().unwrap().finish().unwrap();
+    }
+
+    #[test]
+    fn publish_dir() {
+        let dir = TempDir::new("dhl-test-publish").unwrap();
+        let registry_dir = dir.path().join("registry");
+        let source = dir.path().join("exported.tar.gz");
+        archive(
+            &source,
+            "name = \"priv\"\nversion = \"1.0.0\"\ntarget = \"x86_64-unknown-linux-gnu\"\n",
+        );
+        let registry = Registry::new(registry_dir.to_string_lossy().into_owned(), None);
+
+        match registry.publish(&source, &ArchiveMetadata::default(), false) {
+            Err(PublishError::MissingField(_, "rustc")) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        for &(rustc, profile) in [("../1.21.0", None), ("1.21.0", Some("a/b"))].iter() {
+            let overrides = ArchiveMetadata {
+                rustc: Some(rustc.into()),
+                profile: profile.map(Into::into),
+                ..ArchiveMetadata::default()
+            };
+            match registry.publish(&source, &overrides, false) {
+                Err(PublishError::InvalidField(..)) => {}
+                r => panic!("unexpected result {:?}", r),
+            }
+        }
+        assert!(!registry_dir.exists());
+
+        let overrides = ArchiveMetadata {
+            rustc: Some("1.21.0".into()),
+            ..ArchiveMetadata::default()
+        };
+        let entry = registry.publish(&source, &overrides, false).unwrap();
+        assert_eq!(entry.source, "priv-1.0.0-x86_64-unknown-linux-gnu-1.21.0.tar.gz");
+        assert!(registry_dir.join(&entry.source).is_file());
+        assert!(!registry_dir.join(".index.toml.lock").exists());
+
+        match registry.publish(&source, &overrides, false) {
+            Err(PublishError::Duplicate(ref e)) => assert_eq!(*e, entry),
+            r => panic!("unexpected result {:?}", r),
+        }
+        let overrides = ArchiveMetadata {
+            profile: Some("release".into()),
+            ..overrides
+        };
+        registry.publish(&source, &overrides, false).unwrap();
+        registry.publish(&source, &overrides, true).unwrap();
+
+        let index = Index::load("index.toml", &registry_dir).unwrap();
+        assert_eq!(index.entries().len(), 2);
+        assert_eq!(index.entries()[0], entry);
+        assert_eq!(index.entries()[1].profile, Some("release".to_owned()));
+    }
+
+    #[cfg(feature = "reqwest")]
+    #[test]
+    fn publish_url() {
+        use std::thread;
+
+        use serve::Server;
+
+        let dir = TempDir::new("dhl-test-publish").unwrap();
+        let registry_dir = dir.path().join("registry");
+        let source = dir.path().join("exported.tar.gz");
+        archive(
+            &source,
+            "name = \"priv\"\nversion = \"1.0.0\"\ntarget = \"x86_64-unknown-linux-gnu\"\n\
+             rustc = \"1.21.0\"\n",
+        );
+        let server = Server::bind("127.0.0.1:0", &registry_dir)
+            .unwrap()
+            .token(Some("secret".into()))
+            .writable(true);
+        let location = format!("http://{}/dhl", server.local_addr().unwrap());
+        thread::spawn(move || server.run(|_| {}));
+
+        let overrides = ArchiveMetadata::default();
+        match Registry::new(location.as_str(), None).publish(&source, &overrides, false) {
+            Err(PublishError::HttpStatus(..)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        let registry = Registry::new(location.as_str(), Some("secret".into()));
+        let entry = registry.publish(&source, &overrides, false).unwrap();
+        assert!(registry_dir.join("dhl").join(&entry.source).is_file());
+        match registry.publish(&source, &overrides, false) {
+            Err(PublishError::Duplicate(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        registry.publish(&source, &overrides, true).unwrap();
+
+        let index = Index::load("index.toml", &registry_dir.join("dhl")).unwrap();
+        assert_eq!(index.entries(), &[entry]);
+    }
+
+    #[cfg(feature = "reqwest")]
+    #[test]
+    fn publish_url_status() {
+        use std::io::{BufRead, BufReader, Read, Write};
+        use std::net::TcpListener;
+        use std::thread;
+
+        use reqwest::StatusCode;
+
+        // Answers every request with `404 Not Found`, like a registry whose
+        // uploads go nowhere
+        const NOT_FOUND: &'static [u8] =
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let location = format!("http://{}/dhl", listener.local_addr().unwrap());
+        thread::spawn(move || for stream in listener.incoming() {
+            let mut stream = BufReader::new(stream.unwrap());
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                stream.read_line(&mut line).unwrap();
+                let line = line.trim().to_lowercase();
+                if line.is_empty() {
+                    break;
+                }
+                if line.starts_with("content-length:") {
+                    length = line["content-length:".len()..].trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            stream.read_exact(&mut body).unwrap();
+            stream.get_mut().write_all(NOT_FOUND).unwrap();
+        });
+
+        let dir = TempDir::new("dhl-test-publish").unwrap();
+        let source = dir.path().join("exported.tar.gz");
+        archive(
+            &source,
+            "name = \"priv\"\nversion = \"1.0.0\"\ntarget = \"x86_64-unknown-linux-gnu\"\n\
+             rustc = \"1.21.0\"\n",
+        );
+
+        // The missing index is created, but the upload must succeed
+        let registry = Registry::new(location.as_str(), None);
+        match registry.publish(&source, &ArchiveMetadata::default(), false) {
+            Err(PublishError::HttpStatus(ref url, StatusCode::NotFound)) => {
+                assert!(url.ends_with("/dhl/priv-1.0.0-x86_64-unknown-linux-gnu-1.21.0.tar.gz"))
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        let overrides = ArchiveMetadata {
+            name: Some("../priv".into()),
+            ..ArchiveMetadata::default()
+        };
+        match registry.publish(&source, &overrides, false) {
+            Err(PublishError::InvalidField(_, "name", ref name)) => assert_eq!(name, "../priv"),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+}
@@ -1,15 +1,18 @@
 use std::collections::HashMap;
-use std::collections::hash_map::Entry::*;
 use std::io::Result as IoResult;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::Relaxed;
 use std::time::SystemTime;
 use std::ffi::{OsString, OsStr};
-use std::fs::Metadata;
+use std::fs::{File, Metadata};
+use std::io::Read;
 use std::path::{Path, PathBuf};
-use std::mem::replace;
+use std::str::FromStr;
 
 use var_os_or;
+use artifact::{ArtifactKind, ArtifactNaming};
+use metadata::NATIVE_DIR;
+use report;
 
 quick_error! {
     #[derive(Debug)]
@@ -22,20 +25,60 @@ quick_error! {
             description("could not find deps dir from OUT_DIR")
             display("Could not find deps from using '{}'", Path::new(&out_dir).display())
         }
+        InvalidTarget(target: OsString) {
+            description("TARGET was not valid utf8")
+            display("Target '{}' was not valid utf8", Path::new(&target).display())
+        }
+        AmbiguousArtifact(name: String, candidates: Vec<OsString>) {
+            description("multiple artifacts for a crate")
+            display("Multiple artifacts for '{}' {:?}, set a hash or a duplicates policy",
+                name, candidates)
+        }
+    }
+}
+
+/// What to do when the `deps` directory has several artifacts for a crate,
+/// e.g. from builds with different profiles or features.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DuplicatePolicy {
+    /// Fail with `RecipientsError::AmbiguousArtifact`.
+    Error,
+    /// Use the most recently modified artifact and warn about the rest.
+    Newest,
+}
+
+impl Default for DuplicatePolicy {
+    fn default() -> Self {
+        DuplicatePolicy::Error
+    }
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "error" => Ok(DuplicatePolicy::Error),
+            "newest" => Ok(DuplicatePolicy::Newest),
+            _ => Err(()),
+        }
     }
 }
 
 /// The info locator stores on each library file
 struct Address {
     file_name: OsString,
+    hash: Option<String>,
     last_modified: IoResult<SystemTime>,
     is_watched: AtomicBool,
 }
 
 impl Address {
-    fn new(file_name: OsString, metadata: IoResult<Metadata>) -> Self {
+    fn new(file_name: OsString, hash: Option<String>, metadata: IoResult<Metadata>) -> Self {
         Address {
             file_name,
+            hash,
             last_modified: metadata.and_then(|v| v.modified()),
             is_watched: AtomicBool::new(false),
         }
@@ -55,31 +98,89 @@ impl Address {
     }
 }
 
-struct Addresses {
-    most_recent: Address,
-    previous: Vec<OsString>,
+/// Where the artifacts of a single hijacked crate should be delivered.
+#[derive(Debug, Clone)]
+pub struct Recipient {
+    deps_dir: PathBuf,
+    native_dir: PathBuf,
+    exports: HashMap<String, PathBuf>,
+    /// The dep-info file cargo writes next to the artifacts, if any.
+    dep_info: Option<PathBuf>,
 }
 
-impl Addresses {
-    fn new(most_recent: Address) -> Self {
-        Addresses {
-            most_recent,
-            previous: Vec::new(),
+impl Recipient {
+    /// A recipient for artifacts that don't exist yet, such as the outputs
+    /// rustc was asked for.
+    pub(crate) fn new(
+        deps_dir: PathBuf,
+        native_dir: PathBuf,
+        exports: HashMap<String, PathBuf>,
+    ) -> Self {
+        Recipient {
+            deps_dir,
+            native_dir,
+            exports,
+            dep_info: None,
+        }
+    }
+
+    /// The directory the crate's dependencies are delivered to.
+    pub fn deps_dir(&self) -> &Path {
+        &self.deps_dir
+    }
+
+    /// The directory the crate's native libraries are delivered to.
+    pub fn native_dir(&self) -> &Path {
+        &self.native_dir
+    }
+
+    /// The artifact an archive entry should be delivered onto, if the entry
+    /// is an export produced by the dummy crate.
+    pub fn export(&self, file_name: &str) -> Option<&Path> {
+        self.exports.get(file_name).map(PathBuf::as_path)
+    }
+
+    /// The source files the crate was compiled from, read from its dep-info
+    /// file. `None` if there is no dep-info file to read.
+    pub fn sources(&self) -> Option<Vec<PathBuf>> {
+        let mut contents = String::new();
+        match self.dep_info.as_ref().map(|d| {
+            File::open(d).and_then(|mut f| f.read_to_string(&mut contents))
+        }) {
+            Some(Ok(_)) => {}
+            _ => return None,
         }
+        // Every source gets a rule of its own without prerequisites, e.g.
+        // `/path/to/src/lib.rs:`, with spaces escaped.
+        let sources = contents
+            .lines()
+            .filter(|line| line.ends_with(':') && !line.contains(": "))
+            .map(|line| {
+                PathBuf::from(line[..line.len() - 1].replace("\\ ", " "))
+            })
+            .filter(|source| source.extension().map_or(false, |e| e == "rs"))
+            .collect();
+        Some(sources)
     }
 }
 
 pub struct Recipients {
+    out_dir: PathBuf,
     deps_dir: PathBuf,
     relative_deps_dir: Option<PathBuf>,
-    addresses: HashMap<String, Addresses>,
+    naming: ArtifactNaming,
+    addresses: HashMap<(String, ArtifactKind), Vec<Address>>,
 }
 
 impl Recipients {
     pub fn new() -> Result<Self, RecipientsError> {
         let out_dir = var_os_or("OUT_DIR", RecipientsError::EnvError)?;
         let manifest_dir = var_os_or("CARGO_MANIFEST_DIR", RecipientsError::EnvError)?;
-        Self::with_env(&out_dir, &manifest_dir)
+        let target = var_os_or("TARGET", RecipientsError::EnvError)?;
+        let target = target.into_string().map_err(
+            RecipientsError::InvalidTarget,
+        )?;
+        Self::with_env(&out_dir, &manifest_dir, &target)
     }
 
     fn get_deps_dir<S>(out_dir: &S) -> Result<PathBuf, RecipientsError>
@@ -96,17 +197,31 @@ impl Recipients {
         }
     }
 
-    pub(super) fn with_env<S, T>(out_dir: &S, manifest_dir: &T) -> Result<Self, RecipientsError>
+    pub(super) fn with_env<S, T>(
+        out_dir: &S,
+        manifest_dir: &T,
+        target: &str,
+    ) -> Result<Self, RecipientsError>
     where
         S: ?Sized + AsRef<OsStr>,
         T: ?Sized + AsRef<OsStr>,
     {
         let manifest_dir = manifest_dir.as_ref();
         let deps_dir = Self::get_deps_dir(out_dir.as_ref())?;
-        Ok(Self::with_path(deps_dir, manifest_dir))
+        Ok(Self::with_path(
+            PathBuf::from(out_dir.as_ref()),
+            deps_dir,
+            manifest_dir,
+            ArtifactNaming::for_target(target),
+        ))
     }
 
-    fn with_path<P>(deps_dir: PathBuf, manifest_dir: &P) -> Self
+    fn with_path<P>(
+        out_dir: PathBuf,
+        deps_dir: PathBuf,
+        manifest_dir: &P,
+        naming: ArtifactNaming,
+    ) -> Self
     where
         P: ?Sized + AsRef<Path>,
     {
@@ -116,7 +231,7 @@ impl Recipients {
         for file in deps_dir.read_dir().unwrap() {
             let file = file.unwrap();
             let file_name = file.file_name();
-            let utf_file_name: String = {
+            let name = {
                 // Skip entries that aren't utf8
                 let utf_file_name = if let Some(file_name) = file_name.to_str() {
                     file_name
@@ -124,75 +239,138 @@ impl Recipients {
                     continue;
                 };
                 // Skip entries that don't match libraries
-                let utf_file_name =
-                    if let Some(file_name) = utf_file_name.splitn(2, "lib").nth(1) {
-                        file_name
-                    } else {
-                        continue;
-                    };
-                if !utf_file_name.ends_with(".rlib") {
-                    continue;
-                }
-                // split on the -
-                let utf_file_name = if let Some(file_name) = utf_file_name.splitn(2, '-').nth(0) {
-                    file_name
+                if let Some(name) = naming.parse(utf_file_name) {
+                    name
                 } else {
                     continue;
-                };
-                utf_file_name.into()
+                }
             };
 
-            let info = Address::new(file_name, file.metadata());
-            match addresses.entry(utf_file_name.clone()) {
-                Vacant(entry) => {
-                    entry.insert(Addresses::new(info));
-                }
-                Occupied(mut entry) => {
-                    let entry = entry.get_mut();
-                    let old = if entry.most_recent.is_newer(&info) {
-                        info
-                    } else {
-                        replace(&mut entry.most_recent, info)
-                    };
-                    entry.previous.push(old.file_name);
-                }
-            }
+            let info = Address::new(file_name, name.hash, file.metadata());
+            addresses
+                .entry((name.crate_name, name.kind))
+                .or_insert_with(Vec::new)
+                .push(info);
         }
 
         Recipients {
+            out_dir,
             deps_dir,
             relative_deps_dir,
+            naming,
             addresses,
         }
     }
 
-    pub(super) fn get(&self, name: &str) -> Option<PathBuf> {
+    /// The naming conventions of the target being built.
+    pub fn naming(&self) -> &ArtifactNaming {
+        &self.naming
+    }
+
+    /// Finds the artifacts of a crate. If `hash` is given only artifacts
+    /// with that hash are considered, otherwise `policy` decides between
+    /// multiple artifacts of the same kind.
+    pub(super) fn get(
+        &self,
+        name: &str,
+        hash: Option<&str>,
+        policy: DuplicatePolicy,
+    ) -> Result<Option<Recipient>, RecipientsError> {
         let name = name.replace('-', "_");
-        self.addresses.get(&name).map(|address| {
-            if !address.previous.is_empty() {
-                println!(
-                    "cargo:warning=duplicate entries for {}, using '{}', ignoring '{:?}'",
-                    name,
-                    Path::new(&address.most_recent.file_name).display(),
-                    &address.previous,
-                );
+        let mut exports = HashMap::new();
+        for &kind in ArtifactKind::ALL.iter() {
+            if let Some(dest) = self.get_artifact(&name, kind, hash, policy)? {
+                exports.insert(self.naming.export_name(kind), dest);
             }
+        }
 
-            let file_name = Path::new(&address.most_recent.file_name);
-            let dest = self.deps_dir.join(file_name);
-
-            // Make sure we watch the library file for changes
-            if address.most_recent.watch() {
-                let rel_dest = self.relative_deps_dir.as_ref().map(
-                    |dir| dir.join(file_name),
-                );
-                let dest = rel_dest.as_ref().unwrap_or(&dest);
-                // TODO: do we need to save this info?
-                println!("cargo:rerun-if-changed={}", dest.display());
-            }
-            dest
+        let dep_info = exports
+            .values()
+            .filter_map(|export| export.file_name().and_then(OsStr::to_str))
+            .filter_map(|file_name| self.naming.parse(file_name))
+            .map(|artifact| {
+                self.deps_dir.join(match artifact.hash {
+                    Some(hash) => format!("{}-{}.d", artifact.crate_name, hash),
+                    None => format!("{}.d", artifact.crate_name),
+                })
+            })
+            .find(|dep_info| dep_info.is_file());
+
+        Ok(if exports.is_empty() {
+            None
+        } else {
+            Some(Recipient {
+                deps_dir: self.deps_dir.clone(),
+                native_dir: self.out_dir.join(NATIVE_DIR).join(&name),
+                exports,
+                dep_info,
+            })
         })
     }
+
+    fn get_artifact(
+        &self,
+        name: &str,
+        kind: ArtifactKind,
+        hash: Option<&str>,
+        policy: DuplicatePolicy,
+    ) -> Result<Option<PathBuf>, RecipientsError> {
+        let candidates: Vec<&Address> = match self.addresses.get(&(name.to_owned(), kind)) {
+            Some(addresses) => {
+                addresses
+                    .iter()
+                    .filter(|address| {
+                        hash.map_or(true, |hash| {
+                            address.hash.as_ref().map(AsRef::as_ref) == Some(hash)
+                        })
+                    })
+                    .collect()
+            }
+            None => return Ok(None),
+        };
+
+        let address = match (candidates.len(), policy) {
+            (0, _) => return Ok(None),
+            (1, _) => candidates[0],
+            (_, DuplicatePolicy::Error) => {
+                return Err(RecipientsError::AmbiguousArtifact(
+                    name.to_owned(),
+                    candidates.iter().map(|a| a.file_name.clone()).collect(),
+                ))
+            }
+            (_, DuplicatePolicy::Newest) => {
+                let most_recent = candidates.iter().skip(1).fold(candidates[0], |a, &b| {
+                    if a.is_newer(b) { a } else { b }
+                });
+                let previous: Vec<&OsString> = candidates
+                    .iter()
+                    .filter(|a| a.file_name != most_recent.file_name)
+                    .map(|a| &a.file_name)
+                    .collect();
+                report::warning(&format!(
+                    "duplicate entries for {}, using '{}', ignoring '{:?}'",
+                    name,
+                    Path::new(&most_recent.file_name).display(),
+                    previous
+                ));
+                most_recent
+            }
+        };
+
+        let file_name = Path::new(&address.file_name);
+        let dest = self.deps_dir.join(file_name);
+
+        // Make sure we watch the library file for changes
+        if address.watch() {
+            let rel_dest = self.relative_deps_dir.as_ref().map(
+                |dir| dir.join(file_name),
+            );
+            let dest = rel_dest.as_ref().unwrap_or(&dest);
+            // TODO: do we need to save this info?
+            println!("cargo:rerun-if-changed={}", dest.display());
+        }
+        Ok(Some(dest))
+    }
 }
 
 
@@ -206,7 +384,7 @@ mod test {
 
     use tempdir::TempDir;
 
-    use super::Recipients;
+    use super::{DuplicatePolicy, Recipients};
 
     #[test]
     fn check_deps_dir() {
@@ -233,11 +411,84 @@ mod test {
         File::create(deps_dir.join("libdhltest_underscore-deadbeef.rlib")).unwrap();
         File::create(deps_dir.join("libdhltest-c000l0ff.rlib")).unwrap();
 
-        let r = Recipients::with_env(&out_dir, base_dir.path()).unwrap();
+        let r = Recipients::with_env(&out_dir, base_dir.path(), "x86_64-unknown-linux-gnu")
+            .unwrap();
+
+        r.get("dhltest", None, DuplicatePolicy::Error).unwrap().unwrap();
+        r.get("dhltest-dash", None, DuplicatePolicy::Error).unwrap().unwrap();
+        r.get("dhltest_underscore", None, DuplicatePolicy::Error).unwrap().unwrap();
+
+        base_dir.close().unwrap();
+    }
+
+    #[test]
+    fn verify_tmp_native_deps() {
+        let base_dir = TempDir::new("example").unwrap();
+        let deps_dir = base_dir.path().join("deps");
+        let out_dir = base_dir.path().join("build").join("example").join("out");
+        create_dir_all(&out_dir).unwrap();
+        create_dir_all(&deps_dir).unwrap();
+
+        File::create(deps_dir.join("dhltest_dash-d15ea5e.dll")).unwrap();
+        File::create(deps_dir.join("dhltest_dash-d15ea5e.dll.lib")).unwrap();
+        File::create(deps_dir.join("dhltest_underscore-deadbeef.lib")).unwrap();
+        File::create(deps_dir.join("libdhltest-c000l0ff.rlib")).unwrap();
+        File::create(deps_dir.join("dhltest-c000l0ff.dll")).unwrap();
+
+        let r = Recipients::with_env(&out_dir, base_dir.path(), "x86_64-pc-windows-msvc").unwrap();
 
-        r.get("dhltest").unwrap();
-        r.get("dhltest-dash").unwrap();
-        r.get("dhltest_underscore").unwrap();
+        let dhltest = r.get("dhltest", None, DuplicatePolicy::Error).unwrap().unwrap();
+        assert_eq!(
+            dhltest.export("export.rlib").unwrap(),
+            deps_dir.join("libdhltest-c000l0ff.rlib")
+        );
+        assert_eq!(
+            dhltest.export("export.dll").unwrap(),
+            deps_dir.join("dhltest-c000l0ff.dll")
+        );
+        assert!(dhltest.export("export.lib").is_none());
+
+        let dash = r.get("dhltest-dash", None, DuplicatePolicy::Error).unwrap().unwrap();
+        assert!(dash.export("export.rlib").is_none());
+        assert!(dash.export("export.lib").is_none());
+        dash.export("export.dll").unwrap();
+
+        let underscore = r.get("dhltest_underscore", None, DuplicatePolicy::Error).unwrap().unwrap();
+        underscore.export("export.lib").unwrap();
+
+        base_dir.close().unwrap();
+    }
+
+    #[test]
+    fn verify_tmp_duplicate_deps() {
+        let base_dir = TempDir::new("example").unwrap();
+        let deps_dir = base_dir.path().join("deps");
+        let out_dir = base_dir.path().join("build").join("example").join("out");
+        create_dir_all(&out_dir).unwrap();
+        create_dir_all(&deps_dir).unwrap();
+
+        File::create(deps_dir.join("libdhltest-c000l0ff.rlib")).unwrap();
+        File::create(deps_dir.join("libdhltest-deadbeef.rlib")).unwrap();
+
+        let r = Recipients::with_env(&out_dir, base_dir.path(), "x86_64-unknown-linux-gnu")
+            .unwrap();
+
+        assert!(r.get("dhltest", None, DuplicatePolicy::Error).is_err());
+        r.get("dhltest", None, DuplicatePolicy::Newest).unwrap().unwrap();
+
+        let dhltest = r.get("dhltest", Some("deadbeef"), DuplicatePolicy::Error)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            dhltest.export("export.rlib").unwrap(),
+            deps_dir.join("libdhltest-deadbeef.rlib")
+        );
+
+        assert!(
+            r.get("dhltest", Some("d15ea5e"), DuplicatePolicy::Error)
+                .unwrap()
+                .is_none()
+        );
 
         base_dir.close().unwrap();
     }
@@ -0,0 +1,56 @@
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+
+static BUILD_SCRIPT: AtomicBool = AtomicBool::new(true);
+
+/// Whether dhl prints the `cargo:rerun-if-*` directives for what it reads,
+/// and where its warnings go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reporter {
+    /// `cargo:` directives on stdout, for build scripts. The default.
+    BuildScript,
+    /// Nothing on stdout and warnings on stderr, for running outside a
+    /// build script, like `dhl-rustc-wrapper` and `dhl` do.
+    Stderr,
+}
+
+impl Reporter {
+    /// The reporter of this process.
+    pub fn current() -> Self {
+        if BUILD_SCRIPT.load(Relaxed) {
+            Reporter::BuildScript
+        } else {
+            Reporter::Stderr
+        }
+    }
+
+    /// Makes this the reporter of this process.
+    pub fn install(self) {
+        BUILD_SCRIPT.store(self == Reporter::BuildScript, Relaxed);
+    }
+}
+
+/// Tells cargo to rerun the build script when a file it read changes.
+/// Files that don't exist are skipped, cargo would rerun every build.
+pub(crate) fn rerun_if_changed(path: &Path) {
+    if Reporter::current() == Reporter::BuildScript && path.is_file() {
+        println!("cargo:rerun-if-changed={}", path.display());
+    }
+}
+
+/// Tells cargo to rerun the build script when a variable it read changes.
+pub(crate) fn rerun_if_env_changed(key: &str) {
+    if Reporter::current() == Reporter::BuildScript {
+        println!("cargo:rerun-if-env-changed={}", key);
+    }
+}
+
+/// Warns about something that doesn't fail the build, as a `cargo:warning`
+/// from build scripts and on stderr otherwise.
+pub(crate) fn warning(message: &str) {
+    match Reporter::current() {
+        Reporter::BuildScript => println!("cargo:warning={}", message),
+        Reporter::Stderr => eprintln!("warning: {}", message),
+    }
+}
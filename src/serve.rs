@@ -0,0 +1,632 @@
+use std::fs::{File, create_dir_all, remove_file, rename};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use lock::{Hashing, sha256_of};
+
+/// The largest request head the server accepts.
+const MAX_HEAD: usize = 64 * 1024;
+/// The largest `PUT` body the server accepts.
+const MAX_BODY: u64 = 4 * 1024 * 1024 * 1024;
+/// The most connections handled at once; more are turned away with a 503.
+const MAX_CONNECTIONS: usize = 64;
+/// How many seconds a read or write on a connection may stall.
+const TIMEOUT_SECS: u64 = 30;
+
+/// A minimal HTTP file server over a directory of archives, for a LAN
+/// mirror or for tests. It supports `GET` and `HEAD` with `Range` and
+/// `ETag`s, bearer token auth and, if writable, `PUT` for `dhl publish`.
+#[derive(Debug)]
+pub struct Server {
+    listener: TcpListener,
+    config: Arc<Config>,
+}
+
+#[derive(Debug)]
+struct Config {
+    dir: PathBuf,
+    token: Option<String>,
+    writable: bool,
+    /// Held while a `PUT` checks its preconditions and writes.
+    write_lock: Mutex<()>,
+    /// The connections being handled.
+    connections: AtomicUsize,
+    /// Numbers the temporary files `PUT` bodies are received into.
+    uploads: AtomicUsize,
+}
+
+/// Counts a connection for as long as it's handled.
+struct Connection(Arc<Config>);
+
+impl Connection {
+    fn open(config: &Arc<Config>) -> Option<Self> {
+        if config.connections.fetch_add(1, Ordering::SeqCst) < MAX_CONNECTIONS {
+            Some(Connection(Arc::clone(config)))
+        } else {
+            config.connections.fetch_sub(1, Ordering::SeqCst);
+            None
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A parsed request.
+#[derive(Debug)]
+struct Request {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        let name = name.to_lowercase();
+        self.headers
+            .iter()
+            .find(|h| h.0.to_lowercase() == name)
+            .map(|h| h.1.as_str())
+    }
+}
+
+/// A response without a body, which is written separately.
+struct Response {
+    status: &'static str,
+    headers: Vec<(&'static str, String)>,
+}
+
+impl Response {
+    fn new(status: &'static str) -> Self {
+        Response {
+            status,
+            headers: Vec::new(),
+        }
+    }
+
+    fn header<V: Into<String>>(mut self, name: &'static str, value: V) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    fn write<R: Read>(self, stream: &mut TcpStream, mut body: R) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {}\r\n", self.status);
+        for (name, value) in self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("Connection: close\r\n\r\n");
+        stream.write_all(head.as_bytes())?;
+        io::copy(&mut body, stream)?;
+        stream.flush()
+    }
+
+    /// A response with a short plain text body.
+    fn text(self, stream: &mut TcpStream, method: &str, body: &str) -> io::Result<()> {
+        let response = self.header("Content-Type", "text/plain")
+            .header("Content-Length", (body.len() + 1).to_string());
+        if method == "HEAD" {
+            response.write(stream, io::empty())
+        } else {
+            response.write(stream, format!("{}\n", body).as_bytes())
+        }
+    }
+}
+
+impl Server {
+    /// Binds to `addr` to serve `dir`. Port 0 picks a free port, see
+    /// `local_addr`.
+    pub fn bind<A: ToSocketAddrs, P: Into<PathBuf>>(addr: A, dir: P) -> io::Result<Self> {
+        Ok(Server {
+            listener: TcpListener::bind(addr)?,
+            config: Arc::new(Config {
+                dir: dir.into(),
+                token: None,
+                writable: false,
+                write_lock: Mutex::new(()),
+                connections: AtomicUsize::new(0),
+                uploads: AtomicUsize::new(0),
+            }),
+        })
+    }
+
+    /// Requires `Authorization: Bearer <token>` on every request.
+    pub fn token(mut self, token: Option<String>) -> Self {
+        Arc::get_mut(&mut self.config).unwrap().token = token;
+        self
+    }
+
+    /// Accepts `PUT` requests, honoring `If-Match` and `If-None-Match`.
+    pub fn writable(mut self, writable: bool) -> Self {
+        Arc::get_mut(&mut self.config).unwrap().writable = writable;
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serves requests until accepting a connection fails, each on its own
+    /// thread. Errors handling a single connection are passed to `log`.
+    pub fn run<F>(&self, log: F) -> io::Result<()>
+    where
+        F: Fn(io::Error) + Send + Sync + 'static,
+    {
+        let log = Arc::new(log);
+        for stream in self.listener.incoming() {
+            let mut stream = stream?;
+            // Turned away before spawning, so there are never more threads
+            // than connections handled
+            let connection = match Connection::open(&self.config) {
+                Some(connection) => connection,
+                None => {
+                    if let Err(e) = Config::refuse(&mut stream) {
+                        log(e);
+                    }
+                    continue;
+                }
+            };
+            let log = Arc::clone(&log);
+            thread::spawn(move || {
+                if let Err(e) = connection.0.accept(&mut stream) {
+                    log(e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    fn set_timeouts(stream: &TcpStream) -> io::Result<()> {
+        let timeout = Some(Duration::from_secs(TIMEOUT_SECS));
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)
+    }
+
+    fn accept(&self, stream: &mut TcpStream) -> io::Result<()> {
+        Self::set_timeouts(stream)?;
+        self.handle(stream)
+    }
+
+    fn refuse(stream: &mut TcpStream) -> io::Result<()> {
+        Self::set_timeouts(stream)?;
+        Response::new("503 Service Unavailable")
+            .header("Retry-After", "1")
+            .text(stream, "GET", "too many connections")
+    }
+
+    fn handle(&self, stream: &mut TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let request = match Self::read_request(&mut reader)? {
+            Some(request) => request,
+            None => return Response::new("400 Bad Request").text(stream, "GET", "bad request"),
+        };
+        let method = request.method.as_str();
+
+        if let Some(ref token) = self.token {
+            let authorized = request.header("Authorization").map_or(false, |auth| {
+                auth.starts_with("Bearer ")
+                    && constant_time_eq(auth["Bearer ".len()..].trim().as_bytes(), token.as_bytes())
+            });
+            if !authorized {
+                return Response::new("401 Unauthorized")
+                    .header("WWW-Authenticate", "Bearer")
+                    .text(stream, method, "unauthorized");
+            }
+        }
+
+        let path = match self.resolve(&request.path) {
+            Some(path) => path,
+            None => return Response::new("404 Not Found").text(stream, method, "not found"),
+        };
+        match method {
+            "GET" | "HEAD" => Self::get(stream, &request, &path),
+            "PUT" if self.writable => self.put(stream, &request, &path, &mut reader),
+            _ => {
+                let allow = if self.writable { "GET, HEAD, PUT" } else { "GET, HEAD" };
+                Response::new("405 Method Not Allowed")
+                    .header("Allow", allow)
+                    .text(stream, method, "method not allowed")
+            }
+        }
+    }
+
+    fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Option<Request>> {
+        let mut head = Vec::new();
+        let mut line = String::new();
+        let mut remaining = MAX_HEAD;
+        loop {
+            line.clear();
+            let read = reader.by_ref().take(remaining as u64).read_line(&mut line)?;
+            if read == 0 || !line.ends_with('\n') {
+                return Ok(None);
+            }
+            remaining -= read;
+            let trimmed = line.trim_end();
+            if trimmed.is_empty() {
+                break;
+            }
+            head.push(trimmed.to_owned());
+        }
+
+        let mut request_line = match head.first() {
+            Some(line) => line.split_whitespace(),
+            None => return Ok(None),
+        };
+        let (method, target) = match (request_line.next(), request_line.next()) {
+            (Some(method), Some(target)) => (method.to_owned(), target),
+            _ => return Ok(None),
+        };
+        let path = match percent_decode(target.split('?').next().unwrap_or("")) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let headers = head[1..]
+            .iter()
+            .filter_map(|line| {
+                line.find(':').map(|colon| {
+                    (line[..colon].trim().to_owned(), line[colon + 1..].trim().to_owned())
+                })
+            })
+            .collect();
+        Ok(Some(Request {
+            method,
+            path,
+            headers,
+        }))
+    }
+
+    /// The file a request path refers to, if it stays within the directory.
+    /// Dot-files aren't served, which keeps the temporary files of uploads
+    /// in progress out of reach.
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let mut resolved = self.dir.clone();
+        for component in Path::new(path.trim_start_matches('/')).components() {
+            match component {
+                Component::Normal(c) if c.to_string_lossy().starts_with('.') => return None,
+                Component::Normal(c) => resolved.push(c),
+                Component::CurDir => {}
+                _ => return None,
+            }
+        }
+        if resolved == self.dir {
+            None
+        } else {
+            Some(resolved)
+        }
+    }
+
+    fn get(stream: &mut TcpStream, request: &Request, path: &Path) -> io::Result<()> {
+        let method = request.method.as_str();
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(_) => return Response::new("404 Not Found").text(stream, method, "not found"),
+        };
+        if file.metadata()?.is_dir() {
+            return Response::new("404 Not Found").text(stream, method, "not found");
+        }
+        let etag = format!("\"{}\"", sha256_of(&mut file)?);
+        let len = file.seek(SeekFrom::End(0))?;
+
+        if request.header("If-None-Match").map_or(false, |tags| matches(tags, &etag)) {
+            return Response::new("304 Not Modified")
+                .header("ETag", etag)
+                .write(stream, io::empty());
+        }
+
+        // A range is only honored for the current contents, per `If-Range`.
+        let range = match request.header("Range") {
+            Some(range) if request.header("If-Range").map_or(true, |tag| tag == etag) => {
+                match parse_range(range, len) {
+                    Some(Ok(range)) => Some(range),
+                    Some(Err(())) => {
+                        return Response::new("416 Range Not Satisfiable")
+                            .header("Content-Range", format!("bytes */{}", len))
+                            .text(stream, method, "range not satisfiable")
+                    }
+                    None => None,
+                }
+            }
+            _ => None,
+        };
+
+        let (response, start, end) = match range {
+            Some((start, end)) => {
+                let response = Response::new("206 Partial Content")
+                    .header("Content-Range", format!("bytes {}-{}/{}", start, end, len));
+                (response, start, end + 1)
+            }
+            None => (Response::new("200 OK"), 0, len),
+        };
+        let response = response
+            .header("Content-Type", "application/octet-stream")
+            .header("Content-Length", (end - start).to_string())
+            .header("Accept-Ranges", "bytes")
+            .header("ETag", etag);
+        if method == "HEAD" {
+            response.write(stream, io::empty())
+        } else {
+            file.seek(SeekFrom::Start(start))?;
+            response.write(stream, file.take(end - start))
+        }
+    }
+
+    fn put<R: Read>(
+        &self,
+        stream: &mut TcpStream,
+        request: &Request,
+        path: &Path,
+        body: &mut R,
+    ) -> io::Result<()> {
+        let method = request.method.as_str();
+        let len = match request.header("Content-Length").and_then(|l| l.parse::<u64>().ok()) {
+            Some(len) if len <= MAX_BODY => len,
+            Some(_) => {
+                return Response::new("413 Payload Too Large").text(stream, method, "too large")
+            }
+            None => {
+                return Response::new("411 Length Required").text(stream, method, "length required")
+            }
+        };
+
+        // The body is received into a temporary file of its own, so only the
+        // checks and the rename happen under the lock.
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        let file_name = path.file_name().map_or("".into(), |f| f.to_string_lossy());
+        let upload = self.uploads.fetch_add(1, Ordering::SeqCst);
+        let tmp = path.with_file_name(format!(".{}.{}.tmp", file_name, upload));
+        let result = self.store(stream, request, path, &tmp, body.take(len), len);
+        let _ = remove_file(&tmp);
+        result
+    }
+
+    fn store<R: Read>(
+        &self,
+        stream: &mut TcpStream,
+        request: &Request,
+        path: &Path,
+        tmp: &Path,
+        mut body: R,
+        len: u64,
+    ) -> io::Result<()> {
+        let method = request.method.as_str();
+        let mut hashing = Hashing::new(File::create(tmp)?);
+        if io::copy(&mut body, &mut hashing)? < len {
+            return Response::new("400 Bad Request").text(stream, method, "incomplete body");
+        }
+        let (file, digest) = hashing.finish();
+        file.sync_all()?;
+
+        let _lock = self.write_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let current = match File::open(path) {
+            Ok(file) => Some(format!("\"{}\"", sha256_of(file)?)),
+            Err(_) => None,
+        };
+        let precondition = match (request.header("If-Match"), request.header("If-None-Match")) {
+            (Some(tags), _) => current.as_ref().map_or(false, |etag| matches(tags, etag)),
+            (None, Some(tags)) => current.as_ref().map_or(true, |etag| !matches(tags, etag)),
+            (None, None) => true,
+        };
+        if !precondition {
+            return Response::new("412 Precondition Failed").text(
+                stream,
+                method,
+                "precondition failed",
+            );
+        }
+
+        rename(tmp, path)?;
+        let status = if current.is_some() { "200 OK" } else { "201 Created" };
+        Response::new(status)
+            .header("ETag", format!("\"{}\"", digest))
+            .text(stream, method, "stored")
+    }
+}
+
+/// Compares without returning early, so the time taken doesn't reveal how
+/// much of a token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Whether an `If-Match` or `If-None-Match` list matches an `ETag`.
+fn matches(tags: &str, etag: &str) -> bool {
+    tags.split(',').map(str::trim).any(|tag| {
+        tag == "*" || tag == etag || (tag.starts_with("W/") && &tag[2..] == etag)
+    })
+}
+
+/// The first and last byte of a single `bytes=` range, `Some(Err(()))` if it
+/// can't be satisfied, or `None` if it should be ignored, e.g. because it
+/// lists several ranges.
+fn parse_range(range: &str, len: u64) -> Option<Result<(u64, u64), ()>> {
+    if !range.starts_with("bytes=") || range.contains(',') {
+        return None;
+    }
+    let spec = range["bytes=".len()..].trim();
+    let (start, end) = match spec.find('-') {
+        Some(dash) => (&spec[..dash], &spec[dash + 1..]),
+        None => return None,
+    };
+    let last = len.wrapping_sub(1);
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(last)),
+        (Ok(start), Err(_)) if end.is_empty() => (start, last),
+        (Err(_), Ok(0)) if start.is_empty() => return Some(Err(())),
+        (Err(_), Ok(suffix)) if start.is_empty() => (len.saturating_sub(suffix), last),
+        _ => return None,
+    };
+    if len == 0 || range.0 >= len {
+        Some(Err(()))
+    } else {
+        Some(Ok(range))
+    }
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(percent) = rest.find('%') {
+        bytes.extend_from_slice(&rest.as_bytes()[..percent]);
+        let hex = rest.get(percent + 1..percent + 3);
+        match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(b) => bytes.push(b),
+            None => return None,
+        }
+        rest = &rest[percent + 3..];
+    }
+    bytes.extend_from_slice(rest.as_bytes());
+    String::from_utf8(bytes).ok()
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::thread;
+
+    use tempdir::TempDir;
+
+    use super::{Connection, MAX_CONNECTIONS, Server, constant_time_eq, parse_range};
+
+    fn start(server: Server) -> SocketAddr {
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run(|_| {}));
+        addr
+    }
+
+    /// Sends a raw request, returning the status code, head and body.
+    fn send(addr: SocketAddr, request: &str) -> (u32, String, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(response[..split].to_vec()).unwrap();
+        let status = head[9..12].parse().unwrap();
+        (status, head, response[split + 4..].to_vec())
+    }
+
+    fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+        head.lines()
+            .find(|l| l.to_lowercase().starts_with(&format!("{}:", name.to_lowercase())))
+            .map(|l| l[name.len() + 1..].trim())
+    }
+
+    #[test]
+    fn parse_ranges() {
+        assert_eq!(parse_range("bytes=0-3", 10), Some(Ok((0, 3))));
+        assert_eq!(parse_range("bytes=5-", 10), Some(Ok((5, 9))));
+        assert_eq!(parse_range("bytes=-3", 10), Some(Ok((7, 9))));
+        assert_eq!(parse_range("bytes=8-20", 10), Some(Ok((8, 9))));
+        assert_eq!(parse_range("bytes=10-", 10), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-1,4-5", 10), None);
+        assert_eq!(parse_range("items=0-1", 10), None);
+    }
+
+    #[test]
+    fn compare_tokens() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+
+    #[test]
+    fn connection_limit() {
+        let server = Server::bind("127.0.0.1:0", ".").unwrap();
+        let connections = (0..MAX_CONNECTIONS)
+            .map(|_| Connection::open(&server.config).unwrap())
+            .collect::<Vec<_>>();
+        assert!(Connection::open(&server.config).is_none());
+        drop(connections);
+        assert!(Connection::open(&server.config).is_some());
+    }
+
+    #[test]
+    fn serve() {
+        let dir = TempDir::new("dhl-test-serve").unwrap();
+        File::create(dir.path().join("priv.tar.gz"))
+            .unwrap()
+            .write_all(b"0123456789")
+            .unwrap();
+        File::create(dir.path().join(".priv.tar.gz.0.tmp")).unwrap();
+        let addr = start(Server::bind("127.0.0.1:0", dir.path()).unwrap());
+
+        let (status, head, body) = send(addr, "GET /priv.tar.gz HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert_eq!(status, 200);
+        assert_eq!(body, b"0123456789");
+        let etag = header(&head, "ETag").unwrap().to_owned();
+
+        let (status, head, body) = send(
+            addr,
+            "GET /priv.tar.gz HTTP/1.1\r\nRange: bytes=2-4\r\n\r\n",
+        );
+        assert_eq!(status, 206);
+        assert_eq!(header(&head, "Content-Range"), Some("bytes 2-4/10"));
+        assert_eq!(body, b"234");
+
+        let request = format!("GET /priv.tar.gz HTTP/1.1\r\nIf-None-Match: {}\r\n\r\n", etag);
+        assert_eq!(send(addr, &request).0, 304);
+        let request = "GET /priv.tar.gz HTTP/1.1\r\nRange: bytes=2-4\r\nIf-Range: \"old\"\r\n\r\n";
+        assert_eq!(send(addr, request).0, 200);
+        assert_eq!(send(addr, "GET /priv.tar.gz HTTP/1.1\r\nRange: bytes=20-\r\n\r\n").0, 416);
+
+        let (status, _, body) = send(addr, "HEAD /priv.tar.gz HTTP/1.1\r\n\r\n");
+        assert_eq!((status, body.len()), (200, 0));
+        assert_eq!(send(addr, "GET /missing.tar.gz HTTP/1.1\r\n\r\n").0, 404);
+        assert_eq!(send(addr, "GET /../priv.tar.gz HTTP/1.1\r\n\r\n").0, 404);
+        assert_eq!(send(addr, "GET /%2e%2e/priv.tar.gz HTTP/1.1\r\n\r\n").0, 404);
+        assert_eq!(send(addr, "GET /.priv.tar.gz.0.tmp HTTP/1.1\r\n\r\n").0, 404);
+        assert_eq!(send(addr, "PUT /priv.tar.gz HTTP/1.1\r\nContent-Length: 0\r\n\r\n").0, 405);
+    }
+
+    #[test]
+    fn serve_token_and_put() {
+        let dir = TempDir::new("dhl-test-serve").unwrap();
+        let server = Server::bind("127.0.0.1:0", dir.path())
+            .unwrap()
+            .token(Some("secret".into()))
+            .writable(true);
+        let addr = start(server);
+
+        let put = |conditions: &str, body: &str| {
+            send(
+                addr,
+                &format!(
+                    "PUT /sub/index.toml HTTP/1.1\r\nAuthorization: Bearer secret\r\n{}\
+                     Content-Length: {}\r\n\r\n{}",
+                    conditions,
+                    body.len(),
+                    body
+                ),
+            )
+        };
+
+        assert_eq!(send(addr, "GET /sub/index.toml HTTP/1.1\r\n\r\n").0, 401);
+        let (status, head, _) = put("If-None-Match: *\r\n", "first");
+        assert_eq!(status, 201);
+        let etag = header(&head, "ETag").unwrap().to_owned();
+        assert_eq!(put("If-None-Match: *\r\n", "again").0, 412);
+        assert_eq!(put("If-Match: \"old\"\r\n", "again").0, 412);
+        assert_eq!(put(&format!("If-Match: {}\r\n", etag), "second").0, 200);
+
+        let (status, _, body) = send(
+            addr,
+            "GET /sub/index.toml HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n",
+        );
+        assert_eq!((status, body), (200, b"second".to_vec()));
+    }
+}
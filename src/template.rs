@@ -1,61 +1,141 @@
-use std::collections::HashMap;
-use std::env::{VarError, var};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::env::VarError;
+#[cfg(feature = "rustc_version")]
+use std::env::var_os;
+#[cfg(feature = "rustc_version")]
+use std::io;
 
+#[cfg(feature = "handlebars")]
 use serde::{Serialize, Serializer};
+#[cfg(feature = "handlebars")]
 use serde::ser::SerializeMap;
-use handlebars::{Handlebars, TemplateRenderError};
+#[cfg(feature = "handlebars")]
+use handlebars::{Handlebars, HelperDef};
+#[cfg(feature = "handlebars")]
+pub use handlebars::TemplateRenderError;
 #[cfg(feature = "rustc_version")]
-use rustc_version::{Error as RustcError, version_meta};
+use rustc_version::{Error as RustcError, version_meta_for};
 
+use env::Env;
+#[cfg(feature = "handlebars")]
+use helpers;
+#[cfg(feature = "rustc_version")]
+use rustc_verbose_version;
 use manifest::Substitution;
 
 
 quick_error! {
     #[derive(Debug)]
     pub enum TemplateGenerationError {
+        #[cfg(feature = "rustc_version")]
+        RustcCommandError(err: io::Error) {
+            description("rustc command error")
+            display("Unable to run rustc: {}", err)
+            cause(err)
+        }
         #[cfg(feature = "rustc_version")]
         RustVersionError(err: RustcError) {
-            from()
             description("rustc version error")
             display("Unable to determine rustc version '{}'", err)
         }
-        EnvError(err: VarError) {
-            from()
+        EnvError(key: String, name: String, err: VarError) {
             description("environment variable error")
-            display("Unable to read environment variable '{}'", err)
+            display("Unable to read environment variable '{}' for substitution '{}': {}",
+                name, key, err)
+            cause(err)
+        }
+        MissingEnv(key: String, names: Vec<String>) {
+            description("environment variable missing")
+            display("Substitution '{}' requires one of the environment variables '{}'",
+                key, names.join("', '"))
+        }
+        SubstitutionCycle(cycle: Vec<String>) {
+            description("substitutions reference each other in a loop")
+            display("Substitutions reference each other in a loop: {}", cycle.join(" -> "))
         }
     }
 }
+#[cfg(not(feature = "handlebars"))]
+quick_error! {
+    /// Errors of the minimal engine used without the `handlebars` feature.
+    #[derive(Debug)]
+    pub enum TemplateRenderError {
+        Unclosed(template: String) {
+            description("unclosed template expression")
+            display("'{}' has a '{{{{' without a matching '}}}}'", template)
+        }
+        Unsupported(template: String, expression: String) {
+            description("unsupported template expression")
+            display("'{{{{{}}}}}' in '{}' needs the `handlebars` feature, only variables are supported",
+                expression, template)
+        }
+    }
+}
+
+
+/// A value in the template context.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Value {
+    String(String),
+    Bool(bool),
+    List(Vec<String>),
+    #[cfg_attr(not(feature = "rustc_version"), allow(dead_code))]
+    Map(BTreeMap<String, Value>),
+}
 
+impl<'a> From<&'a str> for Value {
+    fn from(s: &'a str) -> Self {
+        Value::String(s.to_owned())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
 
+#[cfg(not(feature = "handlebars"))]
+impl Value {
+    /// Renders the value the way handlebars does.
+    fn render(&self) -> String {
+        match *self {
+            Value::String(ref s) => s.clone(),
+            Value::Bool(b) => b.to_string(),
+            Value::List(ref l) => format!("[{}]", l.join(", ")),
+            Value::Map(_) => "[object]".to_owned(),
+        }
+    }
+}
+
+
+#[cfg(feature = "handlebars")]
 struct Data<'a> {
     version: Option<&'a str>,
-    substitutions: &'a HashMap<String, String>,
+    context: &'a BTreeMap<String, Value>,
 }
 
 
+#[cfg(feature = "handlebars")]
 impl<'a> Serialize for Data<'a> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let (version, count) = match self.version {
-            Some(v) => {
-                if let Some(v) = self.substitutions.get("version") {
-                    (Some(v.as_ref()), 0)
-                } else {
-                    (Some(v), 1)
-                }
-            }
-            None => (None, 0),
+        // A `version` substitution takes precedence over the package's
+        let version = match self.version {
+            Some(_) if self.context.contains_key("version") => None,
+            version => version,
         };
-        let mut map = serializer.serialize_map(
-            Some(self.substitutions.len() + count),
-        )?;
+        let count = if version.is_some() { 1 } else { 0 };
+        let mut map = serializer.serialize_map(Some(self.context.len() + count))?;
         if let Some(version) = version {
             map.serialize_entry("version", version)?;
         }
-        for (k, v) in self.substitutions {
+        for (k, v) in self.context {
             map.serialize_entry(k, v)?;
         }
         map.end()
@@ -63,67 +143,693 @@ impl<'a> Serialize for Data<'a> {
 }
 
 
+/// A variable a template uses that isn't defined.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UndefinedVariable {
+    /// The variable, e.g. `targt` or `rustc.semverr`.
+    pub name: String,
+    /// The template that uses it, which is a substitution if the variable
+    /// is used indirectly.
+    pub template: String,
+    /// Defined variables with similar names.
+    pub suggestions: Vec<String>,
+}
+
+impl fmt::Display for UndefinedVariable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "'{}' in '{}' is not defined", self.name, self.template)?;
+        if !self.suggestions.is_empty() {
+            write!(f, ", did you mean '{}'?", self.suggestions.join("', '"))?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for UndefinedVariable {
+    fn description(&self) -> &str {
+        "template uses an undefined variable"
+    }
+}
+
+
+/// The variables available to a template.
+#[derive(Debug, Clone)]
+struct Scope {
+    context: BTreeMap<String, Value>,
+    /// Substitutions that are templates themselves, in the order they're
+    /// rendered so each comes after the ones it references.
+    templates: Vec<(String, String)>,
+}
+
+/// Renders package sources, with handlebars if the feature is enabled and
+/// otherwise with a minimal engine that only substitutes `{{variables}}`.
 pub struct TemplateEngine {
+    #[cfg(feature = "handlebars")]
     engine: Handlebars,
-    substitutions: HashMap<String, String>,
+    /// The built-in context, before any substitutions.
+    builtins: BTreeMap<String, Value>,
+    substitutions: HashMap<String, Substitution>,
+    scope: Scope,
+    /// Every registered helper, which aren't variables.
+    helpers: HashSet<String>,
+    env: Env,
+}
+
+/// A `TemplateEngine` with a package's own substitutions layered over the
+/// global ones.
+pub struct PackageTemplate<'a> {
+    engine: &'a TemplateEngine,
+    scope: Scope,
+}
+
+impl<'a> PackageTemplate<'a> {
+    /// Like `TemplateEngine::check`, with the package's substitutions.
+    pub fn check(&self, template: &str, version: Option<&str>) -> Result<(), UndefinedVariable> {
+        self.scope.check(template, version, &self.engine.helpers)
+    }
+
+    /// Like `TemplateEngine::render`, with the package's substitutions.
+    pub fn render(
+        &self,
+        template: &str,
+        version: Option<&str>,
+    ) -> Result<String, TemplateRenderError> {
+        self.engine.render_in(&self.scope, template, version)
+    }
 }
 
 impl TemplateEngine {
+    /// Creates an engine with the built-in context of the build script,
+    /// overridden by the user's substitutions.
     pub fn new(
         substitutions: HashMap<String, Substitution>,
     ) -> Result<Self, TemplateGenerationError> {
-        let mut resolved_subs = HashMap::new();
-        Self::register_rustc_helpers(&mut resolved_subs)?;
-        for (name, sub) in substitutions.into_iter() {
-            resolved_subs.insert(
-                name,
-                match sub {
-                    Substitution::EnvironmentVariable(key) => var(key)?,
-                    Substitution::Value(val) => val,
-                },
-            );
-        }
+        Self::with_env(substitutions, Env::process())
+    }
+
+    /// Like `new`, reading the build script context, environment variable
+    /// substitutions and the `env` helper from `env`.
+    pub fn with_env(
+        substitutions: HashMap<String, Substitution>,
+        env: Env,
+    ) -> Result<Self, TemplateGenerationError> {
+        let mut builtins = Self::build_context(&env);
+        Self::register_rustc_helpers(&mut builtins)?;
+        let scope = Self::scope(&env, builtins.clone(), substitutions.clone())?;
 
+        #[cfg(feature = "handlebars")]
+        let mut engine = Handlebars::new();
+        #[cfg(feature = "handlebars")]
+        let helpers = helpers::register(&mut engine, &env);
+        #[cfg(not(feature = "handlebars"))]
+        let helpers = HashSet::new();
         Ok(TemplateEngine {
-            engine: Handlebars::new(),
-            substitutions: resolved_subs,
+            #[cfg(feature = "handlebars")]
+            engine,
+            builtins,
+            substitutions,
+            scope,
+            helpers,
+            env,
+        })
+    }
+
+    /// Layers a package's substitutions over the global ones.
+    pub fn for_package(
+        &self,
+        substitutions: &HashMap<String, Substitution>,
+    ) -> Result<PackageTemplate, TemplateGenerationError> {
+        let scope = if substitutions.is_empty() {
+            self.scope.clone()
+        } else {
+            let mut layered = self.substitutions.clone();
+            layered.extend(substitutions.iter().map(|(k, v)| (k.clone(), v.clone())));
+            Self::scope(&self.env, self.builtins.clone(), layered)?
+        };
+        Ok(PackageTemplate {
+            engine: self,
+            scope,
         })
     }
 
+    fn scope(
+        env: &Env,
+        mut context: BTreeMap<String, Value>,
+        substitutions: HashMap<String, Substitution>,
+    ) -> Result<Scope, TemplateGenerationError> {
+        let mut templates = HashMap::new();
+        for (name, sub) in substitutions.into_iter() {
+            match sub {
+                Substitution::EnvironmentVariable {
+                    keys,
+                    default,
+                    required,
+                } => {
+                    let value = Self::read_env(env, &name, keys, default, required)?;
+                    context.insert(name, value.into());
+                }
+                Substitution::Value(val) => {
+                    // Overrides the built-in even before it's rendered
+                    context.remove(&name);
+                    templates.insert(name, val);
+                }
+            }
+        }
+        let templates = Self::order_templates(templates)?;
+        Ok(Scope { context, templates })
+    }
+
+    /// Reads the first of several environment variables that is set.
+    fn read_env(
+        env: &Env,
+        name: &str,
+        keys: Vec<String>,
+        default: Option<String>,
+        required: bool,
+    ) -> Result<String, TemplateGenerationError> {
+        for key in keys.iter() {
+            match env.var(key) {
+                Ok(value) => return Ok(value),
+                Err(VarError::NotPresent) => {}
+                Err(e) => {
+                    return Err(TemplateGenerationError::EnvError(name.to_owned(), key.clone(), e))
+                }
+            }
+        }
+        match default {
+            Some(default) => Ok(default),
+            None if required => Err(TemplateGenerationError::MissingEnv(name.to_owned(), keys)),
+            None => Ok(String::new()),
+        }
+    }
+
+    /// Sorts substitution templates so each comes after the ones it
+    /// references, failing if they reference each other in a loop.
+    fn order_templates(
+        mut templates: HashMap<String, String>,
+    ) -> Result<Vec<(String, String)>, TemplateGenerationError> {
+        fn visit(
+            name: &str,
+            templates: &HashMap<String, String>,
+            stack: &mut Vec<String>,
+            order: &mut Vec<String>,
+        ) -> Result<(), TemplateGenerationError> {
+            if order.iter().any(|n| n == name) {
+                return Ok(());
+            }
+            if let Some(i) = stack.iter().position(|n| n == name) {
+                let mut cycle = stack[i..].to_vec();
+                cycle.push(name.to_owned());
+                return Err(TemplateGenerationError::SubstitutionCycle(cycle));
+            }
+            stack.push(name.to_owned());
+            for reference in references(&templates[name]) {
+                if templates.contains_key(reference) {
+                    visit(reference, templates, stack, order)?;
+                }
+            }
+            stack.pop();
+            order.push(name.to_owned());
+            Ok(())
+        }
+
+        let mut names = templates.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        let mut order = Vec::with_capacity(names.len());
+        for name in names.iter() {
+            visit(name, &templates, &mut Vec::new(), &mut order)?;
+        }
+        Ok(
+            order
+                .into_iter()
+                .map(|name| {
+                    let template = templates.remove(&name).unwrap();
+                    (name, template)
+                })
+                .collect(),
+        )
+    }
+
+    /// The context cargo gives every build script. Variables that aren't
+    /// set, e.g. outside of a build script, are left out.
+    fn build_context(env: &Env) -> BTreeMap<String, Value> {
+        const ENV: [(&'static str, &'static str); 8] = [
+            ("target", "TARGET"),
+            ("host", "HOST"),
+            ("profile", "PROFILE"),
+            ("opt_level", "OPT_LEVEL"),
+            ("target_arch", "CARGO_CFG_TARGET_ARCH"),
+            ("target_os", "CARGO_CFG_TARGET_OS"),
+            ("target_env", "CARGO_CFG_TARGET_ENV"),
+            ("crate_name", "CARGO_PKG_NAME"),
+        ];
+
+        let mut context = BTreeMap::new();
+        for &(name, key) in ENV.iter() {
+            if let Ok(value) = env.var(key) {
+                context.insert(name.to_owned(), value.into());
+            }
+        }
+        if let Ok(debug) = env.var("DEBUG") {
+            context.insert("debug".to_owned(), Value::Bool(debug == "true"));
+        }
+
+        // Cargo sets `CARGO_FEATURE_<NAME>` for each enabled feature, with
+        // the name uppercased and `-` replaced by `_`.
+        let mut features = env.names_with_prefix("CARGO_FEATURE_")
+            .into_iter()
+            .map(|feature| feature.to_lowercase())
+            .collect::<Vec<_>>();
+        features.sort();
+        context.insert("features".to_owned(), Value::List(features));
+        context
+    }
+
     #[cfg(feature = "rustc_version")]
     fn register_rustc_helpers(
-        substitutions: &mut HashMap<String, String>,
+        context: &mut BTreeMap<String, Value>,
     ) -> Result<(), TemplateGenerationError> {
-        let version = version_meta()?;
-        substitutions.insert("rustc_short_version".into(), version.short_version_string);
+        let rustc = var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+        // Gated variants can't use quick_error's `from()`, it would generate
+        // the impls without the cfg
+        let verbose = rustc_verbose_version(&rustc).map_err(
+            TemplateGenerationError::RustcCommandError,
+        )?;
+        let version = version_meta_for(&verbose).map_err(
+            TemplateGenerationError::RustVersionError,
+        )?;
+        let llvm_version = verbose.lines().find(|line| line.starts_with("LLVM version: ")).map(
+            |line| line["LLVM version: ".len()..].trim().to_owned(),
+        );
+
+        let mut rustc = BTreeMap::new();
+        rustc.insert("semver".to_owned(), version.semver.to_string().into());
+        rustc.insert(
+            "channel".to_owned(),
+            format!("{:?}", version.channel).to_lowercase().into(),
+        );
+        rustc.insert(
+            "short_version".to_owned(),
+            version.short_version_string.clone().into(),
+        );
+        if let Some(commit_hash) = version.commit_hash {
+            rustc.insert("commit_hash".to_owned(), commit_hash.into());
+        }
+        if let Some(llvm_version) = llvm_version {
+            rustc.insert("llvm_version".to_owned(), llvm_version.into());
+        }
+
+        context.insert(
+            "rustc_short_version".to_owned(),
+            version.short_version_string.into(),
+        );
+        context.insert("rustc".to_owned(), Value::Map(rustc));
         Ok(())
     }
 
     #[cfg(not(feature = "rustc_version"))]
-    fn register_rustc_helpers(substitutions: &mut HashMap<String, String>) {}
+    fn register_rustc_helpers(
+        _context: &mut BTreeMap<String, Value>,
+    ) -> Result<(), TemplateGenerationError> {
+        Ok(())
+    }
+
+    /// Registers a helper for use in sources, replacing any built-in helper
+    /// of the same name.
+    #[cfg(feature = "handlebars")]
+    pub fn register_helper(&mut self, name: &str, helper: Box<HelperDef + 'static>) {
+        self.engine.register_helper(name, helper);
+        self.helpers.insert(name.to_owned());
+    }
+
+    /// Checks that every variable a template uses, directly or through
+    /// substitutions, is defined. Variables inside `each` and `with` blocks
+    /// aren't checked, since they're relative to the block.
+    pub fn check(&self, template: &str, version: Option<&str>) -> Result<(), UndefinedVariable> {
+        self.scope.check(template, version, &self.helpers)
+    }
 
+    /// Renders a template, after rendering the substitutions it may use.
     pub fn render(
         &self,
         template: &str,
         version: Option<&str>,
+    ) -> Result<String, TemplateRenderError> {
+        self.render_in(&self.scope, template, version)
+    }
+
+    fn render_in(
+        &self,
+        scope: &Scope,
+        template: &str,
+        version: Option<&str>,
+    ) -> Result<String, TemplateRenderError> {
+        let mut context = scope.context.clone();
+        for &(ref name, ref substitution) in scope.templates.iter() {
+            let value = self.render_with(substitution, version, &context)?;
+            context.insert(name.clone(), value.into());
+        }
+        self.render_with(template, version, &context)
+    }
+
+    #[cfg(feature = "handlebars")]
+    fn render_with(
+        &self,
+        template: &str,
+        version: Option<&str>,
+        context: &BTreeMap<String, Value>,
     ) -> Result<String, TemplateRenderError> {
         self.engine.template_render(
             template,
-            &Data {
-                version,
-                substitutions: &self.substitutions,
-            },
+            &Data { version, context },
         )
     }
+
+    #[cfg(not(feature = "handlebars"))]
+    fn render_with(
+        &self,
+        template: &str,
+        version: Option<&str>,
+        context: &BTreeMap<String, Value>,
+    ) -> Result<String, TemplateRenderError> {
+        let mut rendered = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find("{{") {
+            rendered.push_str(&rest[..start]);
+            rest = &rest[start + 2..];
+            let end = match rest.find("}}") {
+                Some(end) => end,
+                None => return Err(TemplateRenderError::Unclosed(template.to_owned())),
+            };
+            let expression = rest[..end].trim();
+            rest = &rest[end + 2..];
+
+            let is_variable = !expression.is_empty() &&
+                expression.chars().all(|c| {
+                    c.is_alphanumeric() || c == '_' || c == '-' || c == '.' || c == '/'
+                });
+            if !is_variable {
+                return Err(TemplateRenderError::Unsupported(
+                    template.to_owned(),
+                    expression.to_owned(),
+                ));
+            }
+            if let Some(value) = lookup(context, version, expression) {
+                rendered.push_str(&value);
+            }
+        }
+        rendered.push_str(rest);
+        Ok(rendered)
+    }
+}
+
+impl Scope {
+    /// Checks that every variable a template uses, directly or through
+    /// substitutions, is defined.
+    fn check(
+        &self,
+        template: &str,
+        version: Option<&str>,
+        helpers: &HashSet<String>,
+    ) -> Result<(), UndefinedVariable> {
+        for name in variables(template, helpers) {
+            let mut path = name.split(|c| c == '.' || c == '/');
+            let first = path.next().unwrap_or(name);
+
+            if let Some(&(_, ref substitution)) = self.templates.iter().find(|t| t.0 == first) {
+                if path.next().is_none() {
+                    self.check(substitution, version, helpers)?;
+                    continue;
+                }
+            } else if first == "version" && version.is_some() &&
+                       !self.context.contains_key("version")
+            {
+                if path.next().is_none() {
+                    continue;
+                }
+            } else if let Some(value) = self.context.get(first) {
+                let mut value = value;
+                let mut found = true;
+                for segment in path {
+                    match *value {
+                        Value::Map(ref map) if map.contains_key(segment) => value = &map[segment],
+                        _ => {
+                            found = false;
+                            break;
+                        }
+                    }
+                }
+                if found {
+                    continue;
+                }
+            }
+
+            return Err(UndefinedVariable {
+                name: name.to_owned(),
+                template: template.to_owned(),
+                suggestions: self.suggestions(name, version),
+            });
+        }
+        Ok(())
+    }
+
+    /// Defined variables with names close to `name`.
+    fn suggestions(&self, name: &str, version: Option<&str>) -> Vec<String> {
+        let mut defined = Vec::new();
+        for (k, v) in self.context.iter() {
+            defined.push(k.clone());
+            if let Value::Map(ref map) = *v {
+                defined.extend(map.keys().map(|m| format!("{}.{}", k, m)));
+            }
+        }
+        defined.extend(self.templates.iter().map(|t| t.0.clone()));
+        if version.is_some() {
+            defined.push("version".to_owned());
+        }
+
+        let max = if name.len() < 3 { 1 } else { name.len() / 3 };
+        let mut close = defined
+            .into_iter()
+            .map(|d| (distance(name, &d), d))
+            .filter(|&(distance, _)| distance <= max)
+            .collect::<Vec<_>>();
+        close.sort();
+        close.dedup();
+        close.into_iter().take(3).map(|(_, d)| d).collect()
+    }
+}
+
+/// Renders the value of a variable, if it's defined.
+#[cfg(not(feature = "handlebars"))]
+fn lookup(context: &BTreeMap<String, Value>, version: Option<&str>, path: &str) -> Option<String> {
+    let mut segments = path.split(|c| c == '.' || c == '/');
+    let first = segments.next().unwrap_or(path);
+    if first == "version" && !context.contains_key("version") {
+        return match segments.next() {
+            Some(_) => None,
+            None => version.map(ToOwned::to_owned),
+        };
+    }
+
+    let mut value = match context.get(first) {
+        Some(value) => value,
+        None => return None,
+    };
+    for segment in segments {
+        value = match *value {
+            Value::Map(ref map) if map.contains_key(segment) => &map[segment],
+            _ => return None,
+        };
+    }
+    Some(value.render())
+}
+
+/// The Levenshtein distance between two strings.
+fn distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..b.len() + 1).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitute = previous + if ca == cb { 0 } else { 1 };
+            previous = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(previous + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// The expressions of a template, without their `{{` and `}}`.
+fn expressions(template: &str) -> Vec<&str> {
+    let mut expressions = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rest = &rest[start + 2..];
+        let end = rest.find("}}").unwrap_or_else(|| rest.len());
+        expressions.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    expressions
+}
+
+/// Splits an expression into words, keeping quoted strings whole. Words
+/// that name a helper in a subexpression are marked `true`.
+fn words(expression: &str) -> Vec<(&str, bool)> {
+    let mut words = Vec::new();
+    let mut start = None;
+    let mut quote = None;
+    let mut helper = false;
+    for (i, c) in expression.char_indices() {
+        if let Some(q) = quote {
+            if c == q {
+                quote = None;
+            }
+            continue;
+        }
+        if c.is_whitespace() || c == '(' || c == ')' {
+            if let Some(s) = start.take() {
+                words.push((&expression[s..i], helper));
+                helper = false;
+            }
+            if c == '(' {
+                helper = true;
+            }
+            continue;
+        }
+        if c == '"' || c == '\'' {
+            quote = Some(c);
+        }
+        if start.is_none() {
+            start = Some(i);
+        }
+    }
+    if let Some(s) = start {
+        words.push((&expression[s..], helper));
+    }
+    words
+}
+
+/// A word of a template expression that can name a variable or helper.
+#[derive(Debug, Clone, Copy)]
+struct Word<'a> {
+    /// The word, e.g. `target` or `rustc.semver`, without the key of a
+    /// `key=value` argument.
+    name: &'a str,
+    /// Whether it's called, as the helper of an expression or
+    /// subexpression.
+    helper: bool,
+    /// Whether it's inside an `each` or `with` block, so relative to the
+    /// block rather than to the template's context.
+    scoped: bool,
+    /// Whether it may be undefined, as the first parameter of `default`.
+    optional: bool,
+}
+
+/// Parses the expressions of a template into the words that can name a
+/// variable or helper. Comments, partials, closing tags, literals, `@data`
+/// variables and block params are left out.
+fn parse(template: &str) -> Vec<Word> {
+    let mut parsed = Vec::new();
+    let mut block_params = Vec::new();
+    let mut scoped = 0usize;
+    for expression in expressions(template) {
+        let expression = expression
+            .trim_matches(|c: char| c == '~' || c == '{' || c == '}' || c == '&')
+            .trim();
+        if expression.starts_with('!') || expression.starts_with('>') {
+            continue;
+        }
+        if expression.starts_with('/') {
+            let name = expression[1..].trim();
+            if name == "each" || name == "with" {
+                scoped = scoped.saturating_sub(1);
+            }
+            continue;
+        }
+        let block = expression.starts_with('#') || expression.starts_with('^');
+        let expression = expression.trim_start_matches(|c| c == '#' || c == '^');
+
+        let mut words = words(expression);
+        if let Some(i) = words.iter().position(|&(w, _)| w == "as") {
+            block_params.extend(words[i + 1..].iter().map(|&(w, _)| w.trim_matches('|')));
+            words.truncate(i);
+        }
+        let opens_scope = block && words.first().map_or(false, |&(w, _)| w == "each" || w == "with");
+        let called = block || words.len() > 1;
+
+        for (i, &(word, helper)) in words.iter().enumerate() {
+            let name = match word.find('=') {
+                Some(eq) => &word[eq + 1..],
+                None => word,
+            };
+            let first = first_segment(name);
+            let literal = name.starts_with(|c: char| c == '"' || c == '\'' || c.is_digit(10)) ||
+                ["true", "false", "null", "undefined", "this"].contains(&first);
+            let special = first.is_empty() || first.starts_with('@') || first.starts_with('[');
+            if literal || special || block_params.contains(&first) {
+                continue;
+            }
+            let optional = i > 0 && words[i - 1].0 == "default" &&
+                (words[i - 1].1 || (i == 1 && called));
+            parsed.push(Word {
+                name,
+                helper: helper || (i == 0 && called),
+                scoped: scoped > 0,
+                optional,
+            });
+        }
+        if opens_scope {
+            scoped += 1;
+        }
+    }
+    parsed
+}
+
+/// The first segment of a path like `rustc.semver` or `rustc/semver`.
+fn first_segment(path: &str) -> &str {
+    path.split(|c| c == '.' || c == '/').next().unwrap_or(path)
+}
+
+/// The variables a template reads, e.g. `target` or `rustc.semver`,
+/// outside of `each` and `with` blocks, and that it needs defined.
+fn variables<'a>(template: &'a str, helpers: &HashSet<String>) -> Vec<&'a str> {
+    const BUILT_IN_HELPERS: [&'static str; 7] =
+        ["if", "unless", "each", "with", "lookup", "log", "else"];
+
+    parse(template)
+        .into_iter()
+        .filter(|word| {
+            !word.helper && !word.scoped && !word.optional &&
+                !BUILT_IN_HELPERS.contains(&word.name) &&
+                !helpers.contains(word.name)
+        })
+        .map(|word| word.name)
+        .collect()
+}
+
+/// The names of the variables a template may reference, over-approximated
+/// as the first segment of every word, including helpers, words inside
+/// blocks and the optional parameter of `default`, which still has to be
+/// rendered first if it's a substitution.
+fn references(template: &str) -> Vec<&str> {
+    parse(template)
+        .into_iter()
+        .map(|word| first_segment(word.name))
+        .collect()
 }
 
 #[cfg(test)]
 mod test {
-    use std::env::{set_var, remove_var};
     use std::collections::HashMap;
 
+    use env::Env;
     use manifest::Substitution;
-    use super::TemplateEngine;
+    use super::{TemplateEngine, TemplateGenerationError, references, variables};
+
+    fn env(vars: &[(&str, &str)]) -> Env {
+        Env::with_vars(vars.iter().cloned())
+    }
 
     fn test_simple(t: &TemplateEngine) {
         assert_eq!(t.render("", None).unwrap(), "");
@@ -165,22 +871,295 @@ mod test {
 
     #[test]
     fn test_env() {
-        set_var("DHL_TEST_ENV_VAR", "dhl_test_env_val");
-
         let mut map = HashMap::new();
         map.insert(
             "dhl_var".to_owned(),
-            Substitution::EnvironmentVariable("DHL_TEST_ENV_VAR".to_owned()),
+            Substitution::EnvironmentVariable {
+                keys: vec!["DHL_TEST_ENV_VAR".to_owned()],
+                default: None,
+                required: true,
+            },
         );
 
-        let t = TemplateEngine::new(map).unwrap();
+        let t = TemplateEngine::with_env(map, env(&[("DHL_TEST_ENV_VAR", "dhl_test_env_val")]))
+            .unwrap();
         test_simple(&t);
         assert_eq!(t.render("{{dhl_var}}", None).unwrap(), "dhl_test_env_val");
         assert_eq!(
             t.render("foo{{dhl_var}}", None).unwrap(),
             "foodhl_test_env_val"
         );
+    }
+
+    #[test]
+    fn test_context() {
+        let vars = env(&[
+            ("CARGO_CFG_TARGET_OS", "dhl_test_os"),
+            ("CARGO_CFG_TARGET_ARCH", "dhl_test_arch"),
+            ("CARGO_FEATURE_DHL_TEST_FEATURE", "1"),
+            ("DEBUG", "true"),
+        ]);
+
+        let mut map = HashMap::new();
+        map.insert(
+            "target_arch".to_owned(),
+            Substitution::Value("dhl_test_override".to_owned()),
+        );
+
+        let t = TemplateEngine::with_env(map, vars).unwrap();
+        test_simple(&t);
+        assert_eq!(t.render("{{target_os}}", None).unwrap(), "dhl_test_os");
+        assert_eq!(
+            t.render("{{target_arch}}", None).unwrap(),
+            "dhl_test_override"
+        );
+        if cfg!(feature = "handlebars") {
+            assert_eq!(
+                t.render("{{#if debug}}debug{{/if}}", None).unwrap(),
+                "debug"
+            );
+            assert!(
+                t.render("{{#each features}}[{{this}}]{{/each}}", None)
+                    .unwrap()
+                    .contains("[dhl_test_feature]")
+            );
+        } else {
+            assert_eq!(t.render("{{debug}}", None).unwrap(), "true");
+            assert!(t.render("{{features}}", None).unwrap().contains("dhl_test_feature"));
+        }
+        if cfg!(feature = "rustc_version") {
+            assert!(!t.render("{{rustc.semver}}", None).unwrap().is_empty());
+            assert!(!t.render("{{rustc.channel}}", None).unwrap().is_empty());
+        }
+    }
+
+    #[cfg(feature = "handlebars")]
+    #[test]
+    fn test_helpers() {
+        let mut map = HashMap::new();
+        map.insert(
+            "triple".to_owned(),
+            Substitution::Value("x86_64-pc-windows-msvc".to_owned()),
+        );
+        map.insert("mode".to_owned(), Substitution::Value("Release".to_owned()));
 
-        remove_var("DHL_TEST_ENV_VAR");
+        let t = TemplateEngine::with_env(map, env(&[("DHL_TEST_HELPER_VAR", "dhl_helper_val")]))
+            .unwrap();
+        assert_eq!(
+            t.render(r#"{{replace triple "x86_64-pc-windows-msvc" "win64"}}"#, None)
+                .unwrap(),
+            "win64"
+        );
+        assert_eq!(t.render("{{lower mode}}", None).unwrap(), "release");
+        assert_eq!(t.render("{{upper mode}}", None).unwrap(), "RELEASE");
+        assert_eq!(
+            t.render("{{semver_major version}}.{{semver_minor version}}", Some("^3.2.1"))
+                .unwrap(),
+            "3.2"
+        );
+        assert_eq!(t.render("{{semver_minor version}}", Some("3")).unwrap(), "0");
+        assert!(t.render("{{semver_major version}}", Some("*")).is_err());
+        assert_eq!(
+            t.render(r#"{{default dhl_missing "fallback"}}"#, None).unwrap(),
+            "fallback"
+        );
+        assert_eq!(
+            t.render(r#"{{default mode "fallback"}}"#, None).unwrap(),
+            "Release"
+        );
+        assert_eq!(
+            t.render(r#"{{#if (eq mode "Release")}}rel{{else}}dbg{{/if}}"#, None)
+                .unwrap(),
+            "rel"
+        );
+        assert_eq!(
+            t.render(r#"{{#if (eq mode "Debug")}}dbg{{else}}rel{{/if}}"#, None)
+                .unwrap(),
+            "rel"
+        );
+        assert_eq!(
+            t.render(r#"{{env "DHL_TEST_HELPER_VAR"}}"#, None).unwrap(),
+            "dhl_helper_val"
+        );
+        assert_eq!(
+            t.render(r#"{{env "DHL_TEST_MISSING_VAR" "none"}}"#, None)
+                .unwrap(),
+            "none"
+        );
+        // A bare `{{lower}}` is looked up as a variable, never called
+        assert!(t.render("{{lower mode mode}}", None).is_err());
+    }
+
+    #[test]
+    fn test_nested_substitutions() {
+        let mut map = HashMap::new();
+        map.insert(
+            "base".to_owned(),
+            Substitution::Value("libs/{{os}}".to_owned()),
+        );
+        map.insert(
+            "archive".to_owned(),
+            Substitution::Value(if cfg!(feature = "handlebars") {
+                "{{base}}/{{version}}/{{lower mode}}".to_owned()
+            } else {
+                "{{base}}/{{version}}/{{mode}}".to_owned()
+            }),
+        );
+        map.insert("os".to_owned(), Substitution::Value("linux".to_owned()));
+        map.insert(
+            "mode".to_owned(),
+            Substitution::Value(if cfg!(feature = "handlebars") {
+                "Release".to_owned()
+            } else {
+                "release".to_owned()
+            }),
+        );
+
+        let t = TemplateEngine::new(map).unwrap();
+        assert_eq!(
+            t.render("{{archive}}/exported.tar.gz", Some("1.0")).unwrap(),
+            "libs/linux/1.0/release/exported.tar.gz"
+        );
+    }
+
+    #[test]
+    fn test_substitution_cycle() {
+        let mut map = HashMap::new();
+        map.insert("a".to_owned(), Substitution::Value("{{b}}".to_owned()));
+        map.insert(
+            "b".to_owned(),
+            Substitution::Value("{{#if c}}{{c.x}}{{/if}}".to_owned()),
+        );
+        map.insert("c".to_owned(), Substitution::Value("{{a}}".to_owned()));
+        match TemplateEngine::new(map) {
+            Err(TemplateGenerationError::SubstitutionCycle(cycle)) => {
+                assert_eq!(cycle, vec!["a", "b", "c", "a"]);
+            }
+            _ => panic!("expected a cycle"),
+        }
+
+        let mut map = HashMap::new();
+        map.insert(
+            "a".to_owned(),
+            Substitution::Value(r#"{{replace "a" "a" "b"}}{{a}}"#.to_owned()),
+        );
+        assert!(TemplateEngine::new(map).is_err());
+    }
+
+    #[test]
+    fn test_references() {
+        assert_eq!(
+            references(r#"x{{a}}y{{#if (eq b.c "d")}}{{{e}}}{{else}}{{/if}}{{f k=g}}"#),
+            vec!["a", "if", "eq", "b", "e", "else", "f", "g"]
+        );
+        // Substitutions passed to `default` are still rendered first
+        assert_eq!(references(r#"{{default a "x"}}"#), vec!["default", "a"]);
+    }
+
+    #[test]
+    fn test_variables() {
+        let helpers = ["lower".to_owned()].iter().cloned().collect();
+        assert_eq!(
+            variables(
+                r#"{{a}}/{{lower b}}/{{#if (eq c.d "e")}}{{f}}{{else}}{{g k=h}}{{/if}}"#,
+                &helpers,
+            ),
+            vec!["a", "b", "c.d", "f", "h"]
+        );
+        assert_eq!(
+            variables(
+                "{{#each features}}{{this}}{{x}}{{/each}}{{{y}}}{{! z}}{{@index}}{{lower}}",
+                &helpers,
+            ),
+            vec!["features", "y"]
+        );
+        assert_eq!(
+            variables("{{#each a as |item|}}{{/each}}{{item.x}}", &helpers),
+            vec!["a"]
+        );
+        let helpers = ["lower".to_owned(), "default".to_owned()].iter().cloned().collect();
+        assert_eq!(
+            variables(r#"{{default a "x"}}/{{lower (default b c)}}/{{default}}"#, &helpers),
+            vec!["c"]
+        );
+    }
+
+    #[test]
+    fn test_strict() {
+        let mut map = HashMap::new();
+        map.insert(
+            "base".to_owned(),
+            Substitution::Value("libs/{{targt}}".to_owned()),
+        );
+        map.insert("target".to_owned(), Substitution::Value("x86".to_owned()));
+        map.insert("mode".to_owned(), Substitution::Value("Release".to_owned()));
+
+        let t = TemplateEngine::new(map).unwrap();
+        t.check("{{target}}/{{mode}}/{{version}}", Some("1.0")).unwrap();
+        if cfg!(feature = "handlebars") {
+            t.check("{{lower mode}}", None).unwrap();
+            t.check(r#"{{default dhl_missing "x"}}/{{default mode "x"}}"#, None).unwrap();
+            assert!(t.check(r#"{{default dhl_missing dhl_other}}"#, None).is_err());
+        }
+        assert!(t.check("{{version}}", None).is_err());
+
+        let err = t.check("{{targte}}/{{mode}}", None).unwrap_err();
+        assert_eq!(err.name, "targte");
+        assert_eq!(err.template, "{{targte}}/{{mode}}");
+        assert_eq!(err.suggestions, vec!["target"]);
+
+        let err = t.check("{{base}}/{{mode}}", None).unwrap_err();
+        assert_eq!(err.name, "targt");
+        assert_eq!(err.template, "libs/{{targt}}");
+
+        if cfg!(feature = "rustc_version") {
+            t.check("{{rustc.semver}}", None).unwrap();
+            let err = t.check("{{rustc.semverr}}", None).unwrap_err();
+            assert_eq!(err.suggestions, vec!["rustc.semver"]);
+        }
+    }
+
+    #[test]
+    fn test_env_fallbacks() {
+        let render = |keys: &[&str], default: Option<&str>, required: bool| {
+            let mut map = HashMap::new();
+            map.insert(
+                "var".to_owned(),
+                Substitution::EnvironmentVariable {
+                    keys: keys.iter().map(|&k| k.to_owned()).collect(),
+                    default: default.map(ToOwned::to_owned),
+                    required,
+                },
+            );
+            TemplateEngine::with_env(map, env(&[("DHL_TEST_FALLBACK_B", "b")]))
+                .map(|t| t.render("{{var}}", None).unwrap())
+        };
+
+        let keys = ["DHL_TEST_FALLBACK_A", "DHL_TEST_FALLBACK_B"];
+        assert_eq!(render(&keys, None, true).unwrap(), "b");
+        assert_eq!(render(&keys[..1], Some("c"), true).unwrap(), "c");
+        assert_eq!(render(&keys[..1], None, false).unwrap(), "");
+        match render(&keys[..1], None, true) {
+            Err(TemplateGenerationError::MissingEnv(key, names)) => {
+                assert_eq!(key, "var");
+                assert_eq!(names, vec!["DHL_TEST_FALLBACK_A"]);
+            }
+            _ => panic!("expected a missing variable"),
+        }
+    }
+
+    #[cfg(not(feature = "handlebars"))]
+    #[test]
+    fn test_minimal_engine() {
+        let mut map = HashMap::new();
+        map.insert("dir".to_owned(), Substitution::Value("libs".to_owned()));
+        let t = TemplateEngine::new(map).unwrap();
+        assert_eq!(
+            t.render("{{ dir }}/{{rustc.nothing}}/{{undefined}}x", None).unwrap(),
+            "libs//x"
+        );
+        assert!(t.render("{{dir", None).is_err());
+        assert!(t.render("{{#if dir}}{{/if}}", None).is_err());
+        assert!(t.render("{{lower dir}}", None).is_err());
     }
 }
@@ -0,0 +1,381 @@
+use std::collections::{HashMap, HashSet};
+use std::env::{var_os, vars_os};
+use std::ffi::{OsStr, OsString};
+use std::fs::{File, create_dir_all, remove_file, rename};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use reqwest::{self, Client as HttpClient, Method, Request, StatusCode, Url, UrlError};
+use reqwest::header::{Authorization, Bearer};
+
+use env::Env;
+use index::{Build, IndexError};
+use manifest::{Manifest, ManifestCreationError, ManifestInspectionError, PackageData, Substitution,
+               vendor_path};
+use template::TemplateGenerationError;
+use {cfg_vars, run_rustc, rustc_verbose_version};
+
+/// The directory `dhl vendor` vendors into by default, relative to the
+/// package.
+pub const VENDOR_DIR_NAME: &'static str = "dhl-vendor";
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum VendorError {
+        ManifestCreation(err: ManifestCreationError) {
+            from()
+            description("manifest creation error")
+            display("Failed to read the manifest: {}", err)
+            cause(err)
+        }
+        ManifestInspection(err: ManifestInspectionError) {
+            from()
+            description("manifest inspection error")
+            display("Failed to inspect the manifest: {}", err)
+            cause(err)
+        }
+        TemplateGeneration(err: TemplateGenerationError) {
+            from()
+            description("template failed to generate")
+            display("Template generation error: {}", err)
+            cause(err)
+        }
+        Rustc(rustc: OsString, err: io::Error) {
+            description("failed to run rustc")
+            display("Failed to run '{}': {}", Path::new(rustc).display(), err)
+            cause(err)
+        }
+        RustcOutput(rustc: OsString) {
+            description("unexpected rustc output")
+            display("Could not find the host in the output of '{} -vV'", Path::new(rustc).display())
+        }
+        Unsupported(url: String) {
+            description("source can't be vendored")
+            display("Can't vendor '{}', only http and https urls can be vendored", url)
+        }
+        Index(err: IndexError) {
+            from()
+            description("index error")
+            display("Index error: {}", err)
+            cause(err)
+        }
+        Url(url: String, err: UrlError) {
+            description("vendor url error")
+            display("Failed to parse url '{}': {}", url, err)
+            cause(err)
+        }
+        Http(url: String, err: reqwest::Error) {
+            description("vendor download error")
+            display("Failed to download '{}': {}", url, err)
+            cause(err)
+        }
+        HttpRead(url: String, err: io::Error) {
+            description("vendor download error")
+            display("Failed to download '{}': {}", url, err)
+            cause(err)
+        }
+        HttpStatus(url: String, status: StatusCode) {
+            description("vendor download error")
+            display("Failed to download '{}': {}", url, status)
+        }
+        Write(path: PathBuf, err: io::Error) {
+            description("vendor write error")
+            display("Failed to write '{}': {}", path.display(), err)
+            cause(err)
+        }
+    }
+}
+
+/// A target and profile to vendor the archives of.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cell {
+    pub target: String,
+    pub profile: String,
+}
+
+/// What `Vendor::vendor` did.
+#[derive(Debug, Clone, Default)]
+pub struct VendorReport {
+    /// The files downloaded, archives and indexes.
+    pub files: Vec<PathBuf>,
+    /// Packages without a build for a cell, as `crate for target (profile)`.
+    pub missing: Vec<String>,
+}
+
+/// Downloads the url sources of a package into a directory, so builds can
+/// use them offline with `vendor-dir`.
+#[derive(Debug, Clone)]
+pub struct Vendor {
+    manifest_dir: PathBuf,
+    dir: PathBuf,
+    substitutions: HashMap<String, String>,
+    features: Vec<String>,
+}
+
+impl Vendor {
+    /// Vendors the package in `manifest_dir` into `dir`, relative to it.
+    pub fn new<P: Into<PathBuf>, Q: AsRef<Path>>(manifest_dir: P, dir: Q) -> Self {
+        let manifest_dir = manifest_dir.into();
+        Vendor {
+            dir: manifest_dir.join(dir),
+            manifest_dir,
+            substitutions: HashMap::new(),
+            features: Vec::new(),
+        }
+    }
+
+    /// Sets a substitution for every cell, over the context of the cell.
+    pub fn substitute<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) -> &mut Self {
+        self.substitutions.insert(key.into(), value.into());
+        self
+    }
+
+    /// Enables a feature of the package for every cell.
+    pub fn feature<F: Into<String>>(&mut self, feature: F) -> &mut Self {
+        self.features.push(feature.into());
+        self
+    }
+
+    /// Resolves the packages for each cell, in the context cargo would give
+    /// the build script, and downloads their url sources and indexes.
+    pub fn vendor(&self, cells: &[Cell]) -> Result<VendorReport, VendorError> {
+        let client = HttpClient::new().map_err(|e| VendorError::Http(String::new(), e))?;
+        let rustc = var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+        let verbose = rustc_verbose_version(&rustc).map_err(
+            |e| VendorError::Rustc(rustc.clone(), e),
+        )?;
+        let host = match verbose.lines().find(|line| line.starts_with("host: ")) {
+            Some(line) => line["host: ".len()..].trim().to_owned(),
+            None => return Err(VendorError::RustcOutput(rustc)),
+        };
+        let version = verbose.lines().next().unwrap_or("").trim();
+
+        let mut fetched = HashSet::new();
+        let mut report = VendorReport::default();
+        for cell in cells {
+            let env = self.cell_env(&rustc, &host, cell)?;
+            let mut manifest = Manifest::produce_from_dir_with_env(self.manifest_dir.clone(), env)?;
+            manifest.vendor_dir = None;
+            for (k, v) in self.substitutions.iter() {
+                manifest.substitutions.insert(k.clone(), Substitution::Value(v.clone()));
+            }
+            let template = manifest.template_engine()?;
+            let build = Build::with_rustc(cell.target.clone(), cell.profile.clone(), version);
+            let packages = manifest.inspect_for(&template, build)?;
+
+            let mut packages = packages.packages.into_iter().collect::<Vec<_>>();
+            packages.sort_by(|a, b| a.0.cmp(&b.0));
+            for (crate_name, package) in packages {
+                if let Some(ref index) = package.index {
+                    self.fetch(&client, index, &mut fetched, &mut report)?;
+                }
+                match package.data {
+                    PackageData::Url(ref url) => {
+                        self.fetch(&client, url.source.as_str(), &mut fetched, &mut report)?
+                    }
+                    PackageData::Missing(_) => {
+                        report.missing.push(format!(
+                            "{} for {} ({})",
+                            crate_name,
+                            cell.target,
+                            cell.profile
+                        ))
+                    }
+                    PackageData::File(_) => {}
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// The environment cargo would give the build script when building
+    /// `cell`, over the process environment. Profiles other than `release`
+    /// and `bench` are taken to be unoptimized with debug info.
+    fn cell_env(&self, rustc: &OsStr, host: &str, cell: &Cell) -> Result<Env, VendorError> {
+        let cfg = run_rustc(rustc, &["--print", "cfg", "--target", &cell.target])
+            .map_err(|e| VendorError::Rustc(rustc.to_owned(), e))?;
+        let release = cell.profile == "release" || cell.profile == "bench";
+
+        let mut vars = vars_os()
+            .filter_map(|(k, v)| match (k.into_string(), v.into_string()) {
+                (Ok(k), Ok(v)) => Some((k, v)),
+                _ => None,
+            })
+            .filter(|&(ref k, _)| !k.starts_with("CARGO_FEATURE_") && !k.starts_with("CARGO_CFG_"))
+            .collect::<HashMap<_, _>>();
+        vars.insert("TARGET".into(), cell.target.clone());
+        vars.insert("HOST".into(), host.to_owned());
+        vars.insert("PROFILE".into(), cell.profile.clone());
+        vars.insert("OPT_LEVEL".into(), if release { "3" } else { "0" }.into());
+        vars.insert("DEBUG".into(), (!release).to_string());
+        vars.extend(cfg_vars(&cfg));
+        for feature in self.features.iter() {
+            let var = format!("CARGO_FEATURE_{}", feature.to_uppercase().replace('-', "_"));
+            vars.insert(var, "1".into());
+        }
+        Ok(Env::with_vars(vars))
+    }
+
+    /// Downloads a url into the vendor dir, once.
+    fn fetch(
+        &self,
+        client: &HttpClient,
+        url: &str,
+        fetched: &mut HashSet<String>,
+        report: &mut VendorReport,
+    ) -> Result<(), VendorError> {
+        // Local sources need no vendoring
+        let path = match vendor_path(&self.dir, url) {
+            Some(path) => path,
+            None if url.contains("://") => return Err(VendorError::Unsupported(url.to_owned())),
+            None => return Ok(()),
+        };
+        if !fetched.insert(url.to_owned()) {
+            return Ok(());
+        }
+
+        let parsed = Url::parse(url).map_err(|e| VendorError::Url(url.to_owned(), e))?;
+        let token = parsed.host_str().and_then(|host| Env::process().token(host));
+        let mut request = Request::new(Method::Get, parsed);
+        if let Some(token) = token {
+            request.headers_mut().set(Authorization(Bearer { token }));
+        }
+        let mut response = client.execute(request).map_err(
+            |e| VendorError::Http(url.to_owned(), e),
+        )?;
+        if !response.status().is_success() {
+            return Err(VendorError::HttpStatus(url.to_owned(), response.status()));
+        }
+        let mut contents = Vec::new();
+        response.read_to_end(&mut contents).map_err(|e| {
+            VendorError::HttpRead(url.to_owned(), e)
+        })?;
+
+        let write_error = |e| VendorError::Write(path.clone(), e);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent).map_err(&write_error)?;
+        }
+        let file_name = path.file_name().map_or("".into(), |f| f.to_string_lossy());
+        let tmp = path.with_file_name(format!(".{}.tmp", file_name));
+        File::create(&tmp)
+            .and_then(|mut f| f.write_all(&contents))
+            .and_then(|_| rename(&tmp, &path))
+            .map_err(|e| {
+                let _ = remove_file(&tmp);
+                write_error(e)
+            })?;
+        report.files.push(path.clone());
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::fs::{File, create_dir_all};
+    use std::io::Write;
+    use std::thread;
+
+    use tempdir::TempDir;
+
+    use manifest::{Manifest, PackageData, Substitution};
+    use serve::Server;
+    use super::{Cell, Vendor, VendorError, VENDOR_DIR_NAME};
+
+    #[test]
+    fn vendor() {
+        let dir = TempDir::new("dhl-test-vendor").unwrap();
+        let served = dir.path().join("served");
+        for profile in ["debug", "release"].iter() {
+            let build = served.join("linux").join(profile);
+            create_dir_all(&build).unwrap();
+            File::create(build.join("priv.tar.gz"))
+                .unwrap()
+                .write_all(profile.as_bytes())
+                .unwrap();
+        }
+        let server = Server::bind("127.0.0.1:0", &served).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run(|_| {}));
+
+        let package = dir.path().join("package");
+        create_dir_all(&package).unwrap();
+        File::create(package.join("Cargo.toml"))
+            .unwrap()
+            .write_all(
+                format!(
+                    r#"
+[package]
+name = "app"
+version = "0.1.0"
+authors = [""]
+
+[package.metadata.dhl.packages]
+priv = "http://{}/{{{{target_os}}}}/{{{{profile}}}}/priv.tar.gz"
+local = "./libs/local.tar.gz"
+"#,
+                    addr
+                ).as_bytes(),
+            )
+            .unwrap();
+
+        let cell = |profile: &str| {
+            Cell {
+                target: "x86_64-unknown-linux-gnu".into(),
+                profile: profile.into(),
+            }
+        };
+        let report = Vendor::new(&package, VENDOR_DIR_NAME)
+            .vendor(&[cell("debug"), cell("release"), cell("debug")])
+            .unwrap();
+        assert_eq!(report.files.len(), 2);
+        assert!(report.missing.is_empty());
+
+        let vendored = package
+            .join(VENDOR_DIR_NAME)
+            .join(addr.to_string().replace(':', "_"))
+            .join("linux");
+        assert!(vendored.join("release").join("priv.tar.gz").is_file());
+
+        let mut manifest = Manifest::produce_from_dir(&package).unwrap();
+        manifest.vendor_dir = Some(package.join(VENDOR_DIR_NAME));
+        for &(k, v) in [("target_os", "linux"), ("profile", "debug")].iter() {
+            manifest.substitutions.insert(k.into(), Substitution::Value(v.into()));
+        }
+        let packages = manifest.inspect().unwrap().packages;
+        match packages["priv"].data {
+            PackageData::File(ref f) => {
+                assert_eq!(f.source, vendored.join("debug").join("priv.tar.gz"))
+            }
+            ref data => panic!("expected the vendored file, got {:?}", data),
+        }
+    }
+
+    #[test]
+    fn vendor_oci() {
+        let dir = TempDir::new("dhl-test-vendor").unwrap();
+        File::create(dir.path().join("Cargo.toml"))
+            .unwrap()
+            .write_all(
+                br#"
+[package]
+name = "app"
+version = "0.1.0"
+authors = [""]
+
+[package.metadata.dhl.packages]
+priv = "oci://registry.example.com/team/priv:1.0"
+"#,
+            )
+            .unwrap();
+
+        let cell = Cell {
+            target: "x86_64-unknown-linux-gnu".into(),
+            profile: "debug".into(),
+        };
+        match Vendor::new(dir.path(), VENDOR_DIR_NAME).vendor(&[cell]) {
+            Err(VendorError::Unsupported(..)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+}
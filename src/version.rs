@@ -0,0 +1,121 @@
+use semver::{ReqParseError, SemVerError, Version, VersionReq};
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum VersionError {
+        InvalidRequirement(crate_name: String, requirement: String, err: ReqParseError) {
+            description("invalid version requirement")
+            display("crate '{}' has an invalid version requirement '{}': {}",
+                crate_name, requirement, err)
+            cause(err)
+        }
+        InvalidVersion(crate_name: String, version: String, err: SemVerError) {
+            description("invalid version")
+            display("crate '{}' has an invalid version '{}': {}", crate_name, version, err)
+            cause(err)
+        }
+        Unsatisfied(crate_name: String, what: &'static str, version: String, requirement: String) {
+            description("version does not satisfy the requirement")
+            display("the {} of crate '{}' is version {}, which does not satisfy the requirement '{}'",
+                what, crate_name, version, requirement)
+        }
+        Mismatch(crate_name: String, dummy: String, archive: String) {
+            description("archive version differs from the dummy crate")
+            display("the archive of crate '{}' is version {}, but the dummy crate is version {}",
+                crate_name, archive, dummy)
+        }
+    }
+}
+
+fn parse(crate_name: &str, version: &str) -> Result<Version, VersionError> {
+    Version::parse(version).map_err(|e| {
+        VersionError::InvalidVersion(crate_name.to_owned(), version.to_owned(), e)
+    })
+}
+
+/// Checks that `version` satisfies `requirement`. `what` names where the
+/// version came from, e.g. `"archive"`.
+pub fn check_requirement(
+    crate_name: &str,
+    what: &'static str,
+    version: &str,
+    requirement: Option<&str>,
+) -> Result<(), VersionError> {
+    let requirement = match requirement {
+        Some(requirement) => requirement,
+        None => return Ok(()),
+    };
+    let req = VersionReq::parse(requirement).map_err(|e| {
+        VersionError::InvalidRequirement(crate_name.to_owned(), requirement.to_owned(), e)
+    })?;
+    if req.matches(&parse(crate_name, version)?) {
+        Ok(())
+    } else {
+        Err(VersionError::Unsatisfied(
+            crate_name.to_owned(),
+            what,
+            version.to_owned(),
+            requirement.to_owned(),
+        ))
+    }
+}
+
+/// Checks the version an archive declares against the requirement and the
+/// version of the dummy crate it hijacks.
+pub fn check_archive(
+    crate_name: &str,
+    archive: &str,
+    dummy: Option<&str>,
+    requirement: Option<&str>,
+) -> Result<(), VersionError> {
+    check_requirement(crate_name, "archive", archive, requirement)?;
+    if let Some(dummy) = dummy {
+        if parse(crate_name, dummy)? != parse(crate_name, archive)? {
+            return Err(VersionError::Mismatch(
+                crate_name.to_owned(),
+                dummy.to_owned(),
+                archive.to_owned(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::{VersionError, check_archive, check_requirement};
+
+    #[test]
+    fn requirements() {
+        check_requirement("priv", "dummy", "3.2.1", None).unwrap();
+        check_requirement("priv", "dummy", "3.2.1", Some("^3.2")).unwrap();
+        check_requirement("priv", "dummy", "3.2.1", Some("3.2")).unwrap();
+        match check_requirement("priv", "dummy", "4.0.0", Some("^3.2")) {
+            Err(VersionError::Unsatisfied(..)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        match check_requirement("priv", "dummy", "3.2", Some("^3.2")) {
+            Err(VersionError::InvalidVersion(..)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        match check_requirement("priv", "dummy", "3.2.1", Some("^^3")) {
+            Err(VersionError::InvalidRequirement(..)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn archives() {
+        check_archive("priv", "1.0.0", None, None).unwrap();
+        check_archive("priv", "1.0.0", Some("1.0.0"), Some("1.0")).unwrap();
+        match check_archive("priv", "1.0.1", Some("1.0.0"), Some("1.0")) {
+            Err(VersionError::Mismatch(..)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        match check_archive("priv", "2.0.0", Some("2.0.0"), Some("1.0")) {
+            Err(VersionError::Unsatisfied(..)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+}
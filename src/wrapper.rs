@@ -0,0 +1,1063 @@
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::{File, remove_file};
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str;
+
+use serde_json;
+
+use {cfg_vars, run_rustc, rustc_verbose_version};
+use artifact::{ArtifactKind, ArtifactNaming};
+use depot::{Depot, DepotError};
+use env::Env;
+use index::{Build, IndexError};
+use lock::{LockError, LockMode, Lockfile};
+use manifest::{Manifest, ManifestCreationError, ManifestInspectionError, PackageData,
+               UninspectedPackage};
+use metadata::NATIVE_DIR;
+use recipients::Recipient;
+use report::Reporter;
+use template::{TemplateEngine, TemplateGenerationError};
+
+/// The extension of the file next to a hijacked rlib that lists the rustc
+/// arguments to link its native libraries.
+const LINK_ARGS_EXTENSION: &'static str = "dhl-link";
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum WrapperError {
+        NoRustc {
+            description("no rustc to wrap")
+            display("Expected the path to rustc as the first argument")
+        }
+        Rustc(rustc: OsString, err: io::Error) {
+            description("failed to run rustc")
+            display("Failed to run '{}': {}", Path::new(rustc).display(), err)
+            cause(err)
+        }
+        RustcOutput(rustc: OsString) {
+            description("unexpected rustc output")
+            display("Could not find the host in the output of '{} -vV'", Path::new(rustc).display())
+        }
+        ManifestCreation(err: ManifestCreationError) {
+            from()
+            description("manifest creation error")
+            display("Failed to read the manifest: {}", err)
+            cause(err)
+        }
+        ManifestInspection(err: ManifestInspectionError) {
+            from()
+            description("manifest inspection error")
+            display("Failed to inspect the manifest: {}", err)
+            cause(err)
+        }
+        TemplateGeneration(err: TemplateGenerationError) {
+            from()
+            description("template failed to generate")
+            display("Template generation error: {}", err)
+            cause(err)
+        }
+        Index(err: IndexError) {
+            from()
+            description("index error")
+            display("Index error: {}", err)
+            cause(err)
+        }
+        Lock(err: LockError) {
+            from()
+            description("lock error")
+            display("Failed to update lockfile: {}", err)
+            cause(err)
+        }
+        Depot(err: DepotError) {
+            from()
+            description("depot error")
+            display("Failed to deliver packages: {}", err)
+            cause(err)
+        }
+        MissingExport(crate_name: String, export: String) {
+            description("archive is missing an export")
+            display("The archive of '{}' has no '{}' for rustc to emit", crate_name, export)
+        }
+        Write(path: PathBuf, err: io::Error) {
+            description("failed to write output")
+            display("Failed to write '{}': {}", path.display(), err)
+            cause(err)
+        }
+    }
+}
+
+/// Flags of rustc that take a value, either as the next argument or joined
+/// to the flag.
+const VALUE_FLAGS: [&'static str; 35] = [
+    "--crate-name",
+    "--crate-type",
+    "--out-dir",
+    "--emit",
+    "--target",
+    "--json",
+    "--extern",
+    "--edition",
+    "--cfg",
+    "--check-cfg",
+    "--cap-lints",
+    "--error-format",
+    "--print",
+    "--explain",
+    "--sysroot",
+    "--remap-path-prefix",
+    "--diagnostic-width",
+    "--color",
+    "--codegen",
+    "--warn",
+    "--allow",
+    "--deny",
+    "--forbid",
+    "--force-warn",
+    "-C",
+    "-L",
+    "-l",
+    "-o",
+    "-W",
+    "-A",
+    "-D",
+    "-F",
+    "-Z",
+    "--extern-location",
+    "--env-set",
+];
+
+/// The parts of a rustc command line that matter for hijacking it.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct Invocation {
+    crate_name: Option<String>,
+    crate_types: Vec<String>,
+    out_dir: Option<PathBuf>,
+    /// `-C extra-filename`, the metadata hash cargo appends, e.g. `-d15ea5e`.
+    extra_filename: String,
+    emit: Vec<String>,
+    target: Option<String>,
+    input: Option<PathBuf>,
+    opt_level: Option<String>,
+    debuginfo: Option<String>,
+    json: Vec<String>,
+    /// The artifacts of `--extern` dependencies.
+    externs: Vec<PathBuf>,
+}
+
+impl Invocation {
+    fn parse(args: &[OsString]) -> Self {
+        let mut invocation = Invocation::default();
+        let mut args = args.iter().map(|arg| arg.to_string_lossy());
+        while let Some(arg) = args.next() {
+            let (flag, joined) = split_flag(&arg);
+            if !VALUE_FLAGS.contains(&flag) {
+                if (arg == "-" || !arg.starts_with('-')) && invocation.input.is_none() {
+                    invocation.input = Some(PathBuf::from(&*arg));
+                }
+                continue;
+            }
+            let value = match joined {
+                Some(value) => value.to_owned(),
+                None => {
+                    match args.next() {
+                        Some(value) => value.into_owned(),
+                        None => break,
+                    }
+                }
+            };
+            let list = || value.split(',').map(str::to_owned).collect::<Vec<_>>();
+            match flag {
+                "--crate-name" => invocation.crate_name = Some(value.clone()),
+                "--crate-type" => invocation.crate_types.extend(list()),
+                "--out-dir" => invocation.out_dir = Some(PathBuf::from(&value)),
+                // `link=path` names the output explicitly, cargo doesn't
+                "--emit" => {
+                    invocation.emit.extend(list().into_iter().map(|emit| {
+                        emit.split('=').next().unwrap_or("").to_owned()
+                    }))
+                }
+                "--target" => invocation.target = Some(value.clone()),
+                "--json" => invocation.json.extend(list()),
+                "--extern" => {
+                    if let Some(eq) = value.find('=') {
+                        invocation.externs.push(PathBuf::from(&value[eq + 1..]));
+                    }
+                }
+                "-C" | "--codegen" => {
+                    let mut parts = value.splitn(2, '=');
+                    let key = parts.next().unwrap_or("");
+                    let option = parts.next().unwrap_or("").to_owned();
+                    match key {
+                        "extra-filename" => invocation.extra_filename = option,
+                        "opt-level" => invocation.opt_level = Some(option),
+                        "debuginfo" => invocation.debuginfo = Some(option),
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+        invocation
+    }
+
+    /// The library artifacts rustc was asked to emit, or `None` if it isn't
+    /// asked to build a library, e.g. for binaries, proc macros and probes.
+    fn outputs(&self, naming: &ArtifactNaming) -> Option<Vec<(ArtifactKind, PathBuf)>> {
+        let (crate_name, out_dir) = match (self.crate_name.as_ref(), self.out_dir.as_ref()) {
+            (Some(crate_name), Some(out_dir)) => (crate_name, out_dir),
+            _ => return None,
+        };
+        if self.crate_types.is_empty() {
+            return None;
+        }
+        let link = self.emit.is_empty() || self.emit.iter().any(|e| e == "link");
+        let metadata = self.emit.iter().any(|e| e == "metadata");
+
+        let mut kinds = Vec::new();
+        if metadata {
+            kinds.push(ArtifactKind::Rmeta);
+        }
+        for crate_type in self.crate_types.iter() {
+            let kind = match crate_type.as_str() {
+                "lib" | "rlib" => ArtifactKind::Rlib,
+                "dylib" | "cdylib" => ArtifactKind::Dylib,
+                "staticlib" => ArtifactKind::Staticlib,
+                _ => return None,
+            };
+            if link && !kinds.contains(&kind) {
+                kinds.push(kind);
+            }
+        }
+        Some(
+            kinds
+                .into_iter()
+                .map(|kind| {
+                    let file_name = format!(
+                        "{}{}{}{}",
+                        naming.prefix(kind),
+                        crate_name,
+                        self.extra_filename,
+                        naming.extension(kind)
+                    );
+                    (kind, out_dir.join(file_name))
+                })
+                .collect(),
+        )
+    }
+
+    /// Whether rustc links something that needs the native libraries of its
+    /// dependencies, i.e. anything but an rlib, rather than only checking it.
+    fn links(&self) -> bool {
+        let link = self.emit.is_empty() || self.emit.iter().any(|e| e == "link");
+        let final_link = self.crate_types.is_empty() ||
+            self.crate_types.iter().any(|t| t != "lib" && t != "rlib");
+        link && final_link
+    }
+
+    /// Whether rustc builds an rlib, whose dependents inherit its link
+    /// arguments.
+    fn builds_rlib(&self) -> bool {
+        self.crate_types.iter().any(|t| t == "lib" || t == "rlib")
+    }
+
+    /// The file listing the link arguments of the crate and its dependencies.
+    fn link_args_file(&self) -> Option<PathBuf> {
+        match (self.crate_name.as_ref(), self.out_dir.as_ref()) {
+            (Some(crate_name), Some(out_dir)) => {
+                Some(out_dir.join(format!(
+                    "lib{}{}.{}",
+                    crate_name,
+                    self.extra_filename,
+                    LINK_ARGS_EXTENSION
+                )))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Splits `--flag=value` and `-Cvalue` into the flag and its value.
+fn split_flag(arg: &str) -> (&str, Option<&str>) {
+    if arg.starts_with("--") {
+        match arg.find('=') {
+            Some(eq) => (&arg[..eq], Some(&arg[eq + 1..])),
+            None => (arg, None),
+        }
+    } else if arg.starts_with('-') && arg.len() > 2 && arg.is_char_boundary(2) {
+        (&arg[..2], Some(&arg[2..]))
+    } else {
+        (arg, None)
+    }
+}
+
+/// Runs as `RUSTC_WRAPPER`: `args` is the path to rustc followed by its
+/// arguments. Returns the exit code to exit with.
+///
+/// The packages are read from the manifest in `DHL_MANIFEST_DIR`, or else the
+/// nearest `Cargo.toml` above rustc's `--out-dir`, which is in the target
+/// directory of the workspace unless that's moved elsewhere.
+pub fn wrap_rustc(args: Vec<OsString>) -> Result<i32, WrapperError> {
+    // Cargo passes rustc's stdout through, it isn't build script output
+    Reporter::Stderr.install();
+    let env = Env::process();
+    let manifest_dir = env.var_os("DHL_MANIFEST_DIR").map(PathBuf::from);
+    let mut args = args.into_iter();
+    let rustc = match args.next() {
+        Some(rustc) => rustc,
+        None => return Err(WrapperError::NoRustc),
+    };
+    let args = args.collect::<Vec<_>>();
+    wrap(&rustc, &args, manifest_dir.as_ref().map(PathBuf::as_path), &env)
+}
+
+fn find_manifest_dir(dir: &Path) -> Option<PathBuf> {
+    let mut dir = Some(dir);
+    while let Some(d) = dir {
+        if d.join("Cargo.toml").is_file() {
+            return Some(d.to_owned());
+        }
+        dir = d.parent();
+    }
+    None
+}
+
+fn wrap(
+    rustc: &OsStr,
+    args: &[OsString],
+    manifest_dir: Option<&Path>,
+    env: &Env,
+) -> Result<i32, WrapperError> {
+    let invocation = Invocation::parse(args);
+    if hijack(rustc, &invocation, manifest_dir, env)? {
+        return Ok(0);
+    }
+
+    // Native libraries are only linked into the final artifact, otherwise
+    // every rlib between it and the hijacked crate would bundle them. The
+    // search paths don't hurt anywhere.
+    let inherited = link_args(&invocation.externs, &[]);
+    let links = invocation.links();
+    let status = Command::new(rustc)
+        .args(args)
+        .args(inherited.iter().filter(|arg| links || arg.starts_with("-L")))
+        .status()
+        .map_err(|e| WrapperError::Rustc(rustc.to_owned(), e))?;
+    if status.success() && invocation.builds_rlib() {
+        write_link_args(&invocation, &inherited)?;
+    }
+    // Killed by a signal
+    Ok(status.code().unwrap_or(101))
+}
+
+/// `args` followed by the link arguments of every crate among `externs`
+/// that has them, i.e. hijacked crates and the rlibs depending on them.
+fn link_args(externs: &[PathBuf], args: &[String]) -> Vec<String> {
+    let mut args = args.to_vec();
+    for path in externs {
+        let mut contents = String::new();
+        let read = File::open(path.with_extension(LINK_ARGS_EXTENSION))
+            .and_then(|mut f| f.read_to_string(&mut contents));
+        if read.is_err() {
+            continue;
+        }
+        for arg in contents.lines().filter(|arg| !arg.is_empty()) {
+            if !args.iter().any(|a| a == arg) {
+                args.push(arg.to_owned());
+            }
+        }
+    }
+    args
+}
+
+/// Writes the link arguments of the crate rustc built, for its dependents.
+fn write_link_args(invocation: &Invocation, args: &[String]) -> Result<(), WrapperError> {
+    match invocation.link_args_file() {
+        Some(ref path) if args.is_empty() => remove_stale(path),
+        Some(ref path) => write_file(path, args.join("\n").as_bytes()),
+        None => Ok(()),
+    }
+}
+
+/// Whether the crate rustc compiles is the dummy of `package`, rather than a
+/// crate of the same name from elsewhere: the crate in its directory, or for
+/// registry and git dummies one whose source is empty.
+fn is_dummy(package: &UninspectedPackage, crate_dir: Option<&PathBuf>, empty: bool) -> bool {
+    match (package.path.as_ref(), crate_dir) {
+        (Some(path), Some(crate_dir)) => same_dir(path, crate_dir),
+        (Some(_), None) => false,
+        (None, _) => empty,
+    }
+}
+
+/// Whether two paths are the same existing directory.
+fn same_dir(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// The build script context cargo would give the dummy crate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BuildContext {
+    target: String,
+    host: String,
+    profile: String,
+    /// The output of `rustc --version`.
+    rustc: String,
+    opt_level: String,
+    debug: bool,
+    /// The `CARGO_CFG_*` variables of the target.
+    cfg: Vec<(String, String)>,
+}
+
+impl BuildContext {
+    fn new(rustc: &OsStr, invocation: &Invocation, out_dir: &Path) -> Result<Self, WrapperError> {
+        let verbose = rustc_verbose_version(rustc).map_err(
+            |e| WrapperError::Rustc(rustc.to_owned(), e),
+        )?;
+        let host = match verbose.lines().find(|line| line.starts_with("host: ")) {
+            Some(line) => line["host: ".len()..].trim().to_owned(),
+            None => return Err(WrapperError::RustcOutput(rustc.to_owned())),
+        };
+        let target = invocation.target.clone().unwrap_or_else(|| host.clone());
+        let cfg = run_rustc(rustc, &["--print", "cfg", "--target", &target]).map_err(
+            |e| WrapperError::Rustc(rustc.to_owned(), e),
+        )?;
+
+        // Cargo builds into `<profile>/deps`, or `<target>/<profile>/deps`
+        let profile_dir = if out_dir.ends_with("deps") {
+            out_dir.parent().unwrap_or(out_dir)
+        } else {
+            out_dir
+        };
+        let profile = profile_dir.file_name().map_or_else(
+            || "debug".to_owned(),
+            |name| name.to_string_lossy().into_owned(),
+        );
+
+        let debug = match invocation.debuginfo.as_ref().map(String::as_str) {
+            None | Some("0") | Some("none") => false,
+            Some(_) => true,
+        };
+
+        Ok(BuildContext {
+            target,
+            host,
+            profile,
+            rustc: verbose.lines().next().unwrap_or("").trim().to_owned(),
+            opt_level: invocation.opt_level.clone().unwrap_or_else(|| "0".to_owned()),
+            debug,
+            cfg: cfg_vars(&cfg),
+        })
+    }
+
+    /// The environment of the build script, over `env`, which is that of
+    /// the dummy crate's compilation.
+    fn env(&self, env: &Env) -> Env {
+        let mut vars = env.names_with_prefix("")
+            .into_iter()
+            .filter(|k| !k.starts_with("CARGO_FEATURE_") && !k.starts_with("CARGO_CFG_"))
+            .filter_map(|k| env.var(&k).ok().map(|v| (k, v)))
+            .collect::<HashMap<_, _>>();
+        vars.insert("TARGET".into(), self.target.clone());
+        vars.insert("HOST".into(), self.host.clone());
+        vars.insert("PROFILE".into(), self.profile.clone());
+        vars.insert("OPT_LEVEL".into(), self.opt_level.clone());
+        vars.insert("DEBUG".into(), self.debug.to_string());
+        vars.extend(self.cfg.iter().cloned());
+        Env::with_vars(vars)
+    }
+}
+
+/// Delivers the archive of the crate rustc is asked to build, if it's the
+/// dummy crate of a package of the manifest, instead of running rustc.
+/// Returns whether it did.
+fn hijack(
+    rustc: &OsStr,
+    invocation: &Invocation,
+    manifest_dir: Option<&Path>,
+    env: &Env,
+) -> Result<bool, WrapperError> {
+    let (crate_name, out_dir) = match (invocation.crate_name.as_ref(), invocation.out_dir.as_ref()) {
+        (Some(crate_name), Some(out_dir)) => (crate_name, out_dir),
+        _ => return Ok(false),
+    };
+    // Rule out binaries, probes and the packages being built before reading
+    // the manifest. Dummies are either path dependencies or empty, so the
+    // manifest is only read for those, not for every crate of the build.
+    if invocation.outputs(&ArtifactNaming::for_target("")).is_none() ||
+        env.var_os("CARGO_PRIMARY_PACKAGE").is_some()
+    {
+        return Ok(false);
+    }
+    let crate_dir = env.var_os("CARGO_MANIFEST_DIR").map(PathBuf::from);
+    let empty = invocation.input.as_ref().map_or(false, |input| Depot::is_empty_source(input));
+    if !empty && crate_dir.is_none() {
+        return Ok(false);
+    }
+
+    // Cargo runs rustc in the package's own directory for registry and git
+    // dependencies, so the workspace is found from the target directory
+    let manifest_dir = match manifest_dir {
+        Some(manifest_dir) => manifest_dir.to_path_buf(),
+        None => {
+            match find_manifest_dir(out_dir) {
+                Some(manifest_dir) => manifest_dir,
+                None => return Ok(false),
+            }
+        }
+    };
+    let manifest = Manifest::produce_from_dir_with_env(&manifest_dir, env.clone())?;
+    if !manifest.packages.values().any(|package| package.lib_name.replace('-', "_") == *crate_name) {
+        return Ok(false);
+    }
+
+    // Which `[target.*]` dependencies apply, and the templated hashes, are
+    // only known in the dummy's build context
+    let context = BuildContext::new(rustc, invocation, out_dir)?;
+    let env = context.env(env);
+    let mut manifest = Manifest::produce_from_dir_with_env(manifest_dir, env.clone())?;
+    let mut candidates = manifest
+        .packages
+        .iter()
+        .filter(|&(name, package)| {
+            package.lib_name.replace('-', "_") == *crate_name &&
+                !manifest.from_source.contains(name) &&
+                is_dummy(package, crate_dir.as_ref(), empty)
+        })
+        .map(|(name, _)| name.clone())
+        .collect::<Vec<_>>();
+    if candidates.is_empty() {
+        return Ok(false);
+    }
+    candidates.sort();
+
+    let template = TemplateEngine::with_env(manifest.substitutions.clone(), env)?;
+    let hash = invocation.extra_filename.trim_start_matches('-');
+    let mut found = None;
+    for name in candidates {
+        let package = &manifest.packages[&name];
+        let matches = match package.hash {
+            Some(ref package_hash) => {
+                let version = package.version.as_ref().map(AsRef::as_ref);
+                let rendered = template
+                    .for_package(&package.substitutions)?
+                    .render(package_hash, version)
+                    .map_err(|e| {
+                        ManifestInspectionError::TemplateRender(name.clone(), package.clone(), e)
+                    })?;
+                rendered == hash
+            }
+            None => true,
+        };
+        if matches {
+            found = Some(name);
+            break;
+        }
+    }
+    let name = match found {
+        Some(name) => name,
+        None => return Ok(false),
+    };
+    manifest.packages.retain(|package_name, _| *package_name == name);
+
+    let lock_file = manifest.lock_file.clone();
+    let build = Build::with_rustc(context.target.clone(), context.profile.clone(), &context.rustc);
+    let package = match manifest.inspect_for(&template, build)?.packages.remove(&name) {
+        Some(package) => package,
+        None => return Ok(false),
+    };
+    let archive = match package.data {
+        PackageData::File(ref source) => Some(source.source.clone()),
+        _ => None,
+    };
+
+    let naming = ArtifactNaming::for_target(&context.target);
+    let outputs = match invocation.outputs(&naming) {
+        Some(outputs) => outputs,
+        None => return Ok(false),
+    };
+    let mut exports = HashMap::new();
+    for &(kind, ref path) in outputs.iter() {
+        remove_stale(path)?;
+        exports.insert(naming.export_name(kind), path.clone());
+    }
+    let native_dir = out_dir.join(NATIVE_DIR).join(
+        format!("{}{}", crate_name, invocation.extra_filename),
+    );
+    let dest = Recipient::new(out_dir.clone(), native_dir, exports);
+
+    let mut lock = Lockfile::with_env(
+        lock_file,
+        context.target.clone(),
+        context.profile.clone(),
+        context.rustc.clone(),
+        LockMode::from_env(),
+    )?;
+    let depot = Depot::for_target(&context.target);
+    let receipt = depot.deliver_package(name.clone(), package, &naming, &dest, Some(&mut lock))?;
+    lock.save()?;
+
+    // Nothing was delivered for a package that may be missing, so the dummy
+    // is built as it is.
+    let delivered = outputs.iter().any(|&(kind, ref path)| {
+        kind != ArtifactKind::Rmeta && path.is_file()
+    });
+    if !delivered {
+        return Ok(false);
+    }
+    for &(kind, ref path) in outputs.iter() {
+        if path.is_file() {
+            continue;
+        }
+        let rlib = outputs.iter().find(|&&(kind, _)| kind == ArtifactKind::Rlib);
+        match (kind, rlib) {
+            (ArtifactKind::Rmeta, Some(&(_, ref rlib))) => write_rmeta(&name, rlib, path)?,
+            _ => return Err(WrapperError::MissingExport(name, naming.export_name(kind))),
+        }
+    }
+
+    write_link_args(invocation, &link_args(&invocation.externs, &receipt.link_args()))?;
+    if invocation.emit.iter().any(|e| e == "dep-info") {
+        let path = out_dir.join(format!("{}{}.d", crate_name, invocation.extra_filename));
+        let sources = invocation.input.iter().chain(archive.iter()).collect::<Vec<_>>();
+        let targets = outputs.iter().map(|&(_, ref path)| path).collect::<Vec<_>>();
+        write_file(&path, dep_info(&targets, &sources).as_bytes())?;
+    }
+    if invocation.json.iter().any(|json| json == "artifacts") {
+        for &(kind, ref path) in outputs.iter() {
+            let emit = if kind == ArtifactKind::Rmeta { "metadata" } else { "link" };
+            let artifact = serde_json::to_string(&path.to_string_lossy()).unwrap_or_default();
+            eprintln!("{{\"artifact\":{},\"emit\":\"{}\"}}", artifact, emit);
+        }
+    }
+    Ok(true)
+}
+
+/// Removes an output of a previous build, so an archive without it can't
+/// leave it behind.
+fn remove_stale(path: &Path) -> Result<(), WrapperError> {
+    match remove_file(path) {
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        result => result.map_err(|e| WrapperError::Write(path.to_path_buf(), e)),
+    }
+}
+
+fn write_file(path: &Path, contents: &[u8]) -> Result<(), WrapperError> {
+    File::create(path).and_then(|mut f| f.write_all(contents)).map_err(|e| {
+        WrapperError::Write(path.to_path_buf(), e)
+    })
+}
+
+/// Writes the metadata of an rlib as the `.rmeta` of a pipelined build, for
+/// archives that only export `export.rlib`.
+fn write_rmeta(crate_name: &str, rlib: &Path, rmeta: &Path) -> Result<(), WrapperError> {
+    let mut contents = Vec::new();
+    File::open(rlib).and_then(|mut f| f.read_to_end(&mut contents)).map_err(|e| {
+        WrapperError::Write(rlib.to_path_buf(), e)
+    })?;
+    match extract_rmeta(&contents) {
+        Some(metadata) => write_file(rmeta, metadata),
+        None => {
+            Err(WrapperError::MissingExport(
+                crate_name.to_owned(),
+                "export.rmeta".to_owned(),
+            ))
+        }
+    }
+}
+
+/// The `lib.rmeta` member of an rlib, which is an `ar` archive with GNU or
+/// BSD member names.
+fn extract_rmeta(rlib: &[u8]) -> Option<&[u8]> {
+    const MAGIC: &'static [u8] = b"!<arch>\n";
+    const HEADER_LEN: usize = 60;
+    if !rlib.starts_with(MAGIC) {
+        return None;
+    }
+    let mut pos = MAGIC.len();
+    while pos + HEADER_LEN <= rlib.len() {
+        let header = &rlib[pos..pos + HEADER_LEN];
+        let size = match str::from_utf8(&header[48..58]).ok().and_then(|size| {
+            size.trim().parse::<usize>().ok()
+        }) {
+            Some(size) => size,
+            None => return None,
+        };
+        let start = pos + HEADER_LEN;
+        if start + size > rlib.len() {
+            return None;
+        }
+        let name = str::from_utf8(&header[..16]).unwrap_or("").trim_end();
+        let (name, data) = if name.starts_with("#1/") {
+            // BSD puts long names before the data
+            let len = match name[3..].parse::<usize>() {
+                Ok(len) if len <= size => len,
+                _ => return None,
+            };
+            let name = str::from_utf8(&rlib[start..start + len]).unwrap_or("");
+            (name.trim_end_matches('\0'), &rlib[start + len..start + size])
+        } else {
+            (name.trim_end_matches('/'), &rlib[start..start + size])
+        };
+        if name == "lib.rmeta" {
+            return Some(data);
+        }
+        // Members are aligned to two bytes
+        pos = start + size + size % 2;
+    }
+    None
+}
+
+/// A makefile rule like the dep-info rustc writes.
+fn dep_info(targets: &[&PathBuf], sources: &[&PathBuf]) -> String {
+    let escape = |path: &&PathBuf| path.display().to_string().replace(' ', "\\ ");
+    let targets = targets.iter().map(&escape).collect::<Vec<_>>();
+    let sources = sources.iter().map(&escape).collect::<Vec<_>>();
+    let mut contents = format!("{}: {}\n", targets.join(" "), sources.join(" "));
+    for source in sources {
+        contents.push_str(&format!("\n{}:\n", source));
+    }
+    contents
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsString;
+    use std::fs::{File, create_dir_all};
+    use std::io::{Read, Write};
+    use std::path::PathBuf;
+
+    use libflate::gzip::Encoder;
+    use tar::{Builder, Header};
+    use tempdir::TempDir;
+
+    use artifact::{ArtifactKind, ArtifactNaming};
+    use env::Env;
+    use super::{Invocation, dep_info, extract_rmeta, hijack, link_args, wrap, write_link_args};
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn parse_invocation() {
+        let invocation = Invocation::parse(&args(
+            &[
+                "--crate-name",
+                "priv",
+                "--edition=2015",
+                "priv/src/lib.rs",
+                "--error-format=json",
+                "--json=diagnostic-rendered-ansi,artifacts",
+                "--crate-type",
+                "lib",
+                "--emit=dep-info,metadata,link",
+                "-C",
+                "opt-level=3",
+                "-Cdebuginfo=2",
+                "-C",
+                "extra-filename=-d15ea5e",
+                "--out-dir",
+                "/t/release/deps",
+                "-L",
+                "dependency=/t/release/deps",
+                "--extern",
+                "dep=/t/release/deps/libdep-abc.rmeta",
+                "--extern",
+                "proc_macro",
+                "--target",
+                "x86_64-unknown-linux-gnu",
+            ],
+        ));
+        assert_eq!(
+            invocation,
+            Invocation {
+                crate_name: Some("priv".into()),
+                crate_types: vec!["lib".into()],
+                out_dir: Some("/t/release/deps".into()),
+                extra_filename: "-d15ea5e".into(),
+                emit: vec!["dep-info".into(), "metadata".into(), "link".into()],
+                target: Some("x86_64-unknown-linux-gnu".into()),
+                input: Some("priv/src/lib.rs".into()),
+                opt_level: Some("3".into()),
+                debuginfo: Some("2".into()),
+                json: vec!["diagnostic-rendered-ansi".into(), "artifacts".into()],
+                externs: vec!["/t/release/deps/libdep-abc.rmeta".into()],
+            }
+        );
+        assert!(invocation.builds_rlib());
+        assert!(!invocation.links());
+
+        let naming = ArtifactNaming::for_target("x86_64-unknown-linux-gnu");
+        assert_eq!(
+            invocation.outputs(&naming),
+            Some(vec![
+                (ArtifactKind::Rmeta, "/t/release/deps/libpriv-d15ea5e.rmeta".into()),
+                (ArtifactKind::Rlib, "/t/release/deps/libpriv-d15ea5e.rlib".into()),
+            ])
+        );
+
+        let bin = Invocation::parse(&args(&["--crate-name", "app", "src/main.rs", "--out-dir", "/t"]));
+        assert_eq!(bin.outputs(&naming), None);
+        assert!(bin.links());
+        let check = Invocation::parse(&args(&["--crate-name", "app", "--emit=metadata"]));
+        assert!(!check.links());
+        let probe = Invocation::parse(
+            &args(&["-", "--crate-name", "___", "--print=file-names", "--crate-type", "bin"]),
+        );
+        assert_eq!(probe.input, Some("-".into()));
+        assert_eq!(probe.outputs(&naming), None);
+        let proc_macro = Invocation::parse(&args(&[
+            "--crate-name",
+            "derive",
+            "--crate-type",
+            "proc-macro",
+            "--out-dir",
+            "/t",
+        ]));
+        assert_eq!(proc_macro.outputs(&naming), None);
+    }
+
+    #[test]
+    fn rmeta_from_rlib() {
+        let member = |name: &str, data: &[u8]| {
+            let mut member = format!(
+                "{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+                name,
+                0,
+                0,
+                0,
+                644,
+                data.len()
+            ).into_bytes();
+            member.extend_from_slice(data);
+            if data.len() % 2 == 1 {
+                member.push(b'\n');
+            }
+            member
+        };
+
+        let mut gnu = b"!<arch>\n".to_vec();
+        gnu.extend(member("priv-abc.o/", b"odd"));
+        gnu.extend(member("lib.rmeta/", b"rust metadata"));
+        assert_eq!(extract_rmeta(&gnu), Some(&b"rust metadata"[..]));
+
+        let mut bsd = b"!<arch>\n".to_vec();
+        bsd.extend(member("#1/12", b"priv-abc.o\0\0odd"));
+        bsd.extend(member("#1/12", b"lib.rmeta\0\0\0rust metadata"));
+        assert_eq!(extract_rmeta(&bsd), Some(&b"rust metadata"[..]));
+
+        assert_eq!(extract_rmeta(&gnu[..gnu.len() - 4]), None);
+        assert_eq!(extract_rmeta(b"not an rlib"), None);
+    }
+
+    #[test]
+    fn dep_info_rule() {
+        let rlib = PathBuf::from("/t/deps/libpriv-abc.rlib");
+        let lib = PathBuf::from("priv/src/lib.rs");
+        let archive = PathBuf::from("/libs/my exported.tar.gz");
+        assert_eq!(
+            dep_info(&[&rlib], &[&lib, &archive]),
+            "/t/deps/libpriv-abc.rlib: priv/src/lib.rs /libs/my\\ exported.tar.gz\n\
+             \npriv/src/lib.rs:\n\
+             \n/libs/my\\ exported.tar.gz:\n"
+        );
+    }
+
+    #[test]
+    fn hijack_rustc() {
+        let tmp_dir = TempDir::new("dhlwrapper").unwrap();
+        let package = tmp_dir.path().to_path_buf();
+        let out_dir = package.join("target").join("debug").join("deps");
+        create_dir_all(&out_dir).unwrap();
+
+        // The dummy is empty, a crate of the same name that isn't is left
+        // alone
+        create_dir_all(package.join("priv").join("src")).unwrap();
+        File::create(package.join("priv").join("src").join("lib.rs")).unwrap();
+        File::create(package.join("priv").join("src").join("main.rs"))
+            .unwrap()
+            .write_all(b"pub fn not_a_dummy() {}\n")
+            .unwrap();
+
+        File::create(package.join("Cargo.toml"))
+            .unwrap()
+            .write_all(
+                b"[package]\n\
+                  name = \"app\"\n\
+                  version = \"0.1.0\"\n\
+                  [package.metadata.dhl.packages]\n\
+                  priv = \"./exported.tar.gz\"\n",
+            )
+            .unwrap();
+
+        {
+            let mut builder = Builder::new(Encoder::new(
+                File::create(package.join("exported.tar.gz")).unwrap(),
+            ).unwrap());
+            for &(path, data) in [
+                ("export.rlib", &b"hijacked"[..]),
+                ("libdep-abc.rlib", b"dependency"),
+                ("native/libfoo.a", b"native"),
+                ("export.toml", b"link-libs = [\"static=foo\"]\n"),
+            ].iter()
+            {
+                let mut header = Header::new_gnu();
+                header.set_path(path).unwrap();
+                header.set_size(data.len() as u64);
+                header.set_cksum();
+                builder.append(&header, data).unwrap();
+            }
+            builder.into_inner().unwrap().finish().unwrap();
+        }
+
+        let out = out_dir.to_str().unwrap();
+        let env = Env::with_vars(Vec::<(String, String)>::new());
+        let other = Invocation::parse(&args(&[
+            "--crate-name",
+            "priv",
+            package.join("priv").join("src").join("main.rs").to_str().unwrap(),
+            "--crate-type",
+            "lib",
+            "--out-dir",
+            out,
+        ]));
+        assert!(!hijack("rustc".as_ref(), &other, Some(&package), &env).unwrap());
+
+        let code = wrap(
+            "rustc".as_ref(),
+            &args(
+                &[
+                    "--crate-name",
+                    "priv",
+                    package.join("priv").join("src").join("lib.rs").to_str().unwrap(),
+                    "--crate-type",
+                    "lib",
+                    "--emit=dep-info,link",
+                    "-C",
+                    "extra-filename=-d15ea5e",
+                    "--out-dir",
+                    out,
+                ],
+            ),
+            Some(&package),
+            &env,
+        ).unwrap();
+        assert_eq!(code, 0);
+
+        let read = |name: &str| {
+            let mut contents = String::new();
+            File::open(out_dir.join(name))
+                .unwrap()
+                .read_to_string(&mut contents)
+                .unwrap();
+            contents
+        };
+        assert_eq!(read("libpriv-d15ea5e.rlib"), "hijacked");
+        assert_eq!(read("libdep-abc.rlib"), "dependency");
+        assert_eq!(read("native/priv-d15ea5e/libfoo.a"), "native");
+        assert!(read("priv-d15ea5e.d").contains("exported.tar.gz"));
+        assert!(package.join("dhl.lock").is_file());
+
+        let link = link_args(&[out_dir.join("libpriv-d15ea5e.rmeta")], &[]);
+        assert_eq!(
+            link,
+            vec![
+                format!("-Lnative={}", out_dir.join("native").join("priv-d15ea5e").display()),
+                "-lstatic=foo".to_owned(),
+            ]
+        );
+
+        // Dependents pass them on to theirs
+        let dependent = Invocation::parse(&args(&[
+            "--crate-name",
+            "mid",
+            "--crate-type",
+            "lib",
+            "-C",
+            "extra-filename=-abc",
+            "--out-dir",
+            out,
+        ]));
+        write_link_args(&dependent, &link).unwrap();
+        assert_eq!(link_args(&[out_dir.join("libmid-abc.rlib")], &[]), link);
+    }
+
+    #[test]
+    fn hijack_registry_dummy() {
+        let tmp_dir = TempDir::new("dhlwrapper").unwrap();
+        let package = tmp_dir.path().join("app");
+        let out_dir = package.join("target").join("debug").join("deps");
+        create_dir_all(&out_dir).unwrap();
+
+        // Registry dummies are compiled in their own directory, outside the
+        // workspace
+        let registry = tmp_dir.path().join("registry").join("priv-1.0.0");
+        create_dir_all(registry.join("src")).unwrap();
+        File::create(registry.join("src").join("lib.rs")).unwrap();
+
+        File::create(package.join("Cargo.toml"))
+            .unwrap()
+            .write_all(
+                b"[package]\n\
+                  name = \"app\"\n\
+                  version = \"0.1.0\"\n\
+                  [dependencies]\n\
+                  priv = \"1.0.0\"\n\
+                  [package.metadata.dhl.packages.priv]\n\
+                  source = \"./exported.tar.gz\"\n\
+                  hash = \"d15ea5e{{opt_level}}\"\n",
+            )
+            .unwrap();
+
+        {
+            let mut builder = Builder::new(Encoder::new(
+                File::create(package.join("exported.tar.gz")).unwrap(),
+            ).unwrap());
+            let mut header = Header::new_gnu();
+            header.set_path("export.rlib").unwrap();
+            header.set_size(8);
+            header.set_cksum();
+            builder.append(&header, &b"hijacked"[..]).unwrap();
+            builder.into_inner().unwrap().finish().unwrap();
+        }
+
+        let out = out_dir.to_str().unwrap();
+        let lib = registry.join("src").join("lib.rs");
+        let env = Env::with_vars(Vec::<(String, String)>::new());
+        let invocation = |hash: &str| {
+            Invocation::parse(&args(&[
+                "--crate-name",
+                "priv",
+                lib.to_str().unwrap(),
+                "--crate-type",
+                "lib",
+                "-C",
+                "opt-level=3",
+                "-C",
+                &format!("extra-filename=-{}", hash),
+                "--out-dir",
+                out,
+            ]))
+        };
+
+        // The hash is compared once rendered, not whenever it's a template
+        assert!(!hijack("rustc".as_ref(), &invocation("d15ea5e0"), None, &env).unwrap());
+        assert!(!out_dir.join("libpriv-d15ea5e0.rlib").exists());
+
+        assert!(hijack("rustc".as_ref(), &invocation("d15ea5e3"), None, &env).unwrap());
+        let mut contents = String::new();
+        File::open(out_dir.join("libpriv-d15ea5e3.rlib"))
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "hijacked");
+        assert!(package.join("dhl.lock").is_file());
+    }
+}